@@ -0,0 +1,947 @@
+/// Limit alarms on channel values.
+///
+/// A channel with an `[channels.<name>.alarms]` section gets one alarm per
+/// configured limit (HiHi, Hi, Lo, LoLo). An alarm is raised once the value
+/// has been past its limit for `on_delay_ms`, and cleared once it has been
+/// back inside the limit by at least `deadband` for `off_delay_ms`. It stays
+/// listed until it is both cleared and acknowledged.
+///
+/// Operators acknowledge and comment on alarms; users listed under
+/// `[alarm_shelving]` can also shelve one, which keeps it from raising until
+/// the shelve runs out. Every event is appended to the alarm log (an
+/// in-memory ring backed by a JSON-lines file) and broadcast to subscribers
+/// such as the websocket sessions. The state of each alarm is saved as well,
+/// so a restart neither re-raises acknowledged alarms nor forgets unanswered
+/// ones.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use crate::config::ChannelConfig;
+use crate::history::{self, Quality};
+use crate::password;
+use crate::state::SharedState;
+
+// How often channel values are checked against their limits.
+const SCAN_INTERVAL: Duration = Duration::from_millis(100);
+// Events buffered per subscriber before a slow one starts missing events.
+const SUBSCRIBER_BACKLOG: usize = 256;
+const MAX_COMMENT_BYTES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    LoLo,
+    Lo,
+    Hi,
+    HiHi,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::LoLo => "lolo",
+            Level::Lo => "lo",
+            Level::Hi => "hi",
+            Level::HiHi => "hihi",
+        }
+    }
+
+    fn is_high(&self) -> bool {
+        matches!(self, Level::Hi | Level::HiHi)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Medium,
+    High,
+    Critical,
+}
+
+impl Priority {
+    fn raised(&self) -> Priority {
+        match self {
+            Priority::Low => Priority::Medium,
+            Priority::Medium => Priority::High,
+            Priority::High | Priority::Critical => Priority::Critical,
+        }
+    }
+}
+
+/// `[channels.<name>.alarms]`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlarmConfig {
+    pub hihi: Option<f32>,
+    pub hi: Option<f32>,
+    pub lo: Option<f32>,
+    pub lolo: Option<f32>,
+    /// How far back inside a limit the value has to come before the alarm
+    /// clears, in channel units.
+    pub deadband: f32,
+    /// How long the value has to stay past a limit before the alarm raises.
+    pub on_delay_ms: u64,
+    /// How long the value has to stay back inside before the alarm clears.
+    pub off_delay_ms: u64,
+    /// Priority of the Hi and Lo alarms. HiHi and LoLo are one step higher.
+    pub priority: Priority,
+}
+
+impl AlarmConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = self.limits();
+        if limits.is_empty() {
+            return Err("alarms need at least one of hihi, hi, lo or lolo".to_string());
+        }
+        if limits.iter().any(|(_, limit)| !limit.is_finite()) {
+            return Err("alarm limits must be numbers".to_string());
+        }
+        // in level order, so each limit must be above the one before
+        if limits.windows(2).any(|pair| pair[0].1 > pair[1].1) {
+            return Err("alarm limits must be ordered lolo <= lo <= hi <= hihi".to_string());
+        }
+        if self.deadband.is_nan() || self.deadband < 0.0 {
+            return Err("alarm deadband must not be negative".to_string());
+        }
+        Ok(())
+    }
+
+    /// Configured limits, lowest level first.
+    fn limits(&self) -> Vec<(Level, f32)> {
+        [
+            (Level::LoLo, self.lolo),
+            (Level::Lo, self.lo),
+            (Level::Hi, self.hi),
+            (Level::HiHi, self.hihi),
+        ]
+        .into_iter()
+        .filter_map(|(level, limit)| Some((level, limit?)))
+        .collect()
+    }
+
+    fn priority(&self, level: Level) -> Priority {
+        match level {
+            Level::Hi | Level::Lo => self.priority,
+            Level::HiHi | Level::LoLo => self.priority.raised(),
+        }
+    }
+}
+
+/// `[alarm_shelving]`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShelvingConfig {
+    /// Longest a single shelve may last.
+    #[serde(default = "default_max_shelve_minutes")]
+    pub max_minutes: u64,
+    /// The only users allowed to shelve and unshelve.
+    pub users: Vec<ShelvingUser>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShelvingUser {
+    pub username: String,
+    /// Made with `io_server hash-password`, never the password itself.
+    pub password_hash: String,
+}
+
+fn default_max_shelve_minutes() -> u64 {
+    8 * 60
+}
+
+/// A note left on an alarm by an operator.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AlarmComment {
+    pub ts_ms: u64,
+    pub user: String,
+    pub text: String,
+}
+
+/// One limit of one channel, as listed to clients.
+#[derive(Clone, Debug, Serialize)]
+pub struct Alarm {
+    /// `<channel>.<level>`
+    pub id: String,
+    pub channel: String,
+    pub level: Level,
+    pub limit: f32,
+    pub priority: Priority,
+    pub active: bool,
+    pub acknowledged: bool,
+    /// When it last raised, and the value that raised it.
+    pub raised_ms: Option<u64>,
+    pub value: Option<f32>,
+    /// Kept from raising until then, and who shelved it.
+    pub shelved_until_ms: Option<u64>,
+    pub shelved_by: Option<String>,
+    /// Operator notes since it last raised.
+    pub comments: Vec<AlarmComment>,
+    #[serde(skip)]
+    deadband: f32,
+    #[serde(skip)]
+    on_delay: Duration,
+    #[serde(skip)]
+    off_delay: Duration,
+    // since when the value has been past (or, when active, back inside) the
+    // limit without the delay having run out
+    #[serde(skip)]
+    pending_since: Option<Instant>,
+}
+
+impl Alarm {
+    /// Raised, cleared but not yet acknowledged, or shelved.
+    fn is_listed(&self) -> bool {
+        self.active || !self.acknowledged || self.shelved_until_ms.is_some()
+    }
+
+    fn beyond_limit(&self, value: f32) -> bool {
+        if self.level.is_high() {
+            value > self.limit
+        } else {
+            value < self.limit
+        }
+    }
+
+    fn back_inside(&self, value: f32) -> bool {
+        if self.level.is_high() {
+            value < self.limit - self.deadband
+        } else {
+            value > self.limit + self.deadband
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Raised,
+    Acknowledged,
+    Cleared,
+    Shelved,
+    Unshelved,
+    Commented,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Raised => "raised",
+            EventKind::Acknowledged => "acknowledged",
+            EventKind::Cleared => "cleared",
+            EventKind::Shelved => "shelved",
+            EventKind::Unshelved => "unshelved",
+            EventKind::Commented => "commented",
+        }
+    }
+}
+
+/// An entry of the alarm log.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AlarmEvent {
+    pub ts_ms: u64,
+    pub event: EventKind,
+    pub id: String,
+    pub channel: String,
+    pub level: Level,
+    pub priority: Priority,
+    pub limit: f32,
+    /// Channel value when it raised or cleared.
+    pub value: Option<f32>,
+    /// Who acknowledged, shelved, unshelved or commented on it.
+    pub user: Option<String>,
+    pub comment: Option<String>,
+    pub shelved_until_ms: Option<u64>,
+}
+
+/// What is kept of an alarm across restarts.
+#[derive(Deserialize, Serialize)]
+struct SavedAlarm {
+    active: bool,
+    acknowledged: bool,
+    raised_ms: Option<u64>,
+    value: Option<f32>,
+    shelved_until_ms: Option<u64>,
+    shelved_by: Option<String>,
+    comments: Vec<AlarmComment>,
+}
+
+#[derive(Debug)]
+pub enum AlarmError {
+    UnknownAlarm(String),
+    /// Nothing to acknowledge, or not shelved.
+    NotPending(String),
+    /// The user may not shelve.
+    Unauthorized(String),
+    Invalid(String),
+}
+
+impl fmt::Display for AlarmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlarmError::UnknownAlarm(id) => write!(f, "no alarm named {id}"),
+            AlarmError::NotPending(message) => write!(f, "{message}"),
+            AlarmError::Unauthorized(user) => write!(f, "{user} may not shelve alarms"),
+            AlarmError::Invalid(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+pub struct AlarmEngine {
+    alarms: BTreeMap<String, Alarm>,
+    log: VecDeque<AlarmEvent>,
+    log_capacity: usize,
+    log_file: File,
+    state_path: PathBuf,
+    shelving: Option<ShelvingConfig>,
+    events: broadcast::Sender<AlarmEvent>,
+}
+
+impl AlarmEngine {
+    /// Sets up the alarms of every channel that has limits, restoring their
+    /// state from `state_path`, and opens (or creates) the log at `log_path`,
+    /// keeping its newest `log_capacity` events in memory.
+    pub fn open(
+        channels: &BTreeMap<String, ChannelConfig>,
+        shelving: Option<ShelvingConfig>,
+        log_path: impl Into<PathBuf>,
+        state_path: impl Into<PathBuf>,
+        log_capacity: usize,
+    ) -> io::Result<Self> {
+        let state_path = state_path.into();
+        let mut saved = read_state(&state_path);
+        let mut alarms = BTreeMap::new();
+        for (channel, config) in channels.iter() {
+            let Some(config) = config.alarms.as_ref() else {
+                continue;
+            };
+            for (level, limit) in config.limits() {
+                let id = format!("{}.{}", channel, level.as_str());
+                let mut alarm = Alarm {
+                    id: id.clone(),
+                    channel: channel.clone(),
+                    level,
+                    limit,
+                    priority: config.priority(level),
+                    active: false,
+                    acknowledged: true,
+                    raised_ms: None,
+                    value: None,
+                    shelved_until_ms: None,
+                    shelved_by: None,
+                    comments: Vec::new(),
+                    deadband: config.deadband,
+                    on_delay: Duration::from_millis(config.on_delay_ms),
+                    off_delay: Duration::from_millis(config.off_delay_ms),
+                    pending_since: None,
+                };
+                if let Some(saved) = saved.remove(&id) {
+                    alarm.active = saved.active;
+                    alarm.acknowledged = saved.acknowledged;
+                    alarm.raised_ms = saved.raised_ms;
+                    alarm.value = saved.value;
+                    alarm.shelved_until_ms = saved.shelved_until_ms;
+                    alarm.shelved_by = saved.shelved_by;
+                    alarm.comments = saved.comments;
+                }
+                alarms.insert(id, alarm);
+            }
+        }
+
+        let log_path = log_path.into();
+        let log = read_log(&log_path, log_capacity)?;
+        let log_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        println!(
+            "alarms: {} configured, {} listed, {} logged events restored",
+            alarms.len(),
+            alarms.values().filter(|alarm| alarm.is_listed()).count(),
+            log.len()
+        );
+        Ok(Self {
+            alarms,
+            log,
+            log_capacity,
+            log_file,
+            state_path,
+            shelving,
+            events: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+        })
+    }
+
+    /// Checks one channel reading against the channel's limits.
+    pub fn evaluate(&mut self, channel: &str, value: f32, now: Instant) {
+        let mut events = Vec::new();
+        for alarm in self.alarms.values_mut() {
+            if alarm.channel != channel {
+                continue;
+            }
+            // a shelved alarm can still clear, but not raise
+            if !alarm.active && alarm.shelved_until_ms.is_some() {
+                alarm.pending_since = None;
+                continue;
+            }
+            let changing = if alarm.active {
+                alarm.back_inside(value)
+            } else {
+                alarm.beyond_limit(value)
+            };
+            if !changing {
+                alarm.pending_since = None;
+                continue;
+            }
+            let since = *alarm.pending_since.get_or_insert(now);
+            let delay = if alarm.active {
+                alarm.off_delay
+            } else {
+                alarm.on_delay
+            };
+            if now.duration_since(since) < delay {
+                continue;
+            }
+
+            alarm.pending_since = None;
+            alarm.active = !alarm.active;
+            let kind = if alarm.active {
+                alarm.acknowledged = false;
+                alarm.raised_ms = Some(history::now_ms());
+                alarm.value = Some(value);
+                alarm.comments.clear();
+                EventKind::Raised
+            } else {
+                EventKind::Cleared
+            };
+            events.push(AlarmEvent {
+                value: Some(value),
+                ..event_for(alarm, kind)
+            });
+        }
+        for event in events {
+            self.push_event(event);
+        }
+    }
+
+    /// Leaves a channel's pending raises and clears where they are, for
+    /// readings that cannot be trusted.
+    pub fn hold(&mut self, channel: &str) {
+        for alarm in self.alarms.values_mut() {
+            if alarm.channel == channel {
+                alarm.pending_since = None;
+            }
+        }
+    }
+
+    /// Unshelves the alarms whose shelve has run out.
+    pub fn expire_shelves(&mut self, now_ms: u64) {
+        let mut events = Vec::new();
+        for alarm in self.alarms.values_mut() {
+            if alarm.shelved_until_ms.is_some_and(|until| until <= now_ms) {
+                alarm.shelved_until_ms = None;
+                alarm.shelved_by = None;
+                events.push(event_for(alarm, EventKind::Unshelved));
+            }
+        }
+        for event in events {
+            self.push_event(event);
+        }
+    }
+
+    pub fn acknowledge(
+        &mut self,
+        id: &str,
+        user: &str,
+        comment: Option<String>,
+    ) -> Result<Alarm, AlarmError> {
+        let user = check_user(user)?;
+        let comment = check_comment(comment)?;
+        let alarm = self.alarm_mut(id)?;
+        if alarm.acknowledged {
+            return Err(AlarmError::NotPending(format!(
+                "{id} has nothing to acknowledge"
+            )));
+        }
+        alarm.acknowledged = true;
+        add_comment(alarm, &user, comment.as_deref());
+        let alarm = alarm.clone();
+        self.push_event(AlarmEvent {
+            user: Some(user),
+            comment,
+            ..event_for(&alarm, EventKind::Acknowledged)
+        });
+        Ok(alarm)
+    }
+
+    /// Keeps the alarm from raising for `minutes`. If it is raised it stays
+    /// listed until it clears.
+    pub fn shelve(
+        &mut self,
+        id: &str,
+        user: &str,
+        password: &str,
+        minutes: u64,
+        comment: Option<String>,
+    ) -> Result<Alarm, AlarmError> {
+        let max_minutes = self.authorize(user, password)?;
+        if minutes == 0 || minutes > max_minutes {
+            return Err(AlarmError::Invalid(format!(
+                "alarms can be shelved for 1 to {max_minutes} minutes"
+            )));
+        }
+        let comment = check_comment(comment)?;
+        let until = history::now_ms() + minutes * 60 * 1000;
+        let alarm = self.alarm_mut(id)?;
+        alarm.shelved_until_ms = Some(until);
+        alarm.shelved_by = Some(user.to_string());
+        alarm.pending_since = None;
+        add_comment(alarm, user, comment.as_deref());
+        let alarm = alarm.clone();
+        self.push_event(AlarmEvent {
+            user: Some(user.to_string()),
+            comment,
+            shelved_until_ms: Some(until),
+            ..event_for(&alarm, EventKind::Shelved)
+        });
+        Ok(alarm)
+    }
+
+    pub fn unshelve(&mut self, id: &str, user: &str, password: &str) -> Result<Alarm, AlarmError> {
+        self.authorize(user, password)?;
+        let alarm = self.alarm_mut(id)?;
+        if alarm.shelved_until_ms.is_none() {
+            return Err(AlarmError::NotPending(format!("{id} is not shelved")));
+        }
+        alarm.shelved_until_ms = None;
+        alarm.shelved_by = None;
+        let alarm = alarm.clone();
+        self.push_event(AlarmEvent {
+            user: Some(user.to_string()),
+            ..event_for(&alarm, EventKind::Unshelved)
+        });
+        Ok(alarm)
+    }
+
+    pub fn comment(&mut self, id: &str, user: &str, text: &str) -> Result<Alarm, AlarmError> {
+        let user = check_user(user)?;
+        let Some(text) = check_comment(Some(text.to_string()))? else {
+            return Err(AlarmError::Invalid("the comment is empty".to_string()));
+        };
+        let alarm = self.alarm_mut(id)?;
+        add_comment(alarm, &user, Some(&text));
+        let alarm = alarm.clone();
+        self.push_event(AlarmEvent {
+            user: Some(user),
+            comment: Some(text),
+            ..event_for(&alarm, EventKind::Commented)
+        });
+        Ok(alarm)
+    }
+
+    fn alarm_mut(&mut self, id: &str) -> Result<&mut Alarm, AlarmError> {
+        self.alarms
+            .get_mut(id)
+            .ok_or_else(|| AlarmError::UnknownAlarm(id.to_string()))
+    }
+
+    /// Checks the user may shelve, returning the longest shelve allowed.
+    fn authorize(&self, user: &str, password: &str) -> Result<u64, AlarmError> {
+        let shelving = self
+            .shelving
+            .as_ref()
+            .ok_or_else(|| AlarmError::Unauthorized(user.to_string()))?;
+        let allowed = shelving.users.iter().any(|allowed| {
+            allowed.username == user && password::verify(&allowed.password_hash, password)
+        });
+        if !allowed {
+            return Err(AlarmError::Unauthorized(user.to_string()));
+        }
+        Ok(shelving.max_minutes)
+    }
+
+    /// Whether any alarm that is not shelved is raised.
+    pub fn any_raised(&self) -> bool {
+        self.alarms
+            .values()
+            .any(|alarm| alarm.active && alarm.shelved_until_ms.is_none())
+    }
+
+    /// Alarms that are raised or waiting to be acknowledged, highest
+    /// priority first, then newest first.
+    pub fn listed(&self) -> Vec<Alarm> {
+        let mut listed: Vec<Alarm> = self
+            .alarms
+            .values()
+            .filter(|alarm| alarm.is_listed())
+            .cloned()
+            .collect();
+        listed.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then(b.raised_ms.cmp(&a.raised_ms))
+        });
+        listed
+    }
+
+    /// The newest `limit` log entries, oldest first.
+    pub fn log(&self, limit: usize) -> Vec<AlarmEvent> {
+        let skip = self.log.len().saturating_sub(limit);
+        self.log.iter().skip(skip).cloned().collect()
+    }
+
+    /// Live feed of alarm events from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<AlarmEvent> {
+        self.events.subscribe()
+    }
+
+    fn push_event(&mut self, event: AlarmEvent) {
+        println!(
+            "Alarm {} {} ({:?}, value {:?}, user {:?})",
+            event.id,
+            event.event.as_str(),
+            event.priority,
+            event.value,
+            event.user
+        );
+        let written = serde_json::to_string(&event)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(self.log_file, "{}", line));
+        if let Err(e) = written {
+            println!("Failed to write alarm log: {}", e);
+        }
+
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event.clone());
+        }
+        if self.log.len() == self.log_capacity {
+            self.log.pop_front();
+        }
+        self.log.push_back(event);
+        if let Err(e) = self.save_state() {
+            println!("Failed to save alarm state: {}", e);
+        }
+    }
+
+    /// Writes every alarm that is not back to normal to the state file.
+    fn save_state(&self) -> io::Result<()> {
+        let saved: BTreeMap<&str, SavedAlarm> = self
+            .alarms
+            .values()
+            .filter(|alarm| alarm.is_listed() || !alarm.comments.is_empty())
+            .map(|alarm| {
+                let saved = SavedAlarm {
+                    active: alarm.active,
+                    acknowledged: alarm.acknowledged,
+                    raised_ms: alarm.raised_ms,
+                    value: alarm.value,
+                    shelved_until_ms: alarm.shelved_until_ms,
+                    shelved_by: alarm.shelved_by.clone(),
+                    comments: alarm.comments.clone(),
+                };
+                (alarm.id.as_str(), saved)
+            })
+            .collect();
+        let temp = self.state_path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&saved)?)?;
+        fs::rename(&temp, &self.state_path)
+    }
+}
+
+/// An event about `alarm` with only the common fields set.
+fn event_for(alarm: &Alarm, event: EventKind) -> AlarmEvent {
+    AlarmEvent {
+        ts_ms: history::now_ms(),
+        event,
+        id: alarm.id.clone(),
+        channel: alarm.channel.clone(),
+        level: alarm.level,
+        priority: alarm.priority,
+        limit: alarm.limit,
+        value: None,
+        user: None,
+        comment: None,
+        shelved_until_ms: None,
+    }
+}
+
+fn check_user(user: &str) -> Result<String, AlarmError> {
+    let user = user.trim();
+    if user.is_empty() {
+        return Err(AlarmError::Invalid("a user is needed".to_string()));
+    }
+    Ok(user.to_string())
+}
+
+/// The comment without surrounding whitespace, `None` if there is nothing
+/// left.
+fn check_comment(comment: Option<String>) -> Result<Option<String>, AlarmError> {
+    let Some(comment) = comment else {
+        return Ok(None);
+    };
+    let comment = comment.trim();
+    if comment.len() > MAX_COMMENT_BYTES {
+        return Err(AlarmError::Invalid(format!(
+            "comments are limited to {MAX_COMMENT_BYTES} bytes"
+        )));
+    }
+    Ok((!comment.is_empty()).then(|| comment.to_string()))
+}
+
+fn add_comment(alarm: &mut Alarm, user: &str, comment: Option<&str>) {
+    if let Some(text) = comment {
+        alarm.comments.push(AlarmComment {
+            ts_ms: history::now_ms(),
+            user: user.to_string(),
+            text: text.to_string(),
+        });
+    }
+}
+
+/// Saved alarm states by id. A missing or unreadable file starts every
+/// alarm out normal.
+fn read_state(path: &Path) -> BTreeMap<String, SavedAlarm> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(e) => {
+            println!("Failed to read alarm state {}: {}", path.display(), e);
+            return BTreeMap::new();
+        }
+    };
+    serde_json::from_str(&text).unwrap_or_else(|e| {
+        println!("Ignoring alarm state {}: {}", path.display(), e);
+        BTreeMap::new()
+    })
+}
+
+/// The newest `capacity` events of the log file, skipping lines that do not
+/// parse (such as one cut short by a crash).
+fn read_log(path: &Path, capacity: usize) -> io::Result<VecDeque<AlarmEvent>> {
+    let mut log = VecDeque::with_capacity(capacity);
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(log),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let Ok(event) = serde_json::from_str::<AlarmEvent>(&line?) else {
+            continue;
+        };
+        if log.len() == capacity {
+            log.pop_front();
+        }
+        log.push_back(event);
+    }
+    Ok(log)
+}
+
+/// Starts the task that checks every channel against its limits.
+pub fn spawn(engine: Arc<Mutex<AlarmEngine>>, shared_state: SharedState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCAN_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let readings: Vec<(String, f32, Option<Quality>)> = {
+                let io_state = shared_state.snapshot();
+                io_state
+                    .channels
+                    .iter()
+                    .map(|(channel, value)| {
+                        let quality = io_state.quality.get(channel).copied();
+                        (channel.clone(), value.to_f32(), quality)
+                    })
+                    .collect()
+            };
+            let now = Instant::now();
+            let mut engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+            engine.expire_shelves(history::now_ms());
+            for (channel, value, quality) in readings {
+                match quality {
+                    Some(Quality::Good | Quality::Uncertain) => {
+                        engine.evaluate(&channel, value, now)
+                    }
+                    // failed reads, and channels not read yet
+                    Some(Quality::Bad) | None => engine.hold(&channel),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "tank.hi";
+
+    /// An engine with a single Hi alarm on `tank`, logging to a fresh
+    /// directory named after the test.
+    fn engine(test: &str, alarms: AlarmConfig) -> AlarmEngine {
+        let dir =
+            std::env::temp_dir().join(format!("io_server-alarm-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let channels = BTreeMap::from([(
+            "tank".to_string(),
+            ChannelConfig {
+                alarms: Some(alarms),
+                ..ChannelConfig::default()
+            },
+        )]);
+        let shelving = ShelvingConfig {
+            max_minutes: 60,
+            users: vec![ShelvingUser {
+                username: "supervisor".to_string(),
+                password_hash: password::hash("secret").unwrap(),
+            }],
+        };
+        AlarmEngine::open(
+            &channels,
+            Some(shelving),
+            dir.join("alarms.jsonl"),
+            dir.join("alarm_state.json"),
+            100,
+        )
+        .unwrap()
+    }
+
+    fn hi(limit: f32, deadband: f32, on_delay_ms: u64, off_delay_ms: u64) -> AlarmConfig {
+        AlarmConfig {
+            hi: Some(limit),
+            deadband,
+            on_delay_ms,
+            off_delay_ms,
+            ..AlarmConfig::default()
+        }
+    }
+
+    fn alarm(engine: &AlarmEngine) -> &Alarm {
+        &engine.alarms[ID]
+    }
+
+    fn events(engine: &AlarmEngine) -> Vec<EventKind> {
+        engine.log(100).iter().map(|event| event.event).collect()
+    }
+
+    #[test]
+    fn raises_once_past_the_limit_for_the_on_delay() {
+        let mut engine = engine("on_delay", hi(10.0, 0.0, 1000, 0));
+        let start = Instant::now();
+        engine.evaluate("tank", 11.0, start);
+        engine.evaluate("tank", 11.0, start + Duration::from_millis(999));
+        assert!(!alarm(&engine).active);
+        engine.evaluate("tank", 11.0, start + Duration::from_millis(1000));
+        assert!(alarm(&engine).active);
+        assert!(!alarm(&engine).acknowledged);
+        assert_eq!(alarm(&engine).value, Some(11.0));
+        assert_eq!(events(&engine), [EventKind::Raised]);
+    }
+
+    #[test]
+    fn dipping_back_restarts_the_on_delay() {
+        let mut engine = engine("restart", hi(10.0, 0.0, 1000, 0));
+        let start = Instant::now();
+        engine.evaluate("tank", 11.0, start);
+        engine.evaluate("tank", 9.0, start + Duration::from_millis(500));
+        engine.evaluate("tank", 11.0, start + Duration::from_millis(600));
+        engine.evaluate("tank", 11.0, start + Duration::from_millis(1500));
+        assert!(!alarm(&engine).active);
+        engine.evaluate("tank", 11.0, start + Duration::from_millis(1600));
+        assert!(alarm(&engine).active);
+    }
+
+    #[test]
+    fn clears_only_past_the_deadband_after_the_off_delay() {
+        let mut engine = engine("deadband", hi(10.0, 1.0, 0, 500));
+        let start = Instant::now();
+        engine.evaluate("tank", 11.0, start);
+        assert!(alarm(&engine).active);
+        // inside the limit but not the deadband
+        engine.evaluate("tank", 9.5, start + Duration::from_millis(100));
+        engine.evaluate("tank", 9.5, start + Duration::from_millis(2000));
+        assert!(alarm(&engine).active);
+        engine.evaluate("tank", 8.5, start + Duration::from_millis(2100));
+        engine.evaluate("tank", 8.5, start + Duration::from_millis(2599));
+        assert!(alarm(&engine).active);
+        engine.evaluate("tank", 8.5, start + Duration::from_millis(2600));
+        assert!(!alarm(&engine).active);
+        assert_eq!(events(&engine), [EventKind::Raised, EventKind::Cleared]);
+    }
+
+    #[test]
+    fn stays_listed_until_cleared_and_acknowledged() {
+        let mut engine = engine("listed", hi(10.0, 0.0, 0, 0));
+        let now = Instant::now();
+        engine.evaluate("tank", 11.0, now);
+        engine.evaluate("tank", 5.0, now);
+        assert!(!alarm(&engine).active);
+        assert_eq!(engine.listed().len(), 1);
+        engine.acknowledge(ID, "mes", None).unwrap();
+        assert!(engine.listed().is_empty());
+        assert!(matches!(
+            engine.acknowledge(ID, "mes", None),
+            Err(AlarmError::NotPending(_))
+        ));
+    }
+
+    #[test]
+    fn hold_keeps_a_pending_raise_from_completing() {
+        let mut engine = engine("hold", hi(10.0, 0.0, 1000, 0));
+        let start = Instant::now();
+        engine.evaluate("tank", 11.0, start);
+        engine.hold("tank");
+        engine.evaluate("tank", 11.0, start + Duration::from_millis(1000));
+        assert!(!alarm(&engine).active);
+    }
+
+    #[test]
+    fn a_shelved_alarm_does_not_raise_until_the_shelve_runs_out() {
+        let mut engine = engine("shelve", hi(10.0, 0.0, 0, 0));
+        let now = Instant::now();
+        assert!(matches!(
+            engine.shelve(ID, "supervisor", "wrong", 10, None),
+            Err(AlarmError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            engine.shelve(ID, "supervisor", "secret", 61, None),
+            Err(AlarmError::Invalid(_))
+        ));
+        let until = engine
+            .shelve(ID, "supervisor", "secret", 10, None)
+            .unwrap()
+            .shelved_until_ms
+            .unwrap();
+        engine.evaluate("tank", 11.0, now);
+        assert!(!alarm(&engine).active);
+        assert!(!engine.any_raised());
+
+        engine.expire_shelves(until);
+        assert!(alarm(&engine).shelved_until_ms.is_none());
+        engine.evaluate("tank", 11.0, now);
+        assert!(alarm(&engine).active);
+        assert!(engine.any_raised());
+    }
+
+    #[test]
+    fn validate_wants_ordered_limits_and_a_positive_deadband() {
+        assert!(AlarmConfig::default().validate().is_err());
+        assert!(hi(10.0, 0.0, 0, 0).validate().is_ok());
+        assert!(hi(10.0, -1.0, 0, 0).validate().is_err());
+        let crossed = AlarmConfig {
+            lo: Some(20.0),
+            ..hi(10.0, 0.0, 0, 0)
+        };
+        assert!(crossed.validate().is_err());
+    }
+}
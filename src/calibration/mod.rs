@@ -0,0 +1,513 @@
+/// Interactive calibration of the ADC channels.
+///
+/// A session collects points by pairing a known reference value with the raw
+/// volts the channel reads at that moment. Once there are enough points a fit
+/// (linear, piecewise or polynomial) can be previewed against them and then
+/// committed: the scaling is written to `[channels.<name>]` in the config
+/// file together with who calibrated it and when, and takes effect on the
+/// next read without a restart.
+///
+/// The same workflow is served over REST (`/api/calibration/...`) and as
+/// websocket commands.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError};
+use toml_edit::{DocumentMut, InlineTable, Item, Table};
+
+use crate::config::Config;
+use crate::history::Quality;
+use crate::scaling::{Scaling, Scalings};
+use crate::state::SharedState;
+use crate::ADC_CHANNELS;
+
+const DEFAULT_POLYNOMIAL_DEGREE: usize = 2;
+// Higher degrees only fit the noise, and make the normal equations too ill
+// conditioned to solve in floats.
+const MAX_POLYNOMIAL_DEGREE: usize = 5;
+
+/// One reference point: volts read while the input sat at `reference`. Like
+/// the scaling, this uses the channel's filtered volts.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct CalibrationPoint {
+    pub raw: f32,
+    pub reference: f32,
+}
+
+/// Stored with the scaling in the config file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalibrationRecord {
+    pub by: String,
+    /// RFC 3339, UTC.
+    pub at: String,
+    /// The `[raw, reference]` points the fit was made from.
+    pub points: Vec<[f32; 2]>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitKind {
+    #[default]
+    Linear,
+    Piecewise,
+    Polynomial,
+}
+
+/// How to fit the points, as sent by clients.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Fit {
+    #[serde(default)]
+    pub fit: FitKind,
+    /// Polynomial degree, 2 when not given.
+    pub degree: Option<usize>,
+}
+
+impl Fit {
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .degree
+            .is_some_and(|degree| !(1..=MAX_POLYNOMIAL_DEGREE).contains(&degree))
+        {
+            return Err(format!(
+                "degree must be between 1 and {MAX_POLYNOMIAL_DEGREE}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    UnknownChannel(String),
+    /// The request cannot be done with the current points or reading.
+    Invalid(String),
+    /// The config file could not be read, validated or written.
+    Config(String),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::UnknownChannel(channel) => {
+                write!(f, "{channel} is not an ADC channel")
+            }
+            CalibrationError::Invalid(message) => write!(f, "{message}"),
+            CalibrationError::Config(message) => write!(f, "config update failed: {message}"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreviewPoint {
+    pub raw: f32,
+    pub reference: f32,
+    /// What the fit makes of `raw`, and how far that is from `reference`.
+    pub fitted: Option<f32>,
+    pub error: Option<f32>,
+}
+
+/// State of a channel's session and the fit it would get.
+#[derive(Debug, Serialize)]
+pub struct Preview {
+    pub channel: String,
+    pub points: Vec<PreviewPoint>,
+    pub scaling: Option<Scaling>,
+    pub max_error: Option<f32>,
+    /// Why there is no fit yet, if there is none.
+    pub message: Option<String>,
+    /// The live reading, and what it would read as with the fit.
+    pub raw: f32,
+    pub value: Option<f32>,
+}
+
+/// Open sessions plus what is needed to apply and persist a commit.
+pub struct Calibrator {
+    config_path: PathBuf,
+    shared_state: SharedState,
+    scalings: Scalings,
+    sessions: BTreeMap<String, Vec<CalibrationPoint>>,
+}
+
+impl Calibrator {
+    pub(crate) fn new(config_path: PathBuf, shared_state: SharedState, scalings: Scalings) -> Self {
+        Self {
+            config_path,
+            shared_state,
+            scalings,
+            sessions: BTreeMap::new(),
+        }
+    }
+
+    /// Pairs the channel's current raw reading with `reference`.
+    pub fn capture(
+        &mut self,
+        channel: &str,
+        reference: f32,
+        fit: Fit,
+    ) -> Result<Preview, CalibrationError> {
+        check_channel(channel)?;
+        if !reference.is_finite() {
+            return Err(CalibrationError::Invalid(
+                "reference must be a number".to_string(),
+            ));
+        }
+        let raw = {
+            let io_state = self.shared_state.snapshot();
+            if io_state.quality.get(channel) == Some(&Quality::Bad) {
+                return Err(CalibrationError::Invalid(format!(
+                    "{channel} has no good reading to capture"
+                )));
+            }
+            io_state.filtered.get(channel).copied().unwrap_or(0.0)
+        };
+        println!(
+            "Calibration of {}: captured {} V at {}",
+            channel, raw, reference
+        );
+        self.sessions
+            .entry(channel.to_string())
+            .or_default()
+            .push(CalibrationPoint { raw, reference });
+        self.preview(channel, fit)
+    }
+
+    /// Drops the channel's captured points.
+    pub fn discard(&mut self, channel: &str) -> Result<(), CalibrationError> {
+        check_channel(channel)?;
+        self.sessions.remove(channel);
+        Ok(())
+    }
+
+    pub fn preview(&self, channel: &str, fit: Fit) -> Result<Preview, CalibrationError> {
+        check_channel(channel)?;
+        fit.validate().map_err(CalibrationError::Invalid)?;
+        let points = self.sessions.get(channel).cloned().unwrap_or_default();
+        let raw = self
+            .shared_state
+            .snapshot()
+            .filtered
+            .get(channel)
+            .copied()
+            .unwrap_or(0.0);
+        let (scaling, message) = match fit_points(&points, fit) {
+            Ok(scaling) => (Some(scaling), None),
+            Err(message) => (None, Some(message)),
+        };
+
+        let preview_points: Vec<PreviewPoint> = points
+            .iter()
+            .map(|point| {
+                let fitted = scaling.as_ref().map(|scaling| scaling.apply(point.raw).0);
+                PreviewPoint {
+                    raw: point.raw,
+                    reference: point.reference,
+                    fitted,
+                    error: fitted.map(|fitted| fitted - point.reference),
+                }
+            })
+            .collect();
+        let max_error = preview_points
+            .iter()
+            .filter_map(|point| point.error)
+            .map(f32::abs)
+            .reduce(f32::max);
+
+        Ok(Preview {
+            channel: channel.to_string(),
+            points: preview_points,
+            value: scaling.as_ref().map(|scaling| scaling.apply(raw).0),
+            scaling,
+            max_error,
+            message,
+            raw,
+        })
+    }
+
+    /// Fits the captured points, writes the result to the config file and
+    /// applies it. `units` replaces the channel's units when given.
+    pub fn commit(
+        &mut self,
+        channel: &str,
+        fit: Fit,
+        user: &str,
+        units: Option<String>,
+    ) -> Result<Preview, CalibrationError> {
+        check_channel(channel)?;
+        fit.validate().map_err(CalibrationError::Invalid)?;
+        if user.trim().is_empty() {
+            return Err(CalibrationError::Invalid(
+                "a calibration needs a user".to_string(),
+            ));
+        }
+        let points = self.sessions.get(channel).cloned().unwrap_or_default();
+        let scaling = fit_points(&points, fit).map_err(CalibrationError::Invalid)?;
+        let record = CalibrationRecord {
+            by: user.trim().to_string(),
+            at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            points: points
+                .iter()
+                .map(|point| [point.raw, point.reference])
+                .collect(),
+        };
+        self.persist(channel, &scaling, &record, units.as_deref())
+            .map_err(CalibrationError::Config)?;
+
+        println!(
+            "Calibration of {} committed by {}: {:?}",
+            channel, record.by, scaling
+        );
+        let preview = self.preview(channel, fit)?;
+        self.scalings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(channel.to_string(), scaling);
+        if let Some(units) = units {
+            self.shared_state.update(|io_state| {
+                Arc::make_mut(&mut io_state.units).insert(channel.to_string(), units)
+            });
+        }
+        self.sessions.remove(channel);
+        Ok(preview)
+    }
+
+    /// Rewrites `[channels.<channel>]` in the config file, keeping the rest
+    /// of the file (comments included) as it is. The new file is checked to
+    /// load before it replaces the old one.
+    fn persist(
+        &self,
+        channel: &str,
+        scaling: &Scaling,
+        record: &CalibrationRecord,
+        units: Option<&str>,
+    ) -> Result<(), String> {
+        let text = match fs::read_to_string(&self.config_path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: {}", self.config_path.display(), e)),
+        };
+        let mut document: DocumentMut = text.parse().map_err(|e| format!("{e}"))?;
+
+        let channels = document
+            .entry("channels")
+            .or_insert_with(|| {
+                let mut table = Table::new();
+                table.set_implicit(true);
+                Item::Table(table)
+            })
+            .as_table_mut()
+            .ok_or("channels is not a table")?;
+        let section = channels
+            .entry(channel)
+            .or_insert_with(|| Item::Table(Table::new()))
+            .as_table_like_mut()
+            .ok_or_else(|| format!("channels.{channel} is not a table"))?;
+        if let Some(units) = units {
+            section.insert("units", toml_edit::value(units));
+        }
+        section.insert("scaling", Item::Value(inline_table(scaling)?.into()));
+        section.insert("calibration", Item::Value(inline_table(record)?.into()));
+
+        let text = document.to_string();
+        let config: Config = toml::from_str(&text).map_err(|e| format!("{e}"))?;
+        config.validate()?;
+
+        let temp = self.config_path.with_extension("toml.tmp");
+        fs::write(&temp, &text).map_err(|e| format!("{}: {}", temp.display(), e))?;
+        fs::rename(&temp, &self.config_path)
+            .map_err(|e| format!("{}: {}", self.config_path.display(), e))
+    }
+}
+
+fn check_channel(channel: &str) -> Result<(), CalibrationError> {
+    if ADC_CHANNELS.iter().any(|adc| adc.name == channel) {
+        Ok(())
+    } else {
+        Err(CalibrationError::UnknownChannel(channel.to_string()))
+    }
+}
+
+fn inline_table<T: Serialize>(value: &T) -> Result<InlineTable, String> {
+    let text = toml::to_string(value).map_err(|e| format!("{e}"))?;
+    let document: DocumentMut = text.parse().map_err(|e| format!("{e}"))?;
+    Ok(document.as_table().clone().into_inline_table())
+}
+
+/// The scaling that maps the points' raw volts onto their references.
+fn fit_points(points: &[CalibrationPoint], fit: Fit) -> Result<Scaling, String> {
+    if points.len() < 2 {
+        return Err(format!(
+            "{} point(s) captured, at least 2 are needed",
+            points.len()
+        ));
+    }
+    let scaling = match fit.fit {
+        FitKind::Linear => {
+            let (gain, offset) = least_squares_line(points)?;
+            Scaling::Linear { gain, offset }
+        }
+        FitKind::Piecewise => {
+            let mut sorted: Vec<[f32; 2]> = points
+                .iter()
+                .map(|point| [point.raw, point.reference])
+                .collect();
+            sorted.sort_by(|a, b| a[0].total_cmp(&b[0]));
+            Scaling::Piecewise { points: sorted }
+        }
+        FitKind::Polynomial => {
+            let degree = fit.degree.unwrap_or(DEFAULT_POLYNOMIAL_DEGREE);
+            if points.len() <= degree {
+                return Err(format!(
+                    "a degree {degree} polynomial needs at least {} points",
+                    degree + 1
+                ));
+            }
+            Scaling::Polynomial {
+                coefficients: least_squares_polynomial(points, degree)?,
+            }
+        }
+    };
+    scaling.validate()?;
+    Ok(scaling)
+}
+
+fn least_squares_line(points: &[CalibrationPoint]) -> Result<(f32, f32), String> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.raw as f64).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.reference as f64).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.raw as f64 - mean_x).powi(2)).sum();
+    let sxy: f64 = points
+        .iter()
+        .map(|p| (p.raw as f64 - mean_x) * (p.reference as f64 - mean_y))
+        .sum();
+    if sxx == 0.0 {
+        return Err("all points have the same raw reading".to_string());
+    }
+    let gain = sxy / sxx;
+    Ok((gain as f32, (mean_y - gain * mean_x) as f32))
+}
+
+/// Coefficients, lowest order first, from the normal equations solved by
+/// Gaussian elimination.
+fn least_squares_polynomial(
+    points: &[CalibrationPoint],
+    degree: usize,
+) -> Result<Vec<f32>, String> {
+    let size = degree + 1;
+    // augmented matrix [XᵀX | Xᵀy]
+    let mut matrix = vec![vec![0.0f64; size + 1]; size];
+    for point in points {
+        let x = point.raw as f64;
+        let y = point.reference as f64;
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, cell) in row[..size].iter_mut().enumerate() {
+                *cell += x.powi((i + j) as i32);
+            }
+            row[size] += x.powi(i as i32) * y;
+        }
+    }
+
+    for col in 0..size {
+        let pivot = (col..size)
+            .max_by(|a, b| matrix[*a][col].abs().total_cmp(&matrix[*b][col].abs()))
+            .unwrap();
+        if matrix[pivot][col].abs() < 1e-12 {
+            return Err(
+                "points do not determine the polynomial, capture more distinct readings"
+                    .to_string(),
+            );
+        }
+        matrix.swap(col, pivot);
+        let pivot_row = matrix[col].clone();
+        for (i, row) in matrix.iter_mut().enumerate() {
+            if i != col {
+                let factor = row[col] / pivot_row[col];
+                for (cell, pivot_cell) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                    *cell -= factor * pivot_cell;
+                }
+            }
+        }
+    }
+    Ok((0..size)
+        .map(|row| (matrix[row][size] / matrix[row][row]) as f32)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(pairs: &[(f32, f32)]) -> Vec<CalibrationPoint> {
+        pairs
+            .iter()
+            .map(|&(raw, reference)| CalibrationPoint { raw, reference })
+            .collect()
+    }
+
+    fn fit(fit: FitKind, degree: Option<usize>) -> Fit {
+        Fit { fit, degree }
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-3, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn fits_a_line_exactly() {
+        let points = points(&[(0.0, 1.0), (1.0, 3.0), (2.5, 6.0)]);
+        let (gain, offset) = least_squares_line(&points).unwrap();
+        assert_close(&[gain, offset], &[2.0, 1.0]);
+        assert!(matches!(
+            fit_points(&points, fit(FitKind::Linear, None)),
+            Ok(Scaling::Linear { .. })
+        ));
+    }
+
+    #[test]
+    fn fits_a_polynomial_exactly() {
+        // 1 - 2x + 0.5x²
+        let points = points(&[(0.0, 1.0), (1.0, -0.5), (2.0, -1.0), (4.0, 1.0)]);
+        let coefficients = least_squares_polynomial(&points, 2).unwrap();
+        assert_close(&coefficients, &[1.0, -2.0, 0.5]);
+        let line = least_squares_polynomial(&points[..2], 1).unwrap();
+        assert_close(&line, &[1.0, -1.5]);
+    }
+
+    #[test]
+    fn sorts_piecewise_points() {
+        let points = points(&[(2.0, 20.0), (0.0, 0.0), (1.0, 5.0)]);
+        let Ok(Scaling::Piecewise { points }) = fit_points(&points, fit(FitKind::Piecewise, None))
+        else {
+            panic!("not a piecewise scaling");
+        };
+        assert_eq!(points, vec![[0.0, 0.0], [1.0, 5.0], [2.0, 20.0]]);
+    }
+
+    #[test]
+    fn needs_enough_distinct_points() {
+        let one = points(&[(1.0, 1.0)]);
+        assert!(fit_points(&one, fit(FitKind::Linear, None)).is_err());
+        let three = points(&[(0.0, 0.0), (1.0, 1.0), (2.0, 4.0)]);
+        assert!(fit_points(&three, fit(FitKind::Polynomial, Some(3))).is_err());
+        assert!(fit_points(&three, fit(FitKind::Polynomial, Some(2))).is_ok());
+        let same_raw = points(&[(1.0, 0.0), (1.0, 1.0)]);
+        assert!(least_squares_line(&same_raw).is_err());
+        assert!(least_squares_polynomial(&same_raw, 1).is_err());
+    }
+
+    #[test]
+    fn bounds_the_degree() {
+        assert!(fit(FitKind::Polynomial, None).validate().is_ok());
+        assert!(fit(FitKind::Polynomial, Some(MAX_POLYNOMIAL_DEGREE))
+            .validate()
+            .is_ok());
+        for degree in [0, MAX_POLYNOMIAL_DEGREE + 1, usize::MAX] {
+            assert!(fit(FitKind::Polynomial, Some(degree)).validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,344 @@
+/// Settings read from the TOML config file at startup.
+///
+/// Every section is optional; a missing file gives the defaults, which only
+/// run the web server and the sampling loop.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::alarm::{AlarmConfig, ShelvingConfig};
+use crate::calibration::CalibrationRecord;
+use crate::failsafe::FailSafeConfig;
+use crate::filter::Filter;
+use crate::influx::InfluxConfig;
+use crate::interlock::{self, InterlockRule};
+use crate::modbus_master::{self, ModbusDeviceConfig};
+use crate::modbus_server::ModbusServerConfig;
+use crate::mqtt::MqttConfig;
+use crate::opcua_server::OpcUaConfig;
+use crate::password;
+use crate::sampling::{self, I2C_BUDGET};
+use crate::scaling::Scaling;
+use crate::status_led::StatusLedConfig;
+use crate::value::{Precision, Rounding, DEFAULT_DECIMALS, MAX_DECIMALS};
+use crate::watchdog::WatchdogConfig;
+use crate::{
+    default_channel_names, AdcSampling, ADC_CHANNELS, ADS1115_DATA_RATES, MAX_OVERSAMPLING,
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "io_server.toml";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub modbus_server: Option<ModbusServerConfig>,
+    pub modbus_devices: Vec<ModbusDeviceConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub influx: Option<InfluxConfig>,
+    pub opcua: Option<OpcUaConfig>,
+    /// Users allowed to shelve alarms. Without it nobody can.
+    pub alarm_shelving: Option<ShelvingConfig>,
+    /// Conditions the switched outputs need before they may turn on.
+    pub interlocks: Vec<InterlockRule>,
+    /// Safe output levels and the client heartbeat timeout.
+    pub fail_safe: FailSafeConfig,
+    /// Blink times of the status LED.
+    pub status_led: StatusLedConfig,
+    /// Pets a hardware watchdog from the sampling loop. Off when not given.
+    pub watchdog: Option<WatchdogConfig>,
+    /// Per-channel settings keyed by channel name.
+    pub channels: BTreeMap<String, ChannelConfig>,
+}
+
+/// Settings for one channel, `[channels.<name>]`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelConfig {
+    /// Units of the published value. ADC channels default to "V".
+    pub units: Option<String>,
+    /// Decimal places the value is published with, 2 when not given.
+    pub precision: Option<u32>,
+    /// How the value is rounded to `precision`.
+    pub rounding: Rounding,
+    /// Time between reads in milliseconds, ADC channels only. 500 when not
+    /// given.
+    pub period_ms: Option<u64>,
+    /// ADS1115 conversions averaged per read, ADC channels only. 1 when not
+    /// given.
+    pub oversampling: Option<u32>,
+    /// ADS1115 data rate in samples per second, ADC channels only. Defaults to
+    /// 860 when oversampling and 128 otherwise.
+    pub data_rate: Option<u16>,
+    /// Applied in order to the raw volts before scaling, ADC channels only.
+    pub filters: Vec<Filter>,
+    /// Raw volts to engineering units, ADC channels only.
+    pub scaling: Option<Scaling>,
+    /// Written by the calibration workflow along with `scaling`.
+    pub calibration: Option<CalibrationRecord>,
+    /// Limit alarms on the published value.
+    pub alarms: Option<AlarmConfig>,
+}
+
+impl ChannelConfig {
+    pub fn precision(&self) -> Precision {
+        Precision {
+            decimals: self.precision.unwrap_or(DEFAULT_DECIMALS),
+            rounding: self.rounding,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            println!("No config at {}, using defaults", path.display());
+            return Ok(Self::default());
+        }
+        let config = Self::read(path)?;
+        println!("Loaded config from {}", path.display());
+        Ok(config)
+    }
+
+    /// Same as `load` without logging, for commands that write to stdout.
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(path)?;
+        let config: Self =
+            toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        modbus_master::validate(&self.modbus_devices)?;
+        let channels: Vec<String> = default_channel_names()
+            .into_iter()
+            .chain(
+                self.modbus_devices
+                    .iter()
+                    .flat_map(|device| device.registers.iter())
+                    .map(|register| register.channel.clone()),
+            )
+            .collect();
+        for (name, channel) in self.channels.iter() {
+            // a misspelt name would quietly lose its settings and alarms
+            if !channels.contains(name) {
+                return Err(format!(
+                    "channels.{name}: no ADC or Modbus device channel has this name"
+                ));
+            }
+            if channel
+                .precision
+                .is_some_and(|decimals| decimals > MAX_DECIMALS)
+            {
+                return Err(format!(
+                    "channels.{name}: precision is at most {MAX_DECIMALS} decimal places"
+                ));
+            }
+            let is_adc = ADC_CHANNELS.iter().any(|adc| adc.name == name);
+            let adc_only = [
+                ("period_ms", channel.period_ms.is_some()),
+                ("oversampling", channel.oversampling.is_some()),
+                ("data_rate", channel.data_rate.is_some()),
+                ("filters", !channel.filters.is_empty()),
+                ("scaling", channel.scaling.is_some()),
+            ];
+            if let Some((setting, _)) = adc_only.iter().find(|(_, set)| *set && !is_adc) {
+                return Err(format!(
+                    "channels.{name}: {setting} only applies to ADC channels"
+                ));
+            }
+            if channel
+                .oversampling
+                .is_some_and(|conversions| !(1..=MAX_OVERSAMPLING).contains(&conversions))
+            {
+                return Err(format!(
+                    "channels.{name}: oversampling must be between 1 and {MAX_OVERSAMPLING}"
+                ));
+            }
+            if channel
+                .data_rate
+                .is_some_and(|rate| !ADS1115_DATA_RATES.contains(&rate))
+            {
+                return Err(format!(
+                    "channels.{name}: data_rate must be one of {ADS1115_DATA_RATES:?}"
+                ));
+            }
+            let sampling = AdcSampling::new(Some(channel));
+            let min_period = sampling.min_period_ms();
+            if channel.period_ms.is_some_and(|period| period < min_period) {
+                return Err(format!(
+                    "channels.{name}: period_ms must be at least {min_period} at {} samples per \
+                     second and {} conversions per read; raise data_rate or lower oversampling \
+                     for a shorter one",
+                    sampling.data_rate, sampling.conversions
+                ));
+            }
+            for filter in channel.filters.iter() {
+                filter
+                    .validate()
+                    .map_err(|e| format!("channels.{name}: {e}"))?;
+            }
+            if let Some(scaling) = channel.scaling.as_ref() {
+                scaling
+                    .validate()
+                    .map_err(|e| format!("channels.{name}: {e}"))?;
+            }
+            if let Some(alarms) = channel.alarms.as_ref() {
+                alarms
+                    .validate()
+                    .map_err(|e| format!("channels.{name}.alarms: {e}"))?;
+            }
+        }
+        if let Some(shelving) = self.alarm_shelving.as_ref() {
+            if shelving.max_minutes == 0 {
+                return Err("alarm_shelving.max_minutes must be at least 1".to_string());
+            }
+            if shelving
+                .users
+                .iter()
+                .any(|user| user.username.trim().is_empty())
+            {
+                return Err("alarm_shelving.users: username must not be empty".to_string());
+            }
+            for user in shelving.users.iter() {
+                password::validate(&user.password_hash).map_err(|e| {
+                    format!(
+                        "alarm_shelving.users ({}): password_hash is {e}",
+                        user.username
+                    )
+                })?;
+            }
+        }
+        let load = sampling::i2c_load(&self.channels);
+        if load > I2C_BUDGET {
+            return Err(format!(
+                "the ADC channels would keep the I2C bus {:.0}% busy, more than the {:.0}% budget; \
+                 lengthen period_ms or lower oversampling",
+                load * 100.0,
+                I2C_BUDGET * 100.0
+            ));
+        }
+        interlock::validate(&self.interlocks, &channels)?;
+        if let Some(modbus_server) = self.modbus_server.as_ref() {
+            modbus_server.validate(&channels)?;
+        }
+        if let Some(mqtt) = self.mqtt.as_ref() {
+            mqtt.validate()?;
+        }
+        if let Some(influx) = self.influx.as_ref() {
+            influx.validate()?;
+        }
+        if let Some(opcua) = self.opcua.as_ref() {
+            opcua.validate()?;
+        }
+        self.fail_safe.validate()?;
+        self.status_led.validate()?;
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            watchdog.validate(self.pet_interval_ms())?;
+        }
+        Ok(())
+    }
+
+    /// Longest the sampling loop goes between watchdog pets while every read
+    /// succeeds: the period of the most often read ADC channel plus the read.
+    fn pet_interval_ms(&self) -> u64 {
+        ADC_CHANNELS
+            .iter()
+            .map(|adc| {
+                let channel = self.channels.get(adc.name);
+                let period = channel
+                    .and_then(|channel| channel.period_ms)
+                    .unwrap_or(sampling::DEFAULT_PERIOD_MS);
+                let read_ms = AdcSampling::new(channel).bus_time().as_millis() as u64 + 1;
+                period + read_ms
+            })
+            .min()
+            .unwrap_or(sampling::DEFAULT_PERIOD_MS)
+    }
+
+    /// How often each channel gets a new reading, in milliseconds: the ADC
+    /// channels' `period_ms` and the poll interval of the Modbus registers.
+    pub fn channel_periods(&self) -> BTreeMap<String, u64> {
+        let adc = ADC_CHANNELS.iter().map(|channel| {
+            let period = self
+                .channels
+                .get(channel.name)
+                .and_then(|channel| channel.period_ms)
+                .unwrap_or(sampling::DEFAULT_PERIOD_MS);
+            (channel.name.to_string(), period)
+        });
+        let registers = self.modbus_devices.iter().flat_map(|device| {
+            device
+                .registers
+                .iter()
+                .map(|register| (register.channel.clone(), device.poll_interval_ms))
+        });
+        adc.chain(registers).collect()
+    }
+
+    /// Configured precision of every channel that has a `[channels.<name>]`
+    /// section. Other channels use `Precision::default()`.
+    pub fn precisions(&self) -> BTreeMap<String, Precision> {
+        self.channels
+            .iter()
+            .map(|(name, channel)| (name.clone(), channel.precision()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../../io_server.example.toml");
+
+    #[test]
+    fn accepts_the_example_config() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_channel_names() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        let mut misspelt = config.channels.clone();
+        misspelt.insert("adc1_chanel0".to_string(), ChannelConfig::default());
+        let config = Config {
+            channels: misspelt,
+            ..config
+        };
+        let error = config.validate().unwrap_err();
+        assert!(error.starts_with("channels.adc1_chanel0:"), "{error}");
+    }
+
+    #[test]
+    fn needs_a_channel_read_well_inside_the_watchdog_timeout() {
+        let slow = |period_ms| ChannelConfig {
+            period_ms: Some(period_ms),
+            ..ChannelConfig::default()
+        };
+        let mut config = Config {
+            watchdog: Some(toml::from_str("").unwrap()),
+            ..Config::default()
+        };
+        config.validate().unwrap();
+
+        config.channels = ADC_CHANNELS
+            .iter()
+            .map(|adc| (adc.name.to_string(), slow(20_000)))
+            .collect();
+        assert!(config.validate().unwrap_err().starts_with("watchdog:"));
+
+        // one channel read often enough keeps it petted
+        config
+            .channels
+            .insert("adc2_channel3".to_string(), slow(5_000));
+        config.validate().unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+/// Dumps recorded samples as CSV or Parquet for spreadsheets and pandas.
+///
+/// Samples are pulled from a [`Range`] and written out as they are read, with
+/// Parquet buffered one row group at a time, so the size of an export is not
+/// bounded by memory. Values are rounded to each channel's configured
+/// precision, so the CSV shows the same digits as the live view.
+///
+/// Every channel is read on its own schedule, so no two samples need share a
+/// timestamp. The wide layout therefore puts the samples of each
+/// `resolution_ms` window in one row, stamped with the start of the window,
+/// with the last good value of each channel in it.
+use arrow_array::builder::{Float32Builder, StringBuilder, TimestampMillisecondBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Args, ValueEnum};
+use parquet::arrow::ArrowWriter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::history::{self, Quality, Range, Sample};
+use crate::value::Precision;
+
+// Rows per Parquet row group, which is also how many rows are held in memory.
+const PARQUET_BATCH_ROWS: usize = 64 * 1024;
+pub const DEFAULT_RESOLUTION_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+/// `Long` is one row per sample; `Wide` is one row per `resolution_ms` window
+/// with a column per channel.
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Long,
+    Wide,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// `io_server export` options.
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Start of the range, as unix milliseconds or RFC 3339.
+    #[arg(long, value_parser = parse_time)]
+    from: u64,
+    /// End of the range, as unix milliseconds or RFC 3339. Defaults to now.
+    #[arg(long, value_parser = parse_time)]
+    to: Option<u64>,
+    /// Comma separated channel names. Defaults to every ADC channel.
+    #[arg(long, value_delimiter = ',')]
+    channels: Vec<String>,
+    #[arg(long, value_enum, default_value_t)]
+    format: Format,
+    #[arg(long, value_enum, default_value_t)]
+    layout: Layout,
+    /// Wide layout only: milliseconds of samples that share a row.
+    #[arg(long, default_value_t = DEFAULT_RESOLUTION_MS, value_parser = clap::value_parser!(u64).range(1..))]
+    resolution_ms: u64,
+    /// File to write to. Defaults to stdout.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Directory the server records history into.
+    #[arg(long, default_value = crate::HISTORY_DIR)]
+    history_dir: PathBuf,
+}
+
+/// Runs the `export` subcommand against the on-disk history.
+pub fn run(args: ExportArgs, precisions: &BTreeMap<String, Precision>) -> io::Result<()> {
+    let to = args.to.unwrap_or_else(history::now_ms);
+    let channels = if args.channels.is_empty() {
+        crate::default_channel_names()
+    } else {
+        args.channels
+    };
+    let samples = history::read_range(&args.history_dir, Some(&channels), args.from, to)?;
+
+    match args.output {
+        Some(path) => write(
+            samples,
+            &channels,
+            precisions,
+            args.format,
+            args.layout,
+            args.resolution_ms,
+            File::create(path)?,
+        ),
+        None => write(
+            samples,
+            &channels,
+            precisions,
+            args.format,
+            args.layout,
+            args.resolution_ms,
+            io::stdout(),
+        ),
+    }
+}
+
+/// Writes every sample in `samples` to `out`. `channels` gives the column
+/// order and `resolution_ms` (at least 1) the row width for the wide layout.
+/// Channels missing from `precisions` use the default precision.
+pub fn write(
+    samples: Range,
+    channels: &[String],
+    precisions: &BTreeMap<String, Precision>,
+    format: Format,
+    layout: Layout,
+    resolution_ms: u64,
+    out: impl Write + Send,
+) -> io::Result<()> {
+    let precision = |channel: &str| precisions.get(channel).copied().unwrap_or_default();
+    match (format, layout) {
+        (Format::Csv, Layout::Long) => csv_long(samples, precision, out),
+        (Format::Parquet, Layout::Long) => parquet_long(samples, precision, out),
+        (format, Layout::Wide) => {
+            if resolution_ms == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "resolution must be at least 1 ms",
+                ));
+            }
+            let rows = WideRows::new(samples, channels, resolution_ms);
+            match format {
+                Format::Csv => csv_wide(rows, channels, precision, out),
+                Format::Parquet => parquet_wide(rows, channels, precision, out),
+            }
+        }
+    }
+}
+
+/// Accepts unix milliseconds or an RFC 3339 timestamp.
+pub fn parse_time(text: &str) -> Result<u64, String> {
+    if let Ok(ms) = text.parse::<u64>() {
+        return Ok(ms);
+    }
+    DateTime::parse_from_rfc3339(text)
+        .map(|time| time.timestamp_millis().max(0) as u64)
+        .map_err(|e| format!("expected unix milliseconds or RFC 3339 time: {e}"))
+}
+
+fn rfc3339(ts_ms: u64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(ts_ms as i64)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn csv_long(
+    samples: Range,
+    precision: impl Fn(&str) -> Precision,
+    out: impl Write,
+) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["timestamp", "timestamp_ms", "channel", "value", "quality"])?;
+    for sample in samples {
+        let sample = sample?;
+        let value = precision(&sample.channel).format(sample.value);
+        writer.write_record([
+            rfc3339(sample.ts_ms),
+            sample.ts_ms.to_string(),
+            sample.channel,
+            value,
+            sample.quality.as_str().to_string(),
+        ])?;
+    }
+    writer.flush()
+}
+
+fn csv_wide(
+    rows: WideRows<Range>,
+    channels: &[String],
+    precision: impl Fn(&str) -> Precision,
+    out: impl Write,
+) -> io::Result<()> {
+    let precisions: Vec<Precision> = channels.iter().map(|channel| precision(channel)).collect();
+    let mut writer = csv::Writer::from_writer(out);
+    let mut header = vec!["timestamp".to_string(), "timestamp_ms".to_string()];
+    header.extend(channels.iter().cloned());
+    writer.write_record(&header)?;
+
+    for row in rows {
+        let (ts_ms, values) = row?;
+        let mut record = vec![rfc3339(ts_ms), ts_ms.to_string()];
+        record.extend(
+            values
+                .iter()
+                .zip(precisions.iter())
+                .map(|(value, precision)| {
+                    value
+                        .map(|value| precision.format(value))
+                        .unwrap_or_default()
+                }),
+        );
+        writer.write_record(&record)?;
+    }
+    writer.flush()
+}
+
+fn timestamp_field() -> Field {
+    Field::new(
+        "timestamp",
+        DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+        false,
+    )
+}
+
+fn timestamp_builder() -> TimestampMillisecondBuilder {
+    TimestampMillisecondBuilder::new().with_timezone("UTC")
+}
+
+fn parquet_long(
+    samples: Range,
+    precision: impl Fn(&str) -> Precision,
+    out: impl Write + Send,
+) -> io::Result<()> {
+    let schema = Arc::new(Schema::new(vec![
+        timestamp_field(),
+        Field::new("channel", DataType::Utf8, false),
+        Field::new("value", DataType::Float32, false),
+        Field::new("quality", DataType::Utf8, false),
+    ]));
+    let mut writer = ArrowWriter::try_new(out, schema.clone(), None).map_err(io::Error::other)?;
+
+    let mut samples = samples.peekable();
+    while samples.peek().is_some() {
+        let mut timestamps = timestamp_builder();
+        let mut names = StringBuilder::new();
+        let mut values = Float32Builder::new();
+        let mut qualities = StringBuilder::new();
+        for sample in samples.by_ref().take(PARQUET_BATCH_ROWS) {
+            let sample = sample?;
+            timestamps.append_value(sample.ts_ms as i64);
+            names.append_value(&sample.channel);
+            values.append_value(precision(&sample.channel).round(sample.value));
+            qualities.append_value(sample.quality.as_str());
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(timestamps.finish()),
+            Arc::new(names.finish()),
+            Arc::new(values.finish()),
+            Arc::new(qualities.finish()),
+        ];
+        let batch = RecordBatch::try_new(schema.clone(), columns).map_err(io::Error::other)?;
+        writer.write(&batch).map_err(io::Error::other)?;
+        writer.flush().map_err(io::Error::other)?;
+    }
+    writer.close().map_err(io::Error::other)?;
+    Ok(())
+}
+
+fn parquet_wide(
+    rows: WideRows<Range>,
+    channels: &[String],
+    precision: impl Fn(&str) -> Precision,
+    out: impl Write + Send,
+) -> io::Result<()> {
+    let precisions: Vec<Precision> = channels.iter().map(|channel| precision(channel)).collect();
+    let mut fields = vec![timestamp_field()];
+    fields.extend(
+        channels
+            .iter()
+            .map(|channel| Field::new(channel, DataType::Float32, true)),
+    );
+    let schema = Arc::new(Schema::new(fields));
+    let mut writer = ArrowWriter::try_new(out, schema.clone(), None).map_err(io::Error::other)?;
+
+    let mut rows = rows.peekable();
+    while rows.peek().is_some() {
+        let mut timestamps = timestamp_builder();
+        let mut columns: Vec<Float32Builder> =
+            channels.iter().map(|_| Float32Builder::new()).collect();
+        for row in rows.by_ref().take(PARQUET_BATCH_ROWS) {
+            let (ts_ms, values) = row?;
+            timestamps.append_value(ts_ms as i64);
+            for ((column, value), precision) in columns.iter_mut().zip(values).zip(&precisions) {
+                column.append_option(value.map(|value| precision.round(value)));
+            }
+        }
+        let mut arrays: Vec<ArrayRef> = vec![Arc::new(timestamps.finish())];
+        arrays.extend(
+            columns
+                .iter_mut()
+                .map(|column| Arc::new(column.finish()) as ArrayRef),
+        );
+        let batch = RecordBatch::try_new(schema.clone(), arrays).map_err(io::Error::other)?;
+        writer.write(&batch).map_err(io::Error::other)?;
+        writer.flush().map_err(io::Error::other)?;
+    }
+    writer.close().map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Start of the `resolution_ms` window `ts_ms` falls in.
+fn row_start(ts_ms: u64, resolution_ms: u64) -> u64 {
+    ts_ms - ts_ms % resolution_ms
+}
+
+/// Groups time-ordered samples into one row per `resolution_ms` window,
+/// keeping the last good value of each channel. Channels with no good
+/// reading in the window are left empty.
+struct WideRows<'a, I: Iterator<Item = io::Result<Sample>>> {
+    samples: Peekable<I>,
+    channels: &'a [String],
+    resolution_ms: u64,
+}
+
+impl<'a, I: Iterator<Item = io::Result<Sample>>> WideRows<'a, I> {
+    fn new(samples: I, channels: &'a [String], resolution_ms: u64) -> Self {
+        Self {
+            samples: samples.peekable(),
+            channels,
+            resolution_ms,
+        }
+    }
+
+    fn place(&self, values: &mut [Option<f32>], sample: &Sample) {
+        if sample.quality == Quality::Bad {
+            return;
+        }
+        if let Some(i) = self.channels.iter().position(|c| *c == sample.channel) {
+            values[i] = Some(sample.value);
+        }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Sample>>> Iterator for WideRows<'_, I> {
+    type Item = io::Result<(u64, Vec<Option<f32>>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.samples.next()? {
+            Ok(sample) => sample,
+            Err(e) => return Some(Err(e)),
+        };
+        let start = row_start(first.ts_ms, self.resolution_ms);
+        let mut values = vec![None; self.channels.len()];
+        self.place(&mut values, &first);
+
+        while let Some(Ok(sample)) = self.samples.peek() {
+            if row_start(sample.ts_ms, self.resolution_ms) != start {
+                break;
+            }
+            let sample = self.samples.next().unwrap().unwrap();
+            self.place(&mut values, &sample);
+        }
+        Some(Ok((start, values)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(ts_ms: u64, channel: &str, value: f32, quality: Quality) -> io::Result<Sample> {
+        Ok(Sample {
+            ts_ms,
+            channel: channel.to_string(),
+            value,
+            quality,
+        })
+    }
+
+    fn rows(samples: Vec<io::Result<Sample>>, resolution_ms: u64) -> Vec<(u64, Vec<Option<f32>>)> {
+        let channels = ["a".to_string(), "b".to_string()];
+        WideRows::new(samples.into_iter(), &channels, resolution_ms)
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn wide_rows_share_the_window_of_channels_read_apart() {
+        let samples = vec![
+            sample(1000, "a", 1.0, Quality::Good),
+            sample(1003, "b", 2.0, Quality::Good),
+            sample(1510, "a", 1.5, Quality::Good),
+            sample(2004, "b", 3.0, Quality::Good),
+        ];
+        assert_eq!(
+            rows(samples, 1000),
+            [
+                (1000, vec![Some(1.5), Some(2.0)]),
+                (2000, vec![None, Some(3.0)])
+            ]
+        );
+    }
+
+    #[test]
+    fn wide_rows_keep_exact_timestamps_at_one_millisecond() {
+        let samples = vec![
+            sample(1000, "a", 1.0, Quality::Good),
+            sample(1000, "b", 2.0, Quality::Good),
+            sample(1001, "a", 1.5, Quality::Good),
+        ];
+        assert_eq!(
+            rows(samples, 1),
+            [
+                (1000, vec![Some(1.0), Some(2.0)]),
+                (1001, vec![Some(1.5), None])
+            ]
+        );
+    }
+
+    #[test]
+    fn wide_rows_leave_out_bad_readings() {
+        let samples = vec![
+            sample(1000, "a", 1.0, Quality::Good),
+            sample(1200, "a", 9.0, Quality::Bad),
+            sample(1300, "b", 9.0, Quality::Bad),
+        ];
+        assert_eq!(rows(samples, 1000), [(1000, vec![Some(1.0), None])]);
+    }
+}
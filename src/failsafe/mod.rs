@@ -0,0 +1,295 @@
+/// Safe output states for when the server cannot be trusted to drive them.
+///
+/// Every output has a safe level, off unless `[[fail_safe.outputs]]` says
+/// otherwise. The outputs are driven to it and held there when the server is
+/// asked to stop (SIGTERM or SIGINT), when a task that drives the IO panics
+/// (see `io_task`), and when no
+/// client has sent a heartbeat within `heartbeat_timeout_ms`. While held,
+/// commands that would move an output away from its safe level are refused.
+/// A hold for lost heartbeats ends with the next heartbeat, but the outputs
+/// stay at their safe levels until they are switched again; the other holds
+/// last until the process exits. The pins keep their level after the process
+/// is gone.
+use rppal::gpio::OutputPin;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// How long the panic hook waits for another thread to let go of the outputs.
+const PANIC_LOCK_ATTEMPTS: u32 = 10;
+const PANIC_LOCK_RETRY: Duration = Duration::from_millis(10);
+
+tokio::task_local! {
+    // set while an IO task is being polled, for the panic hook
+    static IO_TASK: ();
+}
+
+/// `[fail_safe]`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailSafeConfig {
+    /// Outputs go safe when no client heartbeat arrives for this long. Not
+    /// checked when not given.
+    pub heartbeat_timeout_ms: Option<u64>,
+    pub outputs: Vec<OutputConfig>,
+}
+
+/// `[[fail_safe.outputs]]`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    /// GPIO pin number.
+    pub pin: u8,
+    pub safe_state: bool,
+}
+
+impl FailSafeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.heartbeat_timeout_ms == Some(0) {
+            return Err("fail_safe.heartbeat_timeout_ms must be at least 1".to_string());
+        }
+        for (index, output) in self.outputs.iter().enumerate() {
+            if self.outputs[..index]
+                .iter()
+                .any(|other| other.pin == output.pin)
+            {
+                return Err(format!(
+                    "fail_safe.outputs: pin {} is listed twice",
+                    output.pin
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Safe level of every listed pin, other pins are safe when off.
+    pub fn safe_states(&self) -> BTreeMap<u8, bool> {
+        self.outputs
+            .iter()
+            .map(|output| (output.pin, output.safe_state))
+            .collect()
+    }
+}
+
+/// Why the outputs are held at their safe levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hold {
+    HeartbeatLost,
+    Shutdown,
+    Panic,
+}
+
+impl Hold {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Hold::HeartbeatLost => "no client heartbeat",
+            Hold::Shutdown => "shutting down",
+            Hold::Panic => "a task panicked",
+        }
+    }
+}
+
+/// The output pins, shared by whatever switches them.
+pub struct Outputs {
+    pins: BTreeMap<u8, OutputPin>,
+    // driven by the status LED task rather than by commands, and not listed
+    // with the other outputs
+    status_led: Option<(u8, OutputPin)>,
+    safe_states: BTreeMap<u8, bool>,
+    held: Option<Hold>,
+    last_heartbeat: Instant,
+    heartbeat_timeout: Option<Duration>,
+}
+
+impl Outputs {
+    pub fn new(config: &FailSafeConfig) -> Self {
+        Self {
+            pins: BTreeMap::new(),
+            status_led: None,
+            safe_states: config.safe_states(),
+            held: None,
+            last_heartbeat: Instant::now(),
+            heartbeat_timeout: config.heartbeat_timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// Takes over a pin, starting it at its safe level.
+    pub fn add(&mut self, pin: u8, mut output: OutputPin) {
+        // keep the safe level once the process has exited instead of
+        // letting the pin float
+        output.set_reset_on_drop(false);
+        output.write(self.safe_state(pin).into());
+        self.pins.insert(pin, output);
+    }
+
+    /// Takes over the status LED pin, starting it at its safe level like
+    /// the other outputs.
+    pub fn add_status_led(&mut self, pin: u8, mut output: OutputPin) {
+        output.set_reset_on_drop(false);
+        output.write(self.safe_state(pin).into());
+        self.status_led = Some((pin, output));
+    }
+
+    /// Switches the status LED. While the outputs are held it stays at its
+    /// safe level and this does nothing.
+    pub fn set_status_led(&mut self, high: bool) {
+        if self.held.is_some() {
+            return;
+        }
+        if let Some((_, output)) = self.status_led.as_mut() {
+            output.write(high.into());
+        }
+    }
+
+    pub fn safe_state(&self, pin: u8) -> bool {
+        self.safe_states.get(&pin).copied().unwrap_or(false)
+    }
+
+    pub fn is_high(&self, pin: u8) -> Option<bool> {
+        self.pins.get(&pin).map(|output| output.is_set_high())
+    }
+
+    /// Levels of every output by pin.
+    pub fn levels(&self) -> BTreeMap<u8, bool> {
+        self.pins
+            .iter()
+            .map(|(pin, output)| (*pin, output.is_set_high()))
+            .collect()
+    }
+
+    /// Whether `set` would drive the pin to `high`: always, unless the
+    /// outputs are held and `high` is not its safe level.
+    pub fn permits(&self, pin: u8, high: bool) -> bool {
+        self.held.is_none() || high == self.safe_state(pin)
+    }
+
+    /// Drives the pin, unless the outputs are held and `high` is not its
+    /// safe level. Returns whether the pin is now at `high`.
+    pub fn set(&mut self, pin: u8, high: bool) -> bool {
+        if let Some(hold) = self.held {
+            if !self.permits(pin, high) {
+                println!(
+                    "Refusing to switch output {} {}, outputs are held safe: {}",
+                    pin,
+                    if high { "on" } else { "off" },
+                    hold.as_str()
+                );
+                return false;
+            }
+        }
+        match self.pins.get_mut(&pin) {
+            Some(output) => {
+                output.write(high.into());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn toggle(&mut self, pin: u8) -> bool {
+        match self.is_high(pin) {
+            Some(high) => self.set(pin, !high),
+            None => false,
+        }
+    }
+
+    /// Drives every output to its safe level and keeps it there.
+    pub fn hold(&mut self, hold: Hold) {
+        // a shutdown or panic is not undone by the next heartbeat
+        if self.held.is_some_and(|held| held != Hold::HeartbeatLost) && hold == Hold::HeartbeatLost
+        {
+            return;
+        }
+        if self.held != Some(hold) {
+            println!("Outputs going to their safe states: {}", hold.as_str());
+        }
+        self.held = Some(hold);
+        let status_led = self
+            .status_led
+            .as_mut()
+            .map(|(pin, output)| (&*pin, output));
+        for (pin, output) in self.pins.iter_mut().chain(status_led) {
+            let safe = self.safe_states.get(pin).copied().unwrap_or(false);
+            output.write(safe.into());
+        }
+    }
+
+    pub fn held(&self) -> Option<Hold> {
+        self.held
+    }
+
+    /// A client is alive.
+    pub fn heartbeat(&mut self) {
+        self.last_heartbeat = Instant::now();
+        if self.held == Some(Hold::HeartbeatLost) {
+            println!("Client heartbeat is back, outputs may be switched again");
+            self.held = None;
+        }
+    }
+
+    fn check_heartbeat(&mut self) {
+        let Some(timeout) = self.heartbeat_timeout else {
+            return;
+        };
+        if self.held.is_none() && self.last_heartbeat.elapsed() > timeout {
+            self.hold(Hold::HeartbeatLost);
+        }
+    }
+}
+
+/// Locks the outputs. If a task panicked while holding them they are put in
+/// their safe states first.
+pub fn lock(outputs: &Mutex<Outputs>) -> MutexGuard<'_, Outputs> {
+    outputs.lock().unwrap_or_else(|poisoned| {
+        let mut outputs = poisoned.into_inner();
+        outputs.hold(Hold::Panic);
+        outputs
+    })
+}
+
+/// Runs one of the tasks that read or drive the IO (sampling, inputs,
+/// outputs, status LED), so that a panic in it holds the outputs safe.
+pub async fn io_task<F: Future>(task: F) -> F::Output {
+    IO_TASK.scope((), task).await
+}
+
+/// Makes a panic in an IO task, or on the main thread while it sets them up,
+/// put the outputs in their safe states after the usual panic message. Other
+/// panics, in a web handler say, only end their own task.
+pub fn install_panic_hook(outputs: Arc<Mutex<Outputs>>) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let in_io_task = IO_TASK.try_with(|_| ()).is_ok();
+        if !in_io_task && std::thread::current().name() != Some("main") {
+            return;
+        }
+        for _ in 0..PANIC_LOCK_ATTEMPTS {
+            match outputs.try_lock() {
+                Ok(mut outputs) => return outputs.hold(Hold::Panic),
+                Err(TryLockError::Poisoned(poisoned)) => {
+                    return poisoned.into_inner().hold(Hold::Panic)
+                }
+                Err(TryLockError::WouldBlock) => std::thread::sleep(PANIC_LOCK_RETRY),
+            }
+        }
+        // most likely the panicking thread holds them; once it has unwound
+        // the next lock() finds them poisoned and holds them then
+        println!("Could not get hold of the outputs from the panic hook");
+    }));
+}
+
+/// Checks the client heartbeat, and for a poisoned lock, until the process
+/// exits.
+pub fn spawn(outputs: Arc<Mutex<Outputs>>) {
+    tokio::spawn(io_task(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            lock(&outputs).check_heartbeat();
+        }
+    }));
+}
@@ -0,0 +1,219 @@
+/// Smoothing of noisy ADC readings.
+///
+/// A channel can list any number of filters in `[channels.<name>]`; each
+/// reading goes through them in order, before the scaling. Only good reads
+/// are filtered, so a failed read neither resets nor skews the history a
+/// filter keeps. The unfiltered volts stay available as the channel's raw
+/// value.
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Filter {
+    /// Mean of the last `window` readings.
+    MovingAverage { window: usize },
+    /// Median of the last `window` readings, good against spikes.
+    Median { window: usize },
+    /// First-order low-pass, `out += alpha * (reading - out)`. Smaller
+    /// `alpha` smooths more.
+    Exponential { alpha: f32 },
+    /// Replaces a reading further than `max_deviation` from the median of the
+    /// last `window` readings with that median. A real step passes once it
+    /// makes up half the window.
+    Outlier { window: usize, max_deviation: f32 },
+}
+
+impl Filter {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Filter::MovingAverage { window } | Filter::Median { window } => check_window(*window),
+            Filter::Exponential { alpha } => {
+                if *alpha > 0.0 && *alpha <= 1.0 {
+                    Ok(())
+                } else {
+                    Err("exponential filter alpha must be in (0, 1]".to_string())
+                }
+            }
+            Filter::Outlier {
+                window,
+                max_deviation,
+            } => {
+                if max_deviation.is_nan() || *max_deviation < 0.0 {
+                    return Err("outlier filter max_deviation must not be negative".to_string());
+                }
+                check_window(*window)
+            }
+        }
+    }
+}
+
+fn check_window(window: usize) -> Result<(), String> {
+    if window == 0 {
+        return Err("filter window must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+/// The filters of one channel together with the readings they remember.
+pub struct FilterChain {
+    stages: Vec<Stage>,
+}
+
+struct Stage {
+    filter: Filter,
+    window: VecDeque<f32>,
+    output: Option<f32>,
+}
+
+impl FilterChain {
+    pub fn new(filters: &[Filter]) -> Self {
+        Self {
+            stages: filters
+                .iter()
+                .map(|filter| Stage {
+                    filter: filter.clone(),
+                    window: VecDeque::new(),
+                    output: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Feeds one reading through every filter and returns the result.
+    pub fn apply(&mut self, reading: f32) -> f32 {
+        self.stages
+            .iter_mut()
+            .fold(reading, |value, stage| stage.apply(value))
+    }
+}
+
+impl Stage {
+    fn apply(&mut self, value: f32) -> f32 {
+        let output = match self.filter {
+            Filter::MovingAverage { window } => {
+                self.push(value, window);
+                self.window.iter().sum::<f32>() / self.window.len() as f32
+            }
+            Filter::Median { window } => {
+                self.push(value, window);
+                self.median()
+            }
+            Filter::Exponential { alpha } => match self.output {
+                Some(previous) => previous + alpha * (value - previous),
+                None => value,
+            },
+            Filter::Outlier {
+                window,
+                max_deviation,
+            } => {
+                self.push(value, window);
+                let median = self.median();
+                if (value - median).abs() > max_deviation {
+                    median
+                } else {
+                    value
+                }
+            }
+        };
+        self.output = Some(output);
+        output
+    }
+
+    fn push(&mut self, value: f32, window: usize) {
+        if self.window.len() == window {
+            self.window.pop_front();
+        }
+        self.window.push_back(value);
+    }
+
+    fn median(&self) -> f32 {
+        let mut sorted: Vec<f32> = self.window.iter().copied().collect();
+        sorted.sort_by(f32::total_cmp);
+        let middle = sorted.len() / 2;
+        if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        } else {
+            sorted[middle]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(filters: &[Filter], readings: &[f32]) -> Vec<f32> {
+        let mut chain = FilterChain::new(filters);
+        readings
+            .iter()
+            .map(|reading| chain.apply(*reading))
+            .collect()
+    }
+
+    #[test]
+    fn moving_average_covers_the_last_window_readings() {
+        let filters = [Filter::MovingAverage { window: 3 }];
+        assert_eq!(run(&filters, &[1.0, 2.0, 3.0, 7.0]), [1.0, 1.5, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn median_ignores_a_spike() {
+        let filters = [Filter::Median { window: 3 }];
+        assert_eq!(
+            run(&filters, &[1.0, 100.0, 2.0, 3.0]),
+            [1.0, 50.5, 2.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn exponential_starts_at_the_first_reading() {
+        let filters = [Filter::Exponential { alpha: 0.5 }];
+        assert_eq!(run(&filters, &[0.0, 10.0, 10.0]), [0.0, 5.0, 7.5]);
+    }
+
+    #[test]
+    fn outlier_passes_a_step_once_it_fills_half_the_window() {
+        let filters = [Filter::Outlier {
+            window: 3,
+            max_deviation: 1.0,
+        }];
+        assert_eq!(
+            run(&filters, &[1.0, 1.0, 10.0, 10.0, 1.5]),
+            [1.0, 1.0, 1.0, 10.0, 10.0]
+        );
+    }
+
+    #[test]
+    fn filters_apply_in_order() {
+        let filters = [
+            Filter::Outlier {
+                window: 3,
+                max_deviation: 1.0,
+            },
+            Filter::MovingAverage { window: 2 },
+        ];
+        assert_eq!(run(&filters, &[2.0, 2.0, 50.0, 4.0]), [2.0, 2.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn validate_rejects_settings_that_cannot_filter() {
+        assert!(Filter::MovingAverage { window: 0 }.validate().is_err());
+        assert!(Filter::Median { window: 0 }.validate().is_err());
+        assert!(Filter::Exponential { alpha: 0.0 }.validate().is_err());
+        assert!(Filter::Exponential { alpha: 1.5 }.validate().is_err());
+        assert!(Filter::Exponential { alpha: 1.0 }.validate().is_ok());
+        assert!(Filter::Outlier {
+            window: 3,
+            max_deviation: -1.0
+        }
+        .validate()
+        .is_err());
+        assert!(Filter::Outlier {
+            window: 3,
+            max_deviation: f32::NAN
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,167 @@
+/// Digital inputs and switched outputs, each in a task of its own.
+///
+/// The input task polls the input pins every few milliseconds. The output
+/// task runs each command as soon as it arrives, so a client toggling an
+/// output does not wait for the ADC, and checks the interlocks again whenever
+/// the inputs change, an ADC channel is read or a short interval passes (for
+/// channels polled from Modbus devices).
+use rppal::gpio::Gpio;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::failsafe::{self, Outputs};
+use crate::interlock::Interlocks;
+use crate::state::SharedState;
+use crate::{OutputCommand, DIGITAL_INPUTS, SWITCHED_OUTPUTS};
+
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const INTERLOCK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// Rechecks queued for the output task; when it is behind one more is no use.
+const EVENT_QUEUE: usize = 8;
+
+/// Something the interlocks may have to look at again.
+#[derive(Debug)]
+pub enum IoEvent {
+    InputsChanged,
+    ChannelRead,
+}
+
+pub fn event_channel() -> (Sender<IoEvent>, Receiver<IoEvent>) {
+    mpsc::channel(EVENT_QUEUE)
+}
+
+/// Polls the digital inputs into `io_state.inputs`, telling `events` when
+/// one changes.
+pub fn spawn_inputs(
+    shared_state: SharedState,
+    events: Sender<IoEvent>,
+) -> Result<(), rppal::gpio::Error> {
+    let gpio = Gpio::new()?;
+    let input_pins = DIGITAL_INPUTS
+        .iter()
+        .map(|(name, pin)| Ok((*name, gpio.get(*pin)?.into_input_pulldown())))
+        .collect::<Result<Vec<_>, rppal::gpio::Error>>()?;
+    tokio::spawn(failsafe::io_task(async move {
+        let mut interval = tokio::time::interval(INPUT_POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut last: Option<Vec<bool>> = None;
+        loop {
+            interval.tick().await;
+            let levels: Vec<bool> = input_pins.iter().map(|(_, pin)| pin.is_high()).collect();
+            if last.as_ref() == Some(&levels) {
+                continue;
+            }
+            shared_state.update(|io_state| {
+                for ((name, _), high) in input_pins.iter().zip(levels.iter()) {
+                    Arc::make_mut(&mut io_state.inputs).insert(name.to_string(), *high);
+                }
+            });
+            last = Some(levels);
+            // a full queue already has a recheck coming
+            let _ = events.try_send(IoEvent::InputsChanged);
+        }
+    }));
+    Ok(())
+}
+
+/// Takes over the switched outputs and runs the commands from `commands`
+/// as they come.
+pub fn spawn_outputs(
+    mut commands: Receiver<OutputCommand>,
+    mut events: Receiver<IoEvent>,
+    shared_state: SharedState,
+    outputs: Arc<Mutex<Outputs>>,
+    interlocks: Arc<Mutex<Interlocks>>,
+) -> Result<(), rppal::gpio::Error> {
+    let gpio = Gpio::new()?;
+    {
+        let mut outputs = failsafe::lock(&outputs);
+        for pin in SWITCHED_OUTPUTS {
+            outputs.add(pin, gpio.get(pin)?.into_output());
+        }
+    }
+    tokio::spawn(failsafe::io_task(async move {
+        let mut interval = tokio::time::interval(INTERLOCK_CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                command = commands.recv() => {
+                    let Some(command) = command else {
+                        println!("Every output command sender is gone, stopping the output task");
+                        break;
+                    };
+                    execute(command, &shared_state, &outputs, &interlocks);
+                }
+                Some(_) = events.recv() => enforce(&shared_state, &outputs, &interlocks),
+                _ = interval.tick() => enforce(&shared_state, &outputs, &interlocks),
+            }
+        }
+    }));
+    Ok(())
+}
+
+fn execute(
+    command: OutputCommand,
+    shared_state: &SharedState,
+    outputs: &Mutex<Outputs>,
+    interlocks: &Mutex<Interlocks>,
+) {
+    let (pin_id, high) = match command {
+        OutputCommand::LedToggle(pin_id) => {
+            println!("Got an output command: toggle {}", pin_id);
+            (pin_id, None)
+        }
+        OutputCommand::LedSet(pin_id, high) => {
+            println!("Got an output command: {} -> {}", pin_id, high);
+            (pin_id, Some(high))
+        }
+    };
+    let Some(pin) = u8::try_from(pin_id)
+        .ok()
+        .filter(|pin| SWITCHED_OUTPUTS.contains(pin))
+    else {
+        println!("Ignoring command for {}, not a switched output", pin_id);
+        return;
+    };
+    let mut outputs = failsafe::lock(outputs);
+    let Some(high) = high.or_else(|| outputs.is_high(pin).map(|high| !high)) else {
+        return;
+    };
+    if interlocks
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .permits(pin, high, &shared_state.snapshot())
+    {
+        outputs.set(pin, high);
+    }
+    publish_levels(shared_state, outputs.levels());
+}
+
+/// Forces outputs that lost their interlock back to their safe state.
+fn enforce(shared_state: &SharedState, outputs: &Mutex<Outputs>, interlocks: &Mutex<Interlocks>) {
+    let mut outputs = failsafe::lock(outputs);
+    let forced = interlocks
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .enforce(&outputs.levels(), &shared_state.snapshot());
+    for (pin, high) in forced {
+        outputs.set(pin, high);
+    }
+    publish_levels(shared_state, outputs.levels());
+}
+
+/// Publishes the output levels, only when one changed so readers that wait
+/// for a new snapshot are not woken for nothing. Called with the outputs
+/// locked, so no other writer changes them in between.
+fn publish_levels(shared_state: &SharedState, levels: BTreeMap<u8, bool>) {
+    let published = shared_state.snapshot();
+    if levels
+        .iter()
+        .all(|(pin, high)| published.outputs.get(pin) == Some(high))
+    {
+        return;
+    }
+    shared_state.update(|io_state| Arc::make_mut(&mut io_state.outputs).extend(levels));
+}
@@ -0,0 +1,732 @@
+/// Time-series storage for channel samples.
+///
+/// Every sample is kept in a bounded in-memory ring and appended to JSON-lines
+/// segment files on disk. Segments are rotated by size and age, and whole
+/// segments are deleted once they fall outside the retention limits. On start
+/// the newest segments are replayed into the ring so a restart does not lose
+/// the recent trend.
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_SUFFIX: &str = ".jsonl";
+
+// Samples buffered per subscriber before a slow one starts missing samples.
+const SUBSCRIBER_BACKLOG: usize = 4096;
+
+/// How far a value can be trusted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    #[default]
+    Good,
+    Uncertain,
+    /// The read failed; the value is the last known good one and is not used
+    /// for aggregates.
+    Bad,
+}
+
+impl Quality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Uncertain => "uncertain",
+            Quality::Bad => "bad",
+        }
+    }
+}
+
+/// A single reading of one channel.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sample {
+    /// Milliseconds since the unix epoch.
+    pub ts_ms: u64,
+    pub channel: String,
+    pub value: f32,
+    #[serde(default)]
+    pub quality: Quality,
+}
+
+/// Aggregate of the samples falling in one `[ts_ms, ts_ms + step)` window.
+/// A bucket with no samples is a gap and carries no values or quality.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Bucket {
+    pub ts_ms: u64,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub avg: Option<f32>,
+    pub last: Option<f32>,
+    pub count: usize,
+    pub quality: Option<Quality>,
+}
+
+/// Limits for what is kept on disk.
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// Segments whose newest sample is older than this are deleted.
+    pub max_age: Duration,
+    /// Oldest segments are deleted while the total exceeds this many bytes.
+    pub max_bytes: u64,
+    /// A segment is closed once it grows past this many bytes.
+    pub segment_bytes: u64,
+    /// A segment is closed once it spans more than this much time.
+    pub segment_span: Duration,
+}
+
+struct Segment {
+    start_ms: u64,
+    bytes: u64,
+    writer: BufWriter<File>,
+}
+
+pub struct History {
+    ring: VecDeque<Sample>,
+    capacity: usize,
+    dir: PathBuf,
+    retention: Retention,
+    segment: Option<Segment>,
+    subscribers: broadcast::Sender<Sample>,
+}
+
+impl History {
+    /// Opens (or creates) the store in `dir` and replays the newest samples
+    /// into the in-memory ring.
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        capacity: usize,
+        retention: Retention,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut history = Self {
+            ring: VecDeque::with_capacity(capacity),
+            capacity,
+            dir,
+            retention,
+            segment: None,
+            subscribers: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+        };
+        history.enforce_retention(now_ms())?;
+        history.reload()?;
+        Ok(history)
+    }
+
+    /// Appends a sample to the ring and the current segment.
+    pub fn record(&mut self, sample: Sample) -> io::Result<()> {
+        self.rotate_if_needed(sample.ts_ms)?;
+
+        let segment = self.segment.as_mut().expect("segment opened by rotate");
+        let mut line = serde_json::to_string(&sample)?;
+        line.push('\n');
+        segment.writer.write_all(line.as_bytes())?;
+        segment.bytes += line.len() as u64;
+
+        if self.subscribers.receiver_count() > 0 {
+            let _ = self.subscribers.send(sample.clone());
+        }
+        self.push_ring(sample);
+        Ok(())
+    }
+
+    /// Live feed of every sample recorded from now on, for exporters.
+    pub fn subscribe(&self) -> broadcast::Receiver<Sample> {
+        self.subscribers.subscribe()
+    }
+
+    /// Pushes buffered samples to disk. Called every `HISTORY_FLUSH_INTERVAL`
+    /// so a crash loses at most that much.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.segment.as_mut() {
+            Some(segment) => segment.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Lazily yields the samples of `channels` (all when `None`) with
+    /// timestamps in `[from_ms, to_ms]`, oldest first.
+    ///
+    /// Only the ring is copied here; older samples are read from disk as the
+    /// returned iterator is advanced, so callers should drop the lock on the
+    /// store before consuming it.
+    pub fn range(
+        &self,
+        channels: Option<&[String]>,
+        from_ms: u64,
+        to_ms: u64,
+    ) -> io::Result<Range> {
+        let channels = channels.map(|channels| channels.to_vec());
+        let wanted = |sample: &Sample| {
+            sample.ts_ms >= from_ms
+                && sample.ts_ms <= to_ms
+                && channels
+                    .as_ref()
+                    .is_none_or(|c| c.contains(&sample.channel))
+        };
+
+        // Samples stamped with the ring's oldest timestamp may have been cut off
+        // partway through that millisecond, so those come from disk and the ring only
+        // covers what is strictly newer.
+        let ring_from_ms = self.ring.front().map_or(u64::MAX, |sample| sample.ts_ms);
+        let ring: Vec<Sample> = self
+            .ring
+            .iter()
+            .filter(|sample| sample.ts_ms > ring_from_ms && wanted(sample))
+            .cloned()
+            .collect();
+
+        let segments = if from_ms <= ring_from_ms {
+            segments_between(&self.dir, from_ms, to_ms.min(ring_from_ms))?
+        } else {
+            VecDeque::new()
+        };
+
+        Ok(Range {
+            channels,
+            from_ms,
+            to_ms: to_ms.min(ring_from_ms),
+            segments,
+            lines: None,
+            ring: ring.into_iter(),
+        })
+    }
+
+    fn push_ring(&mut self, sample: Sample) {
+        if self.ring.len() == self.capacity {
+            self.ring.pop_front();
+        }
+        self.ring.push_back(sample);
+    }
+
+    fn rotate_if_needed(&mut self, ts_ms: u64) -> io::Result<()> {
+        let expired = match self.segment.as_ref() {
+            Some(segment) => {
+                segment.bytes >= self.retention.segment_bytes
+                    || ts_ms.saturating_sub(segment.start_ms)
+                        >= self.retention.segment_span.as_millis() as u64
+            }
+            None => true,
+        };
+        if !expired {
+            return Ok(());
+        }
+
+        if let Some(mut segment) = self.segment.take() {
+            segment.writer.flush()?;
+        }
+        self.enforce_retention(ts_ms)?;
+
+        let path = self.dir.join(segment_name(ts_ms));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let bytes = file.metadata()?.len();
+        self.segment = Some(Segment {
+            start_ms: ts_ms,
+            bytes,
+            writer: BufWriter::new(file),
+        });
+        Ok(())
+    }
+
+    /// Deletes segments that are too old or push the store over its size budget.
+    fn enforce_retention(&self, now_ms: u64) -> io::Result<()> {
+        let segments = list_segments(&self.dir)?;
+        let max_age_ms = self.retention.max_age.as_millis() as u64;
+
+        // A segment only holds samples older than the one that follows it, so
+        // its age is judged by the next segment's start.
+        let mut keep = Vec::new();
+        for (i, (_, path)) in segments.iter().enumerate() {
+            let newest_ms = segments.get(i + 1).map(|(next, _)| *next).unwrap_or(now_ms);
+            if now_ms.saturating_sub(newest_ms) > max_age_ms {
+                println!("history: dropping expired segment {}", path.display());
+                fs::remove_file(path)?;
+            } else {
+                keep.push((path.clone(), fs::metadata(path)?.len()));
+            }
+        }
+
+        let mut total: u64 = keep.iter().map(|(_, bytes)| bytes).sum();
+        for (path, bytes) in keep.iter() {
+            if total <= self.retention.max_bytes {
+                break;
+            }
+            println!(
+                "history: dropping segment {} to stay under size limit",
+                path.display()
+            );
+            fs::remove_file(path)?;
+            total -= bytes;
+        }
+        Ok(())
+    }
+
+    /// Fills the ring from the newest segments on disk.
+    fn reload(&mut self) -> io::Result<()> {
+        let mut loaded: VecDeque<Sample> = VecDeque::new();
+        for (_, path) in list_segments(&self.dir)?.iter().rev() {
+            let samples = read_segment(path)?;
+            for sample in samples.into_iter().rev() {
+                if loaded.len() == self.capacity {
+                    break;
+                }
+                loaded.push_front(sample);
+            }
+            if loaded.len() == self.capacity {
+                break;
+            }
+        }
+        println!(
+            "history: restored {} samples from {}",
+            loaded.len(),
+            self.dir.display()
+        );
+        self.ring = loaded;
+        Ok(())
+    }
+}
+
+/// Reads samples straight from the segment files in `dir` without opening the
+/// store, so another process can export while the server keeps recording.
+pub fn read_range(
+    dir: &Path,
+    channels: Option<&[String]>,
+    from_ms: u64,
+    to_ms: u64,
+) -> io::Result<Range> {
+    Ok(Range {
+        channels: channels.map(|channels| channels.to_vec()),
+        from_ms,
+        to_ms,
+        segments: segments_between(dir, from_ms, to_ms)?,
+        lines: None,
+        ring: Vec::new().into_iter(),
+    })
+}
+
+/// Samples read back from the store. See [`History::range`].
+pub struct Range {
+    channels: Option<Vec<String>>,
+    from_ms: u64,
+    /// Upper bound for samples taken from disk; the ring supplies the rest.
+    to_ms: u64,
+    segments: VecDeque<PathBuf>,
+    lines: Option<Lines<BufReader<File>>>,
+    ring: std::vec::IntoIter<Sample>,
+}
+
+impl Range {
+    fn wanted(&self, sample: &Sample) -> bool {
+        sample.ts_ms >= self.from_ms
+            && sample.ts_ms <= self.to_ms
+            && self
+                .channels
+                .as_ref()
+                .is_none_or(|channels| channels.contains(&sample.channel))
+    }
+}
+
+impl Iterator for Range {
+    type Item = io::Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(lines) = self.lines.as_mut() {
+                match lines.next() {
+                    Some(Ok(line)) => {
+                        // a torn line from a crash is skipped like in `read_segment`
+                        if let Ok(sample) = serde_json::from_str::<Sample>(&line) {
+                            if self.wanted(&sample) {
+                                return Some(Ok(sample));
+                            }
+                        }
+                        continue;
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => self.lines = None,
+                }
+            }
+
+            match self.segments.pop_front() {
+                Some(path) => match File::open(&path) {
+                    Ok(file) => self.lines = Some(BufReader::new(file).lines()),
+                    // retention may have removed it since the range was taken
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Some(Err(e)),
+                },
+                None => return self.ring.next().map(Ok),
+            }
+        }
+    }
+}
+
+/// Folds samples into fixed `step_ms` buckets covering `[from_ms, to_ms]`.
+///
+/// Bad samples only count towards the bucket's quality; min/max/avg/last come
+/// from the usable ones. Buckets without samples are returned empty so gaps
+/// show up as gaps instead of being interpolated over. A range that ends
+/// before it starts has no buckets; a zero `step_ms` is an error.
+pub fn downsample(
+    samples: impl Iterator<Item = io::Result<Sample>>,
+    from_ms: u64,
+    to_ms: u64,
+    step_ms: u64,
+) -> io::Result<Vec<Bucket>> {
+    #[derive(Default)]
+    struct Acc {
+        count: usize,
+        usable: usize,
+        bad: usize,
+        uncertain: usize,
+        min: f32,
+        max: f32,
+        sum: f64,
+        last: Option<(u64, f32)>,
+    }
+
+    if step_ms == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bucket step must be at least 1 ms",
+        ));
+    }
+    if to_ms < from_ms {
+        return Ok(Vec::new());
+    }
+    let bucket_count = ((to_ms - from_ms) / step_ms + 1) as usize;
+    let mut accs: Vec<Acc> = (0..bucket_count).map(|_| Acc::default()).collect();
+
+    for sample in samples {
+        let sample = sample?;
+        if sample.ts_ms < from_ms || sample.ts_ms > to_ms {
+            continue;
+        }
+        let acc = &mut accs[((sample.ts_ms - from_ms) / step_ms) as usize];
+        acc.count += 1;
+        match sample.quality {
+            Quality::Bad => {
+                acc.bad += 1;
+                continue;
+            }
+            Quality::Uncertain => acc.uncertain += 1,
+            Quality::Good => {}
+        }
+        if acc.usable == 0 {
+            acc.min = sample.value;
+            acc.max = sample.value;
+        } else {
+            acc.min = acc.min.min(sample.value);
+            acc.max = acc.max.max(sample.value);
+        }
+        acc.usable += 1;
+        acc.sum += sample.value as f64;
+        if acc.last.is_none_or(|(ts_ms, _)| sample.ts_ms >= ts_ms) {
+            acc.last = Some((sample.ts_ms, sample.value));
+        }
+    }
+
+    let buckets = accs
+        .into_iter()
+        .enumerate()
+        .map(|(i, acc)| {
+            let ts_ms = from_ms + i as u64 * step_ms;
+            if acc.count == 0 {
+                return Bucket {
+                    ts_ms,
+                    ..Bucket::default()
+                };
+            }
+            let quality = if acc.usable == 0 {
+                Quality::Bad
+            } else if acc.bad > 0 || acc.uncertain > 0 {
+                Quality::Uncertain
+            } else {
+                Quality::Good
+            };
+            let usable = acc.usable > 0;
+            Bucket {
+                ts_ms,
+                min: usable.then_some(acc.min),
+                max: usable.then_some(acc.max),
+                avg: usable.then(|| (acc.sum / acc.usable as f64) as f32),
+                last: acc.last.map(|(_, value)| value),
+                count: acc.count,
+                quality: Some(quality),
+            }
+        })
+        .collect();
+    Ok(buckets)
+}
+
+/// Milliseconds since the unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn segment_name(start_ms: u64) -> String {
+    format!("{}{:013}{}", SEGMENT_PREFIX, start_ms, SEGMENT_SUFFIX)
+}
+
+/// Segment files in `dir` as (start time, path), oldest first.
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let start_ms = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+            .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+            .and_then(|stamp| stamp.parse::<u64>().ok());
+        if let Some(start_ms) = start_ms {
+            segments.push((start_ms, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Segments in `dir` that may hold samples in `[from_ms, to_ms]`, oldest first.
+fn segments_between(dir: &Path, from_ms: u64, to_ms: u64) -> io::Result<VecDeque<PathBuf>> {
+    let all = list_segments(dir)?;
+    let mut segments = VecDeque::new();
+    for (i, (start_ms, path)) in all.iter().enumerate() {
+        let next_ms = all.get(i + 1).map_or(u64::MAX, |(next, _)| *next);
+        if *start_ms <= to_ms && next_ms >= from_ms {
+            segments.push_back(path.clone());
+        }
+    }
+    Ok(segments)
+}
+
+/// Reads every sample in a segment. A torn final line from a crash is skipped.
+fn read_segment(path: &Path) -> io::Result<Vec<Sample>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut samples = Vec::new();
+    for line in reader.lines() {
+        if let Ok(sample) = serde_json::from_str::<Sample>(&line?) {
+            samples.push(sample);
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(ts_ms: u64, value: f32, quality: Quality) -> io::Result<Sample> {
+        Ok(Sample {
+            ts_ms,
+            channel: "tank".to_string(),
+            value,
+            quality,
+        })
+    }
+
+    #[test]
+    fn downsample_rejects_a_zero_step() {
+        let samples = vec![sample(0, 1.0, Quality::Good)];
+        assert!(downsample(samples.into_iter(), 0, 10, 0).is_err());
+    }
+
+    #[test]
+    fn downsample_of_a_reversed_range_is_empty() {
+        let samples = vec![sample(5, 1.0, Quality::Good)];
+        assert!(downsample(samples.into_iter(), 10, 0, 1)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn downsample_folds_samples_into_buckets() {
+        let samples = vec![
+            sample(0, 1.0, Quality::Good),
+            sample(5, 3.0, Quality::Good),
+            sample(25, 7.0, Quality::Good),
+            // outside the range
+            sample(31, 9.0, Quality::Good),
+        ];
+        let buckets = downsample(samples.into_iter(), 0, 30, 10).unwrap();
+        assert_eq!(buckets.len(), 4);
+        assert_eq!(buckets[0].count, 2);
+        assert_eq!(buckets[0].min, Some(1.0));
+        assert_eq!(buckets[0].max, Some(3.0));
+        assert_eq!(buckets[0].avg, Some(2.0));
+        assert_eq!(buckets[0].last, Some(3.0));
+        assert_eq!(buckets[0].quality, Some(Quality::Good));
+        // a gap stays a gap
+        assert_eq!(buckets[1].count, 0);
+        assert_eq!(buckets[1].avg, None);
+        assert_eq!(buckets[1].quality, None);
+        assert_eq!(buckets[2].last, Some(7.0));
+        assert_eq!(buckets[3].ts_ms, 30);
+        assert_eq!(buckets[3].count, 0);
+    }
+
+    #[test]
+    fn downsample_keeps_bad_samples_out_of_the_values() {
+        let samples = vec![
+            sample(0, 1.0, Quality::Good),
+            sample(1, 100.0, Quality::Bad),
+            sample(10, 50.0, Quality::Bad),
+        ];
+        let buckets = downsample(samples.into_iter(), 0, 19, 10).unwrap();
+        assert_eq!(buckets[0].count, 2);
+        assert_eq!(buckets[0].max, Some(1.0));
+        assert_eq!(buckets[0].quality, Some(Quality::Uncertain));
+        assert_eq!(buckets[1].count, 1);
+        assert_eq!(buckets[1].avg, None);
+        assert_eq!(buckets[1].last, None);
+        assert_eq!(buckets[1].quality, Some(Quality::Bad));
+    }
+
+    #[test]
+    fn downsample_takes_the_latest_sample_as_last() {
+        let samples = vec![sample(8, 2.0, Quality::Good), sample(3, 1.0, Quality::Good)];
+        let buckets = downsample(samples.into_iter(), 0, 9, 10).unwrap();
+        assert_eq!(buckets[0].last, Some(2.0));
+    }
+
+    /// A fresh directory under the system temp dir, removed again on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("io_server-history-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn retention() -> Retention {
+        Retention {
+            max_age: Duration::from_secs(24 * 3600),
+            max_bytes: u64::MAX,
+            segment_bytes: u64::MAX,
+            segment_span: Duration::from_secs(3600),
+        }
+    }
+
+    fn record(history: &mut History, ts_ms: &[u64]) {
+        for (i, ts_ms) in ts_ms.iter().enumerate() {
+            history
+                .record(sample(*ts_ms, i as f32, Quality::Good).unwrap())
+                .unwrap();
+        }
+        history.flush().unwrap();
+    }
+
+    fn timestamps(samples: impl IntoIterator<Item = Sample>) -> Vec<u64> {
+        samples.into_iter().map(|sample| sample.ts_ms).collect()
+    }
+
+    fn segment_starts(dir: &Path) -> Vec<u64> {
+        list_segments(dir)
+            .unwrap()
+            .into_iter()
+            .map(|(start_ms, _)| start_ms)
+            .collect()
+    }
+
+    #[test]
+    fn ring_keeps_the_newest_and_range_reads_the_rest_from_disk() {
+        let dir = TempDir::new("ring");
+        let now = now_ms();
+        let all: Vec<u64> = (0..5).map(|i| now + i).collect();
+        let mut history = History::open(&dir.0, 3, retention()).unwrap();
+        record(&mut history, &all);
+
+        assert_eq!(timestamps(history.ring.iter().cloned()), all[2..]);
+        let range = history.range(None, 0, u64::MAX).unwrap();
+        let range: Vec<Sample> = range.map(Result::unwrap).collect();
+        assert_eq!(timestamps(range), all);
+    }
+
+    #[test]
+    fn rotates_segments_by_size_and_by_span() {
+        let dir = TempDir::new("rotate");
+        let now = now_ms();
+        let by_size = Retention {
+            segment_bytes: 1,
+            ..retention()
+        };
+        let mut history = History::open(&dir.0, 10, by_size).unwrap();
+        record(&mut history, &[now, now + 1, now + 2]);
+        assert_eq!(segment_starts(&dir.0), vec![now, now + 1, now + 2]);
+        drop(history);
+
+        let dir = TempDir::new("span");
+        let by_span = Retention {
+            segment_span: Duration::from_millis(1000),
+            ..retention()
+        };
+        let mut history = History::open(&dir.0, 10, by_span).unwrap();
+        record(&mut history, &[now, now + 500, now + 999, now + 1000]);
+        assert_eq!(segment_starts(&dir.0), vec![now, now + 1000]);
+    }
+
+    #[test]
+    fn prunes_segments_by_age_and_by_size() {
+        let dir = TempDir::new("age");
+        let now = now_ms();
+        let day = 24 * 3600 * 1000;
+        let by_span = Retention {
+            segment_span: Duration::from_millis(1),
+            ..retention()
+        };
+        let mut history = History::open(&dir.0, 10, by_span).unwrap();
+        // the first segment ends where the second starts, over a day ago
+        record(&mut history, &[now - 3 * day, now - 2 * day, now]);
+        assert_eq!(segment_starts(&dir.0), vec![now - 2 * day, now]);
+        drop(history);
+
+        let dir = TempDir::new("size");
+        let mut history = History::open(&dir.0, 10, by_span).unwrap();
+        record(&mut history, &[now, now + 1, now + 2]);
+        let segment_bytes = fs::metadata(list_segments(&dir.0).unwrap()[0].1.clone())
+            .unwrap()
+            .len();
+        history.retention.max_bytes = segment_bytes * 2;
+        record(&mut history, &[now + 3]);
+        // the oldest go until the closed ones fit, then the new one opens
+        assert_eq!(segment_starts(&dir.0), vec![now + 1, now + 2, now + 3]);
+    }
+
+    #[test]
+    fn reloads_the_newest_samples_on_open() {
+        let dir = TempDir::new("reload");
+        let now = now_ms();
+        let all: Vec<u64> = (0..5).map(|i| now + i).collect();
+        let by_size = Retention {
+            segment_bytes: 100,
+            ..retention()
+        };
+        let mut history = History::open(&dir.0, 10, by_size).unwrap();
+        record(&mut history, &all);
+        drop(history);
+        // a torn line from a crash is skipped
+        let (_, last) = list_segments(&dir.0).unwrap().pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(last).unwrap();
+        file.write_all(b"{\"ts_ms\": 12").unwrap();
+
+        let history = History::open(&dir.0, 3, by_size).unwrap();
+        assert_eq!(timestamps(history.ring.iter().cloned()), all[2..]);
+        let history = History::open(&dir.0, 10, by_size).unwrap();
+        assert_eq!(timestamps(history.ring.iter().cloned()), all);
+    }
+}
@@ -0,0 +1,461 @@
+/// InfluxDB writer: batches recorded samples into line protocol and POSTs
+/// them to the v1 or v2 write API.
+///
+/// Every sample that goes into the history store is also sent here, so Influx
+/// sees the same values and qualities as the exports. A batch that cannot be
+/// written is appended to a file in the buffer directory and retried, oldest
+/// first, once the endpoint accepts writes again. The buffer is capped; when
+/// it is full the oldest batches are dropped. Only transport errors, 429 and
+/// 5xx responses are retried; a batch the server refuses outright is dropped,
+/// or when it comes from the buffer renamed to `.rejected` so it doesn't block
+/// the batches behind it. On shutdown the last batch goes to the buffer
+/// first, so it is kept for the next start however long the server takes,
+/// and the buffer is then replayed for at most `SHUTDOWN_REPLAY_TIME`.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+use crate::history::{self, Sample};
+use crate::shutdown::Shutdown;
+
+const BUFFER_PREFIX: &str = "batch-";
+const BUFFER_SUFFIX: &str = ".lp";
+const REJECTED_EXTENSION: &str = "rejected";
+// Well inside the time main gives the writer to stop.
+const SHUTDOWN_REPLAY_TIME: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InfluxConfig {
+    /// Base URL of the server, e.g. "http://influx.lan:8086".
+    pub url: String,
+    #[serde(flatten)]
+    pub api: InfluxApi,
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    /// Extra tags added to every point, e.g. `site = "lab"`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// A batch is sent once it holds this many points...
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// ...or when it is this old, whichever comes first.
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_buffer_dir")]
+    pub buffer_dir: PathBuf,
+    #[serde(default = "default_max_buffer_bytes")]
+    pub max_buffer_bytes: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "api", rename_all = "lowercase")]
+pub enum InfluxApi {
+    /// `/write` on InfluxDB 1.x.
+    V1 {
+        database: String,
+        retention_policy: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// `/api/v2/write` on InfluxDB 2.x and later, authorized with a token.
+    V2 {
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+fn default_measurement() -> String {
+    "io".to_string()
+}
+
+fn default_batch_size() -> usize {
+    5000
+}
+
+fn default_flush_interval_ms() -> u64 {
+    1000
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_buffer_dir() -> PathBuf {
+    PathBuf::from("influx_buffer")
+}
+
+fn default_max_buffer_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+impl InfluxConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.flush_interval_ms == 0 {
+            return Err("influx.flush_interval_ms must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Why a write failed, and so whether sending the batch again can help.
+enum WriteError {
+    /// The server couldn't be reached, was overloaded or failed itself.
+    Retry(Box<dyn Error + Send + Sync>),
+    /// The server refused the batch, sending it again gets the same answer.
+    Rejected(String),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::Retry(e) => write!(f, "{}", e),
+            WriteError::Rejected(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Starts the writer task on the given sample feed. The task ends once the
+/// last batch is dealt with after `shutdown`.
+pub fn spawn(
+    config: InfluxConfig,
+    samples: broadcast::Receiver<Sample>,
+    shutdown: Shutdown,
+) -> io::Result<JoinHandle<()>> {
+    fs::create_dir_all(&config.buffer_dir)?;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+        .map_err(io::Error::other)?;
+    Ok(tokio::spawn(run(config, client, samples, shutdown)))
+}
+
+async fn run(
+    config: InfluxConfig,
+    client: reqwest::Client,
+    mut samples: broadcast::Receiver<Sample>,
+    shutdown: Shutdown,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(config.flush_interval_ms));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut batch: Vec<String> = Vec::with_capacity(config.batch_size);
+
+    loop {
+        tokio::select! {
+            received = samples.recv() => match received {
+                Ok(sample) => {
+                    if let Some(line) = line(&config, &sample) {
+                        batch.push(line);
+                    }
+                    if batch.len() < config.batch_size {
+                        continue;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    println!("Influx writer fell behind, {} samples not sent", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    flush(&config, &client, &mut batch).await;
+                    return;
+                }
+            },
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => {
+                while let Ok(sample) = samples.try_recv() {
+                    if let Some(line) = line(&config, &sample) {
+                        batch.push(line);
+                    }
+                }
+                if !batch.is_empty() {
+                    if let Err(e) = buffer(&config, &batch.join("\n")) {
+                        println!("Failed to buffer the last Influx batch: {}", e);
+                    }
+                }
+                // a write cut short leaves its file in the buffer
+                match tokio::time::timeout(SHUTDOWN_REPLAY_TIME, replay(&config, &client)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => println!("Influx replay stopped, kept for the next start: {}", e),
+                    Err(_) => println!("Influx replay cut short, the rest is kept for the next start"),
+                }
+                println!("Influx writer stopped");
+                return;
+            }
+        }
+        flush(&config, &client, &mut batch).await;
+    }
+}
+
+/// Sends the pending batch, buffering it on failure. After a successful
+/// write (or on an idle tick) anything buffered is replayed.
+async fn flush(config: &InfluxConfig, client: &reqwest::Client, batch: &mut Vec<String>) {
+    if !send(config, client, batch).await {
+        return;
+    }
+    if let Err(e) = replay(config, client).await {
+        println!("Influx replay stopped: {}", e);
+    }
+}
+
+/// Sends the pending batch, buffering it on failure. Returns whether the
+/// endpoint took it, true as well when there was nothing to send.
+async fn send(config: &InfluxConfig, client: &reqwest::Client, batch: &mut Vec<String>) -> bool {
+    if batch.is_empty() {
+        return true;
+    }
+    let body = batch.join("\n");
+    batch.clear();
+    match write(config, client, body.clone()).await {
+        Ok(()) => true,
+        Err(WriteError::Rejected(e)) => {
+            println!("Influx rejected batch, dropping it: {}", e);
+            true
+        }
+        Err(e @ WriteError::Retry(_)) => {
+            println!("Influx write failed, buffering batch: {}", e);
+            if let Err(e) = buffer(config, &body) {
+                println!("Failed to buffer Influx batch: {}", e);
+            }
+            false
+        }
+    }
+}
+
+async fn write(
+    config: &InfluxConfig,
+    client: &reqwest::Client,
+    body: String,
+) -> Result<(), WriteError> {
+    let base = config.url.trim_end_matches('/');
+    let request = match &config.api {
+        InfluxApi::V1 {
+            database,
+            retention_policy,
+            username,
+            password,
+        } => {
+            let mut query = vec![("db", database.as_str()), ("precision", "ms")];
+            if let Some(rp) = retention_policy {
+                query.push(("rp", rp));
+            }
+            let request = client.post(format!("{base}/write")).query(&query);
+            // basic auth rather than u/p in the query, which would end up in
+            // the URL of every logged error
+            match (username, password) {
+                (Some(username), Some(password)) => request.basic_auth(username, Some(password)),
+                _ => request,
+            }
+        }
+        InfluxApi::V2 { org, bucket, token } => client
+            .post(format!("{base}/api/v2/write"))
+            .query(&[
+                ("org", org.as_str()),
+                ("bucket", bucket.as_str()),
+                ("precision", "ms"),
+            ])
+            .header("Authorization", format!("Token {token}")),
+    };
+
+    let response = request
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| WriteError::Retry(e.into()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let text = response.text().await.unwrap_or_default();
+    let message = format!("{}: {}", status, text.trim());
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        Err(WriteError::Retry(message.into()))
+    } else {
+        Err(WriteError::Rejected(message))
+    }
+}
+
+/// Stores a failed batch and trims the buffer to `max_buffer_bytes`.
+fn buffer(config: &InfluxConfig, body: &str) -> io::Result<()> {
+    let mut path = buffer_path(&config.buffer_dir, history::now_ms(), 0);
+    // several failures can land in the same millisecond
+    let mut seq = 0;
+    while path.exists() {
+        seq += 1;
+        path = buffer_path(&config.buffer_dir, history::now_ms(), seq);
+    }
+    fs::write(&path, body)?;
+
+    let files = buffered_files(&config.buffer_dir)?;
+    let mut total: u64 = files.iter().map(|(_, len)| len).sum();
+    for (path, len) in files {
+        if total <= config.max_buffer_bytes {
+            break;
+        }
+        println!("Influx buffer full, dropping {}", path.display());
+        fs::remove_file(&path)?;
+        total -= len;
+    }
+    Ok(())
+}
+
+/// Sends buffered batches oldest first, deleting each once written. Stops at
+/// the first failure worth retrying so the order is kept; a refused batch is
+/// set aside and the replay goes on.
+async fn replay(
+    config: &InfluxConfig,
+    client: &reqwest::Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for (path, _) in buffered_files(&config.buffer_dir)? {
+        let body = fs::read_to_string(&path)?;
+        match write(config, client, body).await {
+            Ok(()) => {
+                fs::remove_file(&path)?;
+                println!("Influx replayed {}", path.display());
+            }
+            Err(WriteError::Rejected(e)) => {
+                let rejected = path.with_extension(REJECTED_EXTENSION);
+                fs::rename(&path, &rejected)?;
+                println!(
+                    "Influx rejected {}, moved to {}: {}",
+                    path.display(),
+                    rejected.display(),
+                    e
+                );
+            }
+            Err(WriteError::Retry(e)) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Zero padded so the names sort by age.
+fn buffer_path(dir: &Path, ts_ms: u64, seq: u32) -> PathBuf {
+    dir.join(format!(
+        "{BUFFER_PREFIX}{ts_ms:013}-{seq:04}{BUFFER_SUFFIX}"
+    ))
+}
+
+/// Buffered batches with their sizes, oldest first.
+fn buffered_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(BUFFER_PREFIX) && name.ends_with(BUFFER_SUFFIX) {
+            files.push((entry.path(), entry.metadata()?.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// One point: `<measurement>,channel=<c>[,<tags>] value=<v>,quality="<q>" <ts_ms>`.
+/// Influx has no representation for NaN or infinity, so those are skipped.
+fn line(config: &InfluxConfig, sample: &Sample) -> Option<String> {
+    if !sample.value.is_finite() {
+        return None;
+    }
+    let mut line = escape(&config.measurement, &[',', ' ']);
+    line.push_str(",channel=");
+    line.push_str(&escape(&sample.channel, &[',', '=', ' ']));
+    for (key, value) in config.tags.iter() {
+        if key.is_empty() || value.is_empty() {
+            continue;
+        }
+        line.push(',');
+        line.push_str(&escape(key, &[',', '=', ' ']));
+        line.push('=');
+        line.push_str(&escape(value, &[',', '=', ' ']));
+    }
+    line.push_str(&format!(
+        " value={},quality=\"{}\" {}",
+        sample.value,
+        sample.quality.as_str(),
+        sample.ts_ms
+    ));
+    Some(line)
+}
+
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Quality;
+
+    fn config(measurement: &str, tags: &[(&str, &str)]) -> InfluxConfig {
+        InfluxConfig {
+            url: "http://localhost:8086".to_string(),
+            api: InfluxApi::V2 {
+                org: "lab".to_string(),
+                bucket: "io".to_string(),
+                token: "token".to_string(),
+            },
+            measurement: measurement.to_string(),
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            batch_size: default_batch_size(),
+            flush_interval_ms: default_flush_interval_ms(),
+            timeout_ms: default_timeout_ms(),
+            buffer_dir: default_buffer_dir(),
+            max_buffer_bytes: default_max_buffer_bytes(),
+        }
+    }
+
+    fn sample(channel: &str, value: f32) -> Sample {
+        Sample {
+            ts_ms: 1_700_000_000_000,
+            channel: channel.to_string(),
+            value,
+            quality: Quality::Good,
+        }
+    }
+
+    #[test]
+    fn escape_prefixes_special_characters_and_backslashes() {
+        assert_eq!(escape("plain", &[',', '=', ' ']), "plain");
+        assert_eq!(escape("a,b=c d", &[',', '=', ' ']), "a\\,b\\=c\\ d");
+        assert_eq!(escape("back\\slash", &[',']), "back\\\\slash");
+        // measurements don't escape '='
+        assert_eq!(escape("a=b", &[',', ' ']), "a=b");
+    }
+
+    #[test]
+    fn line_escapes_measurement_channel_and_tags() {
+        let config = config("io data", &[("site name", "lab,1"), ("empty", "")]);
+        assert_eq!(
+            line(&config, &sample("tank level=1", 2.5)).unwrap(),
+            "io\\ data,channel=tank\\ level\\=1,site\\ name=lab\\,1 \
+             value=2.5,quality=\"good\" 1700000000000"
+        );
+    }
+
+    #[test]
+    fn line_skips_values_influx_cannot_store() {
+        let config = config("io", &[]);
+        assert!(line(&config, &sample("tank", f32::NAN)).is_none());
+        assert!(line(&config, &sample("tank", f32::INFINITY)).is_none());
+    }
+}
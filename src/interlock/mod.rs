@@ -0,0 +1,483 @@
+/// Safety interlocks on the switched outputs.
+///
+/// Each `[[interlocks]]` rule names an output and the conditions under which
+/// it may leave its safe state, for example "output 20 may only be on while
+/// pin_one is high and adc1_channel0 is below 4.5". Every condition has to
+/// hold; a channel without a good reading fails its condition, and so does
+/// one whose last reading is older than the condition's `max_age_ms`
+/// (`MAX_AGE_PERIODS` of the channel's period unless given). Commands that
+/// would move an output out of its safe state (see `[fail_safe]`) are
+/// checked before they run, and outputs already out of it are checked on
+/// every new reading and forced back if a rule stops holding. Each block and
+/// trip names the rule and what failed, and goes to the console and to
+/// subscribers such as the websocket sessions.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::sync::broadcast;
+
+use crate::history::{self, Quality};
+use crate::{IoState, DIGITAL_INPUTS, SWITCHED_OUTPUTS};
+
+// Events buffered per subscriber before a slow one starts missing events.
+const SUBSCRIBER_BACKLOG: usize = 64;
+// Periods of its channel a reading is trusted for when the condition gives no
+// max_age_ms, enough to ride out a slow or missed read.
+const MAX_AGE_PERIODS: u64 = 3;
+
+/// `[[interlocks]]`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterlockRule {
+    pub name: String,
+    /// GPIO pin of the output.
+    pub output: u8,
+    /// All of these must hold for the output to leave its safe state.
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Condition {
+    /// Digital input at the given level.
+    Input { input: String, high: bool },
+    /// Channel value below `limit`, read at most `max_age_ms` ago.
+    Below {
+        channel: String,
+        limit: f32,
+        max_age_ms: Option<u64>,
+    },
+    /// Channel value above `limit`, read at most `max_age_ms` ago.
+    Above {
+        channel: String,
+        limit: f32,
+        max_age_ms: Option<u64>,
+    },
+}
+
+impl Condition {
+    /// `None` when the condition holds, otherwise why not.
+    fn failure(&self, io_state: &IoState, now_ms: u64) -> Option<String> {
+        match self {
+            Condition::Input { input, high } => {
+                let level = io_state.inputs.get(input).copied();
+                if level == Some(*high) {
+                    return None;
+                }
+                Some(match level {
+                    Some(level) => format!("input {input} is {}", on_off(level)),
+                    None => format!("input {input} has not been read"),
+                })
+            }
+            Condition::Below {
+                channel,
+                limit,
+                max_age_ms,
+            }
+            | Condition::Above {
+                channel,
+                limit,
+                max_age_ms,
+            } => {
+                let quality = io_state.quality.get(channel).copied();
+                let value = io_state.channels.get(channel).copied();
+                let (Some(Quality::Good), Some(value)) = (quality, value) else {
+                    return Some(format!("{channel} has no good reading"));
+                };
+                // filled in by Interlocks::new for every channel condition
+                let max_age_ms = max_age_ms.unwrap_or(u64::MAX);
+                let age_ms = io_state
+                    .read_ms
+                    .get(channel)
+                    .map(|read_ms| now_ms.saturating_sub(*read_ms));
+                match age_ms {
+                    Some(age_ms) if age_ms <= max_age_ms => {}
+                    Some(age_ms) => {
+                        return Some(format!(
+                            "{channel} was last read {age_ms} ms ago, more than {max_age_ms} ms"
+                        ))
+                    }
+                    None => return Some(format!("{channel} has not been read")),
+                }
+                let (holds, relation) = match self {
+                    Condition::Below { .. } => (value.to_f32() < *limit, "below"),
+                    _ => (value.to_f32() > *limit, "above"),
+                };
+                if holds {
+                    return None;
+                }
+                Some(format!("{channel} = {value} is not {relation} {limit}"))
+            }
+        }
+    }
+}
+
+fn on_off(high: bool) -> &'static str {
+    if high {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Checks the rules against the outputs clients can switch and the channels
+/// that exist.
+pub fn validate(rules: &[InterlockRule], channels: &[String]) -> Result<(), String> {
+    for (index, rule) in rules.iter().enumerate() {
+        let context = format!("interlocks[{index}] ({})", rule.name);
+        if rule.name.trim().is_empty() {
+            return Err(format!("interlocks[{index}]: name must not be empty"));
+        }
+        if rules[..index].iter().any(|other| other.name == rule.name) {
+            return Err(format!("{context}: name is used twice"));
+        }
+        if !SWITCHED_OUTPUTS.contains(&rule.output) {
+            return Err(format!(
+                "{context}: output must be one of {SWITCHED_OUTPUTS:?}"
+            ));
+        }
+        if rule.conditions.is_empty() {
+            return Err(format!("{context}: needs at least one condition"));
+        }
+        for condition in rule.conditions.iter() {
+            match condition {
+                Condition::Input { input, .. } => {
+                    if !DIGITAL_INPUTS.iter().any(|(name, _)| name == input) {
+                        return Err(format!("{context}: unknown input {input}"));
+                    }
+                }
+                Condition::Below {
+                    channel,
+                    limit,
+                    max_age_ms,
+                }
+                | Condition::Above {
+                    channel,
+                    limit,
+                    max_age_ms,
+                } => {
+                    if !channels.contains(channel) {
+                        return Err(format!("{context}: unknown channel {channel}"));
+                    }
+                    if !limit.is_finite() {
+                        return Err(format!("{context}: limit of {channel} must be finite"));
+                    }
+                    if *max_age_ms == Some(0) {
+                        return Err(format!(
+                            "{context}: max_age_ms of {channel} must be at least 1"
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterlockAction {
+    /// A command was refused.
+    Blocked,
+    /// An output was forced to its safe state.
+    Tripped,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InterlockEvent {
+    pub ts_ms: u64,
+    pub action: InterlockAction,
+    pub rule: String,
+    pub output: u8,
+    /// The conditions that failed.
+    pub reasons: Vec<String>,
+}
+
+/// A rule as listed to clients.
+#[derive(Clone, Debug, Serialize)]
+pub struct RuleStatus {
+    pub name: String,
+    pub output: u8,
+    pub safe_state: bool,
+    /// Whether the output may currently leave its safe state.
+    pub permits: bool,
+    /// Why not, as of the last check.
+    pub reasons: Vec<String>,
+    pub last_event: Option<InterlockEvent>,
+}
+
+pub struct Interlocks {
+    rules: Vec<InterlockRule>,
+    // level each rule's output is forced to, by rule
+    safe_states: Vec<bool>,
+    status: Vec<RuleStatus>,
+    events: broadcast::Sender<InterlockEvent>,
+}
+
+impl Interlocks {
+    /// `safe_states` are the safe output levels by pin, outputs not in it
+    /// are safe when off. `periods` are how often each channel is read, in
+    /// milliseconds, for the conditions without a `max_age_ms`.
+    pub fn new(
+        mut rules: Vec<InterlockRule>,
+        safe_states: &BTreeMap<u8, bool>,
+        periods: &BTreeMap<String, u64>,
+    ) -> Self {
+        println!("interlocks: {} rules", rules.len());
+        for condition in rules.iter_mut().flat_map(|rule| rule.conditions.iter_mut()) {
+            if let Condition::Below {
+                channel,
+                max_age_ms,
+                ..
+            }
+            | Condition::Above {
+                channel,
+                max_age_ms,
+                ..
+            } = condition
+            {
+                // a channel with no known period is never too old
+                let period = periods.get(channel).copied().unwrap_or(u64::MAX);
+                max_age_ms.get_or_insert(period.saturating_mul(MAX_AGE_PERIODS));
+            }
+        }
+        let safe_states: Vec<bool> = rules
+            .iter()
+            .map(|rule| safe_states.get(&rule.output).copied().unwrap_or(false))
+            .collect();
+        let status = rules
+            .iter()
+            .zip(safe_states.iter())
+            .map(|(rule, safe_state)| RuleStatus {
+                name: rule.name.clone(),
+                output: rule.output,
+                safe_state: *safe_state,
+                permits: false,
+                reasons: vec!["not checked yet".to_string()],
+                last_event: None,
+            })
+            .collect();
+        Self {
+            rules,
+            safe_states,
+            status,
+            events: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+        }
+    }
+
+    /// Whether `output` may be set to `high`. Moving to the safe state is
+    /// always allowed; anything else needs every rule on the output to hold.
+    pub(crate) fn permits(&mut self, output: u8, high: bool, io_state: &IoState) -> bool {
+        self.check(io_state);
+        let mut permitted = true;
+        for index in 0..self.rules.len() {
+            let rule = &self.rules[index];
+            let safe_state = self.safe_states[index];
+            if rule.output != output || high == safe_state || self.status[index].permits {
+                continue;
+            }
+            self.push_event(index, InterlockAction::Blocked);
+            permitted = false;
+        }
+        permitted
+    }
+
+    /// Checks every rule against the latest readings and returns the outputs
+    /// that have to be forced, with the level to force them to. `outputs`
+    /// are the current output levels by pin.
+    pub(crate) fn enforce(
+        &mut self,
+        outputs: &BTreeMap<u8, bool>,
+        io_state: &IoState,
+    ) -> Vec<(u8, bool)> {
+        self.check(io_state);
+        let mut forced: Vec<(u8, bool)> = Vec::new();
+        for index in 0..self.rules.len() {
+            let rule = &self.rules[index];
+            let Some(level) = outputs.get(&rule.output).copied() else {
+                continue;
+            };
+            let safe_state = self.safe_states[index];
+            if level == safe_state || self.status[index].permits {
+                continue;
+            }
+            if !forced.iter().any(|(output, _)| *output == rule.output) {
+                forced.push((rule.output, safe_state));
+            }
+            self.push_event(index, InterlockAction::Tripped);
+        }
+        forced
+    }
+
+    pub fn status(&self) -> Vec<RuleStatus> {
+        self.status.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<InterlockEvent> {
+        self.events.subscribe()
+    }
+
+    fn check(&mut self, io_state: &IoState) {
+        let now_ms = history::now_ms();
+        for (rule, status) in self.rules.iter().zip(self.status.iter_mut()) {
+            status.reasons = rule
+                .conditions
+                .iter()
+                .filter_map(|condition| condition.failure(io_state, now_ms))
+                .collect();
+            status.permits = status.reasons.is_empty();
+        }
+    }
+
+    fn push_event(&mut self, index: usize, action: InterlockAction) {
+        let rule = &self.rules[index];
+        let safe_state = self.safe_states[index];
+        let status = &mut self.status[index];
+        let event = InterlockEvent {
+            ts_ms: history::now_ms(),
+            action,
+            rule: rule.name.clone(),
+            output: rule.output,
+            reasons: status.reasons.clone(),
+        };
+        let what = match action {
+            InterlockAction::Blocked => "blocked switching",
+            InterlockAction::Tripped => "forced",
+        };
+        let level = match action {
+            InterlockAction::Blocked => on_off(!safe_state),
+            InterlockAction::Tripped => on_off(safe_state),
+        };
+        println!(
+            "Interlock {} {} output {} {}: {}",
+            rule.name,
+            what,
+            rule.output,
+            level,
+            event.reasons.join(", ")
+        );
+        status.last_event = Some(event.clone());
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Precision;
+    use std::sync::Arc;
+
+    const OUTPUT: u8 = 20;
+
+    /// Output 20 may only be on while pin_one is high and tank reads below
+    /// 4.5, tank being read every 500 ms.
+    fn heater_door(max_age_ms: Option<u64>) -> Interlocks {
+        let rule = InterlockRule {
+            name: "heater_door".to_string(),
+            output: OUTPUT,
+            conditions: vec![
+                Condition::Input {
+                    input: "pin_one".to_string(),
+                    high: true,
+                },
+                Condition::Below {
+                    channel: "tank".to_string(),
+                    limit: 4.5,
+                    max_age_ms,
+                },
+            ],
+        };
+        let periods = BTreeMap::from([("tank".to_string(), 500)]);
+        Interlocks::new(vec![rule], &BTreeMap::new(), &periods)
+    }
+
+    fn io_state(pin_one: bool, tank: f32, quality: Quality, age_ms: u64) -> IoState {
+        let mut io_state = IoState::new(tokio::sync::mpsc::channel(1).0);
+        Arc::make_mut(&mut io_state.inputs).insert("pin_one".to_string(), pin_one);
+        let value = Precision::default().value(tank).unwrap();
+        Arc::make_mut(&mut io_state.channels).insert("tank".to_string(), value);
+        Arc::make_mut(&mut io_state.quality).insert("tank".to_string(), quality);
+        Arc::make_mut(&mut io_state.read_ms).insert("tank".to_string(), history::now_ms() - age_ms);
+        io_state
+    }
+
+    #[test]
+    fn permits_when_every_condition_holds() {
+        let mut interlocks = heater_door(None);
+        assert!(interlocks.permits(OUTPUT, true, &io_state(true, 3.0, Quality::Good, 0)));
+        assert!(interlocks.status()[0].reasons.is_empty());
+    }
+
+    #[test]
+    fn denies_when_a_condition_fails() {
+        let mut interlocks = heater_door(None);
+        let mut events = interlocks.subscribe();
+        assert!(!interlocks.permits(OUTPUT, true, &io_state(false, 3.0, Quality::Good, 0)));
+        assert!(!interlocks.permits(OUTPUT, true, &io_state(true, 5.0, Quality::Good, 0)));
+        assert!(!interlocks.permits(OUTPUT, true, &io_state(true, 3.0, Quality::Bad, 0)));
+        assert_eq!(interlocks.status()[0].reasons.len(), 1);
+        assert!(matches!(
+            events.try_recv().unwrap().action,
+            InterlockAction::Blocked
+        ));
+    }
+
+    #[test]
+    fn the_safe_state_and_other_outputs_are_always_permitted() {
+        let mut interlocks = heater_door(None);
+        let failing = io_state(false, 5.0, Quality::Bad, 0);
+        assert!(interlocks.permits(OUTPUT, false, &failing));
+        assert!(interlocks.permits(21, true, &failing));
+    }
+
+    #[test]
+    fn stale_readings_do_not_permit() {
+        let mut interlocks = heater_door(None);
+        // three periods by default
+        assert!(interlocks.permits(OUTPUT, true, &io_state(true, 3.0, Quality::Good, 1000)));
+        assert!(!interlocks.permits(OUTPUT, true, &io_state(true, 3.0, Quality::Good, 2000)));
+
+        let mut interlocks = heater_door(Some(100));
+        assert!(!interlocks.permits(OUTPUT, true, &io_state(true, 3.0, Quality::Good, 1000)));
+
+        let mut never_read = io_state(true, 3.0, Quality::Good, 0);
+        Arc::make_mut(&mut never_read.read_ms).clear();
+        assert!(!interlocks.permits(OUTPUT, true, &never_read));
+    }
+
+    #[test]
+    fn enforce_forces_an_output_that_is_on_back_to_safe() {
+        let mut interlocks = heater_door(None);
+        let on = BTreeMap::from([(OUTPUT, true)]);
+        let off = BTreeMap::from([(OUTPUT, false)]);
+        let failing = io_state(true, 5.0, Quality::Good, 0);
+        assert_eq!(interlocks.enforce(&on, &failing), [(OUTPUT, false)]);
+        assert!(interlocks.enforce(&off, &failing).is_empty());
+        let holding = io_state(true, 3.0, Quality::Good, 0);
+        assert!(interlocks.enforce(&on, &holding).is_empty());
+    }
+
+    #[test]
+    fn validate_checks_outputs_inputs_channels_and_ages() {
+        let channels = vec!["tank".to_string()];
+        let rule = |output: u8, condition: Condition| InterlockRule {
+            name: "rule".to_string(),
+            output,
+            conditions: vec![condition],
+        };
+        let below = |channel: &str, max_age_ms: Option<u64>| Condition::Below {
+            channel: channel.to_string(),
+            limit: 4.5,
+            max_age_ms,
+        };
+        assert!(validate(&[rule(OUTPUT, below("tank", None))], &channels).is_ok());
+        assert!(validate(&[rule(OUTPUT, below("tank", Some(0)))], &channels).is_err());
+        assert!(validate(&[rule(OUTPUT, below("other", None))], &channels).is_err());
+        assert!(validate(&[rule(2, below("tank", None))], &channels).is_err());
+        let input = Condition::Input {
+            input: "pin_nine".to_string(),
+            high: true,
+        };
+        assert!(validate(&[rule(OUTPUT, input)], &channels).is_err());
+    }
+}
@@ -1,20 +1,57 @@
 // Server that displays IO Status
-use axum::{extract::State, response::Html};
-
+use clap::{Parser, Subcommand};
 use tokio::sync::mpsc::Sender;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use rppal::gpio::Gpio;
 use rppal::i2c::I2c;
-use rust_decimal::prelude::*;
-use rust_decimal_macros::dec;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::io;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+mod alarm;
+mod calibration;
+mod config;
+mod export;
+mod failsafe;
+mod filter;
+mod gpio;
+mod history;
+mod influx;
+mod interlock;
+mod metrics;
+mod modbus_master;
+mod modbus_server;
+mod mqtt;
+mod opcua_server;
+mod password;
 mod rhino;
+mod sampling;
+mod scaling;
+mod shutdown;
+mod state;
+mod status_led;
+mod value;
+mod watchdog;
 mod web;
+use alarm::AlarmEngine;
+use calibration::Calibrator;
+use config::{ChannelConfig, Config};
+use failsafe::{Hold, Outputs};
+use filter::FilterChain;
+use history::{History, Quality, Retention, Sample};
+use interlock::Interlocks;
+use sampling::{ChannelStats, Scheduler};
+use scaling::{Scaling, Scalings};
+use shutdown::Shutdown;
+use state::SharedState;
+use value::{ChannelValue, Precision};
+use watchdog::Watchdog;
 use web::app;
 
 // ADS1115 I2C address when ADDR pin pulled to ground
@@ -24,35 +61,200 @@ const ADDR_ADS115_TWO: u16 = 0x49; // Address of second ADS115 chip
 // ADS115 register addresses.
 const REG_CONFIGURATION: u8 = 0x01;
 const REG_CONVERSION: u8 = 0x00;
-const DELAY_TIME: u64 = 100;
-const MAIN_LOOP_DELAY: u64 = 100;
 const I2C_DELAY_TIME: u64 = 10;
-const VOLTAGE_LIMIT: f32 = 6.5;
+// Estimated time of one register write or read at the bus's 100 kHz.
+const I2C_TRANSFER_TIME: Duration = Duration::from_micros(500);
+// Samples are written to disk at most this often.
+const HISTORY_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+// How long a shutdown waits for each task to wrap up (the web clients, the
+// sampling loop, the Influx writer) before going on without it.
+const SHUTDOWN_STEP_TIMEOUT: Duration = Duration::from_secs(5);
+// Exit code when the server stopped on a signal but a task did not wrap up
+// in time or a buffer could not be flushed. Startup and web server failures
+// exit with 1.
+const EXIT_INCOMPLETE_SHUTDOWN: u8 = 2;
+
+// ADS1115 data rates in samples per second, indexed by the DR bits of the
+// configuration register.
+const ADS1115_DATA_RATES: [u16; 8] = [8, 16, 32, 64, 128, 250, 475, 860];
+const DEFAULT_DATA_RATE: u16 = 128;
+const OVERSAMPLING_DATA_RATE: u16 = 860;
+const MAX_OVERSAMPLING: u32 = 64;
+
+// Sample history
+const HISTORY_DIR: &str = "history";
+const HISTORY_RING_CAPACITY: usize = 250_000; // a few hours of every channel at the default periods
+const HISTORY_RETENTION: Retention = Retention {
+    max_age: Duration::from_secs(30 * 24 * 60 * 60),
+    max_bytes: 1024 * 1024 * 1024,
+    segment_bytes: 16 * 1024 * 1024,
+    segment_span: Duration::from_secs(60 * 60),
+};
+
+// Alarm events, the newest of which are also kept in memory.
+const ALARM_LOG: &str = "alarm_log.jsonl";
+const ALARM_LOG_CAPACITY: usize = 1000;
+const ALARM_STATE: &str = "alarm_state.json";
+
+/// A single-ended input on one of the ADS1115 chips.
+struct AdcChannel {
+    name: &'static str,
+    address: u16,
+    config_reg1: u8,
+    config_reg2: u8,
+}
+
+impl AdcChannel {
+    const fn new(name: &'static str, address: u16, config_reg1: u8, config_reg2: u8) -> Self {
+        Self {
+            name,
+            address,
+            config_reg1,
+            config_reg2,
+        }
+    }
+}
+
+/// How often a channel is read, how many conversions are averaged each
+/// read, and how fast the chip converts while they are taken.
+#[derive(Clone, Copy)]
+struct AdcSampling {
+    period: Duration,
+    conversions: u32,
+    data_rate: u16,
+}
+
+impl AdcSampling {
+    fn new(channel: Option<&ChannelConfig>) -> Self {
+        let conversions = channel
+            .and_then(|channel| channel.oversampling)
+            .unwrap_or(1);
+        let default_rate = if conversions > 1 {
+            OVERSAMPLING_DATA_RATE
+        } else {
+            DEFAULT_DATA_RATE
+        };
+        Self {
+            period: Duration::from_millis(
+                channel
+                    .and_then(|channel| channel.period_ms)
+                    .unwrap_or(sampling::DEFAULT_PERIOD_MS),
+            ),
+            conversions,
+            data_rate: channel
+                .and_then(|channel| channel.data_rate)
+                .unwrap_or(default_rate),
+        }
+    }
+
+    /// `config_reg2` with its data rate bits replaced by this data rate.
+    fn config_reg2(&self, config_reg2: u8) -> u8 {
+        let bits = ADS1115_DATA_RATES
+            .iter()
+            .position(|rate| *rate == self.data_rate)
+            .unwrap() as u8;
+        (config_reg2 & 0x1f) | (bits << 5)
+    }
+
+    // One conversion, with 10% margin for the tolerance of the chip's clock.
+    fn conversion_period(&self) -> Duration {
+        Duration::from_micros(1_100_000 / self.data_rate as u64)
+    }
+
+    // After switching inputs the conversion under way still finishes on the
+    // old one, so the first good reading is the one after it.
+    fn settle_time(&self) -> Duration {
+        self.conversion_period() * 2
+    }
+
+    /// How long `get_adc_value` keeps the bus for one read: the settling,
+    /// every further conversion, and the register writes and reads.
+    fn bus_time(&self) -> Duration {
+        let transfers = 2 + self.conversions;
+        self.settle_time()
+            + self.conversion_period() * (self.conversions - 1)
+            + I2C_TRANSFER_TIME * transfers
+    }
+
+    /// Shortest period the channel can be read at on its own, leaving the
+    /// bus the slack of `I2C_BUDGET`.
+    fn min_period_ms(&self) -> u64 {
+        (self.bus_time().as_secs_f64() * 1000.0 / sampling::I2C_BUDGET).ceil() as u64
+    }
+}
+
+// Every analog channel read by the sampling loop.
+const ADC_CHANNELS: [AdcChannel; 8] = [
+    AdcChannel::new("adc1_channel0", ADDR_ADS115, 0x42, 0x82),
+    AdcChannel::new("adc1_channel1", ADDR_ADS115, 0x52, 0x82),
+    AdcChannel::new("adc1_channel2", ADDR_ADS115, 0x62, 0x82),
+    AdcChannel::new("adc1_channel3", ADDR_ADS115, 0x72, 0x82),
+    AdcChannel::new("adc2_channel0", ADDR_ADS115_TWO, 0x42, 0x82),
+    AdcChannel::new("adc2_channel1", ADDR_ADS115_TWO, 0x52, 0x82),
+    AdcChannel::new("adc2_channel2", ADDR_ADS115_TWO, 0x62, 0x82),
+    AdcChannel::new("adc2_channel3", ADDR_ADS115_TWO, 0x72, 0x82),
+];
+
+// Digital inputs as (name, GPIO pin).
+const DIGITAL_INPUTS: [(&str, u8); 2] = [("pin_one", 24), ("pin_two", 25)];
+
+/// Names of the channels recorded by the sampling loop.
+fn default_channel_names() -> Vec<String> {
+    ADC_CHANNELS
+        .iter()
+        .map(|channel| channel.name.to_string())
+        .collect()
+}
 
 //Output setup
 
 const OUTPUT20: u8 = 20;
 
+// GPIO outputs that clients may switch. The pin picked at startup is left
+// out since it is the status LED.
+const SWITCHED_OUTPUTS: [u8; 1] = [OUTPUT20];
+
 enum OutputCommand {
     LedToggle(i32),
+    LedSet(i32, bool),
 }
 
+// The maps are behind their own Arc, so publishing a change only copies
+// the maps it touches; the rest are shared with the previous snapshot.
 #[derive(Clone)]
 struct IoState {
-    // digital input pins.
-    pin_one: bool,
-    pin_two: bool,
-    pin_three: bool,
-
-    pub adc1_channel0: f32,
-    pub adc1_channel1: f32,
-    pub adc1_channel2: f32,
-    pub adc1_channel3: f32,
-
-    pub adc2_channel0: f32,
-    pub adc2_channel1: f32,
-    pub adc2_channel2: f32,
-    pub adc2_channel3: f32,
+    // bumped every time a snapshot is published, so readers can tell
+    // whether anything changed since the one they last looked at.
+    pub seq: u64,
+
+    // digital input pins, keyed by name.
+    pub inputs: Arc<BTreeMap<String, bool>>,
+
+    // latest value of every channel (ADC voltages and polled Modbus
+    // registers), keyed by name.
+    pub channels: Arc<BTreeMap<String, ChannelValue>>,
+    pub quality: Arc<BTreeMap<String, Quality>>,
+    // when each channel was last read, good or bad, in ms since the epoch.
+    pub read_ms: Arc<BTreeMap<String, u64>>,
+    pub units: Arc<BTreeMap<String, String>>,
+    // configured rounding, channels without an entry use the default.
+    pub precision: Arc<BTreeMap<String, Precision>>,
+    // ADC volts as read, and after the channel's filters (what the scaling
+    // is applied to), keyed by channel name.
+    pub raw: Arc<BTreeMap<String, f32>>,
+    pub filtered: Arc<BTreeMap<String, f32>>,
+    // standard deviation of the volts averaged in the last read, for
+    // channels that oversample.
+    pub noise: Arc<BTreeMap<String, f32>>,
+    // how each ADC channel is being sampled, keyed by channel name.
+    pub sampling: Arc<BTreeMap<String, ChannelStats>>,
+
+    // output pin levels, keyed by GPIO pin number.
+    pub outputs: Arc<BTreeMap<u8, bool>>,
+
+    // diagnostics
+    pub i2c_errors: Arc<BTreeMap<u16, u64>>,
 
     sneaky_sender: Sender<OutputCommand>,
 }
@@ -60,35 +262,89 @@ struct IoState {
 impl IoState {
     fn new(tx: Sender<OutputCommand>) -> Self {
         Self {
-            pin_one: false,
-            pin_two: false,
-            pin_three: false,
-
-            adc1_channel0: 0.0,
-            adc1_channel1: 0.0,
-            adc1_channel2: 0.0,
-            adc1_channel3: 0.0,
-            adc2_channel0: 0.0,
-            adc2_channel1: 0.0,
-            adc2_channel2: 0.0,
-            adc2_channel3: 0.0,
-            
+            seq: 0,
+            inputs: Arc::new(
+                DIGITAL_INPUTS
+                    .iter()
+                    .map(|(name, _)| (name.to_string(), false))
+                    .collect(),
+            ),
+            channels: Arc::new(
+                ADC_CHANNELS
+                    .iter()
+                    .map(|channel| (channel.name.to_string(), ChannelValue::default()))
+                    .collect(),
+            ),
+            quality: Arc::default(),
+            read_ms: Arc::default(),
+            units: Arc::new(
+                ADC_CHANNELS
+                    .iter()
+                    .map(|channel| (channel.name.to_string(), "V".to_string()))
+                    .collect(),
+            ),
+            precision: Arc::default(),
+            raw: Arc::new(
+                ADC_CHANNELS
+                    .iter()
+                    .map(|channel| (channel.name.to_string(), 0.0))
+                    .collect(),
+            ),
+            filtered: Arc::new(
+                ADC_CHANNELS
+                    .iter()
+                    .map(|channel| (channel.name.to_string(), 0.0))
+                    .collect(),
+            ),
+            noise: Arc::default(),
+            sampling: Arc::default(),
+            outputs: Arc::default(),
+
+            i2c_errors: Arc::default(),
+
             sneaky_sender: tx,
         }
     }
 
-    fn set_pin(&mut self, pin: u8, value: bool) {
-        if pin == 0 {
-            self.pin_one = value;
-        } else if pin == 1 {
-            self.pin_two = value;
-        }
+    fn precision(&self, channel: &str) -> Precision {
+        self.precision.get(channel).copied().unwrap_or_default()
     }
 }
 
+#[derive(Parser)]
+#[command(about = "Server that displays IO Status")]
+struct Cli {
+    /// TOML file with the optional subsystems to run.
+    #[arg(long, global = true, default_value = config::DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Dump recorded samples to CSV or Parquet instead of running the server.
+    Export(export::ExportArgs),
+    /// Read a password from stdin and print the hash to put in the config.
+    HashPassword,
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Export(args)) => {
+            let config = Config::read(&cli.config)?;
+            export::run(args, &config.precisions())?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::HashPassword) => {
+            password::run()?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
+    }
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
@@ -97,15 +353,71 @@ async fn main() -> Result<(), Box<dyn Error>> {
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
-    
-    let (tx, mut rx) = tokio::sync::mpsc::channel(32);
 
-    let io_state = IoState::new(tx);
+    let config = Config::load(&cli.config)?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(32);
+
+    let mut io_state = IoState::new(tx);
+    io_state.precision = Arc::new(config.precisions());
+    for (name, channel) in config.channels.iter() {
+        if let Some(units) = channel.units.as_ref() {
+            Arc::make_mut(&mut io_state.units).insert(name.clone(), units.clone());
+        }
+    }
+    let scalings: BTreeMap<String, Scaling> = config
+        .channels
+        .iter()
+        .filter_map(|(name, channel)| Some((name.clone(), channel.scaling.clone()?)))
+        .collect();
+    let scalings: Scalings = Arc::new(Mutex::new(scalings));
+    let background_scalings = scalings.clone();
+    let precisions = io_state.precision.clone();
+    let mut filters: BTreeMap<String, FilterChain> = config
+        .channels
+        .iter()
+        .filter(|(_, channel)| !channel.filters.is_empty())
+        .map(|(name, channel)| (name.clone(), FilterChain::new(&channel.filters)))
+        .collect();
+    let samplings: Vec<AdcSampling> = ADC_CHANNELS
+        .iter()
+        .map(|channel| AdcSampling::new(config.channels.get(channel.name)))
+        .collect();
+
+    let shared_state = SharedState::new(io_state);
 
-    let shared_state: Arc<Mutex<IoState>> = Arc::new(Mutex::new(io_state));
-   
     let background_state = shared_state.clone();
 
+    let history = History::open(HISTORY_DIR, HISTORY_RING_CAPACITY, HISTORY_RETENTION)?;
+    let history = Arc::new(Mutex::new(history));
+    let background_history = history.clone();
+
+    let alarms = AlarmEngine::open(
+        &config.channels,
+        config.alarm_shelving.clone(),
+        ALARM_LOG,
+        ALARM_STATE,
+        ALARM_LOG_CAPACITY,
+    )?;
+    let alarms = Arc::new(Mutex::new(alarms));
+
+    let outputs = Outputs::new(&config.fail_safe);
+    let outputs = Arc::new(Mutex::new(outputs));
+    failsafe::install_panic_hook(outputs.clone());
+    failsafe::spawn(outputs.clone());
+
+    let interlocks = Interlocks::new(
+        config.interlocks.clone(),
+        &config.fail_safe.safe_states(),
+        &config.channel_periods(),
+    );
+    let interlocks = Arc::new(Mutex::new(interlocks));
+
+    let shutdown = Shutdown::new();
+
+    // only armed once the sampling loop is about to start petting it
+    let mut watchdog: Option<Arc<Mutex<Watchdog>>> = None;
+    let sampler: Option<JoinHandle<()>>;
     // #[cfg(target_arch = "arm")]
     {
         let mut pin_pic_one = String::new();
@@ -115,19 +427,27 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .expect("Failed to read line");
         let pick_one = get_pin_number(pin_pic_one);
         println!("pick_one equals: {pick_one}");
+        status_led::spawn(
+            pick_one,
+            Gpio::new()?.get(pick_one)?.into_output(),
+            config.status_led.clone(),
+            shared_state.clone(),
+            alarms.clone(),
+            outputs.clone(),
+        );
 
         let mut i2c = I2c::new()?;
         i2c.set_slave_address(ADDR_ADS115)?; // Set the I2C slave address to the device we're communicating with.
 
         i2c.block_write(REG_CONFIGURATION, &[0x42, 0x82])?; // Set configuration setting to ADS115
-        tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME));
+        tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
 
         i2c.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-        tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME));
+        tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
         let mut reg = [0u8; 2];
 
         i2c.block_read(0x00, &mut reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-        tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME));
+        tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
 
         //let adc0val:u16 = u16::from_be_bytes(reg);
         //println!(" ADC 0 decimal value = {:?} ", adc0val);
@@ -136,87 +456,308 @@ async fn main() -> Result<(), Box<dyn Error>> {
         //let adc0voltage:f32 = adc0voltage * 0.000125;
         //println!(" ADC 0 voltage = {:?} ", adc0voltage);
 
-        tokio::spawn(async move {
-            // set input pins
-            let pin_25 = Gpio::new().unwrap().get(25).unwrap().into_input_pulldown();
-            let pin_24 = Gpio::new().unwrap().get(24).unwrap().into_input_pulldown();
-
-            // set the user selected outputs
-            let mut pin_selection_one = Gpio::new().unwrap().get(pick_one).unwrap().into_output();
-            let mut output_20 = Gpio::new().unwrap().get(OUTPUT20).unwrap().into_output();
-            output_20.set_low();
+        if let Some(watchdog_config) = config.watchdog.as_ref() {
+            watchdog = Some(Arc::new(Mutex::new(Watchdog::open(watchdog_config)?)));
+        }
+        let background_watchdog = watchdog.clone();
+        let background_shutdown = shutdown.clone();
+
+        // inputs and outputs get tasks of their own so commands do not wait for the ADC
+        let (io_events, io_event_receiver) = gpio::event_channel();
+        gpio::spawn_inputs(shared_state.clone(), io_events.clone())?;
+        gpio::spawn_outputs(
+            rx,
+            io_event_receiver,
+            shared_state.clone(),
+            outputs.clone(),
+            interlocks.clone(),
+        )?;
+
+        sampler = Some(tokio::spawn(failsafe::io_task(async move {
+            let mut scheduler = Scheduler::new(&samplings);
+            // last good filtered voltage per channel, recorded alongside failed reads
+            let mut last_voltages: BTreeMap<&str, f32> = BTreeMap::new();
+            let mut last_flush = Instant::now();
             loop {
-                let adc1_channel0 = get_adc_value(ADDR_ADS115, 0x42, 0x82, "ADC1_CH0_Voltage")
-                    .await
-                    .unwrap();
-                let adc1_channel1 = get_adc_value(ADDR_ADS115, 0x52, 0x82, "ADC1_CH1_Voltage")
-                    .await
-                    .unwrap();
-                let adc1_channel2 = get_adc_value(ADDR_ADS115, 0x62, 0x82, "ADC1_CH2_Voltage")
-                    .await
-                    .unwrap();
-                let adc1_channel3 = get_adc_value(ADDR_ADS115, 0x72, 0x82, "ADC1_CH3_Voltage")
-                    .await
-                    .unwrap();
-                println!("");
-                println!("");
-                let adc2_channel0 = get_adc_value(ADDR_ADS115_TWO, 0x42, 0x82, "ADC2_CH0_Voltage")
-                    .await
-                    .unwrap();
-                let adc2_channel1 = get_adc_value(ADDR_ADS115_TWO, 0x52, 0x82, "ADC2_CH1_Voltage")
-                    .await
-                    .unwrap();
-                let adc2_channel2 = get_adc_value(ADDR_ADS115_TWO, 0x62, 0x82, "ADC2_CH2_Voltage")
-                    .await
-                    .unwrap();
-                let adc2_channel3 = get_adc_value(ADDR_ADS115_TWO, 0x72, 0x82, "ADC2_CH3_Voltage")
-                    .await
-                    .unwrap();
-                println!("");
-
+                let (index, due) = scheduler.next();
+                tokio::select! {
+                    _ = tokio::time::sleep_until(due.into()) => {}
+                    _ = background_shutdown.wait() => break,
+                }
+                let channel = &ADC_CHANNELS[index];
+                let sampling = &samplings[index];
+
+                let started = Instant::now();
+                let reading = get_adc_value(
+                    channel.address,
+                    channel.config_reg1,
+                    sampling.config_reg2(channel.config_reg2),
+                    sampling,
+                    channel.name,
+                )
+                .await;
+                scheduler.done(index, started, Instant::now());
+                scheduler.report_overruns();
+
+                let reading = match reading {
+                    Ok(reading) => Some(reading),
+                    Err(e) => {
+                        println!("Failed to read {}: {}", channel.name, e);
+                        None
+                    }
+                };
+                if let Some((voltage, _)) = reading {
+                    let filtered = match filters.get_mut(channel.name) {
+                        Some(chain) => chain.apply(voltage),
+                        None => voltage,
+                    };
+                    last_voltages.insert(channel.name, filtered);
+                }
+                let volts = last_voltages.get(channel.name).copied().unwrap_or(0.0);
+                let scaled = match background_scalings
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(channel.name)
+                {
+                    Some(scaling) => scaling.apply(volts),
+                    None => (volts, Quality::Good),
+                };
+                let precision = precisions.get(channel.name).copied().unwrap_or_default();
+                // a scaling can blow up to infinity, which has no decimal
+                let (value, in_range) = match precision.value(scaled.0) {
+                    Some(value) => (value, scaled.1),
+                    None => (ChannelValue::default(), Quality::Bad),
+                };
+                let quality = if reading.is_some() {
+                    in_range
+                } else {
+                    Quality::Bad
+                };
+                let read_ms = history::now_ms();
                 {
-                    let mut io_state = background_state.lock().unwrap();
-                    io_state.adc1_channel0 = adc1_channel0;
-                    io_state.adc1_channel1 = adc1_channel1;
-                    io_state.adc1_channel2 = adc1_channel2;
-                    io_state.adc1_channel3 = adc1_channel3;
-
-                    io_state.adc2_channel0 = adc2_channel0;
-                    io_state.adc2_channel1 = adc2_channel1;
-                    io_state.adc2_channel2 = adc2_channel2;
-                    io_state.adc2_channel3 = adc2_channel3;
-
-                    io_state.set_pin(0, pin_24.is_high());
-                    io_state.set_pin(1, pin_25.is_high());
+                    let mut history = background_history
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    let sample = Sample {
+                        ts_ms: read_ms,
+                        channel: channel.name.to_string(),
+                        value: value.to_f32(),
+                        quality,
+                    };
+                    if let Err(e) = history.record(sample) {
+                        println!("Failed to record history: {}", e);
+                    }
+                    if last_flush.elapsed() >= HISTORY_FLUSH_INTERVAL {
+                        last_flush = Instant::now();
+                        if let Err(e) = history.flush() {
+                            println!("Failed to flush history: {}", e);
+                        }
+                    }
                 }
-                pin_selection_one.toggle();
-                match rx.try_recv() {
-                    Ok(OutputCommand::LedToggle(pin_id)) => {
-                        println!("Got a message in main from rx: {}", pin_id);
-                        if pin_id == 20 {
-                            if output_20.is_set_low() {
-                                output_20.set_high();
-                            } else 
-                            {
-                                output_20.set_low();
+
+                background_state.update(|io_state| {
+                    match reading {
+                        Some((voltage, noise)) => {
+                            Arc::make_mut(&mut io_state.raw)
+                                .insert(channel.name.to_string(), voltage);
+                            if sampling.conversions > 1 {
+                                Arc::make_mut(&mut io_state.noise)
+                                    .insert(channel.name.to_string(), noise);
                             }
-                            
+                        }
+                        None => {
+                            *Arc::make_mut(&mut io_state.i2c_errors)
+                                .entry(channel.address)
+                                .or_insert(0) += 1
                         }
                     }
-                    Err(_) => {} 
+                    Arc::make_mut(&mut io_state.filtered).insert(channel.name.to_string(), volts);
+                    Arc::make_mut(&mut io_state.channels).insert(channel.name.to_string(), value);
+                    Arc::make_mut(&mut io_state.quality).insert(channel.name.to_string(), quality);
+                    Arc::make_mut(&mut io_state.read_ms).insert(channel.name.to_string(), read_ms);
+                    Arc::make_mut(&mut io_state.sampling)
+                        .insert(channel.name.to_string(), scheduler.stats(index));
+                });
+                // the interlocks look at the new reading right away
+                let _ = io_events.try_send(gpio::IoEvent::ChannelRead);
+                // only reached when neither the bus nor a lock hung the read
+                if let Some(watchdog) = background_watchdog.as_ref() {
+                    watchdog
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .read_done(reading.is_some());
                 }
-                tokio::time::sleep(Duration::from_millis(MAIN_LOOP_DELAY)).await;
             }
 
+            println!("Sampling stopped");
+
             // sleep for half a second
             // toggle pin values
             // sleep a bit
             // toggle
+        })));
+    }
+    // the OPC UA address space is built from the channels registered here
+    modbus_master::spawn(
+        config.modbus_devices.clone(),
+        shared_state.clone(),
+        history.clone(),
+    );
+
+    alarm::spawn(alarms.clone(), shared_state.clone());
+
+    let mut opcua: Option<JoinHandle<()>> = None;
+    if let Some(opcua_config) = config.opcua {
+        opcua = Some(opcua_server::spawn(
+            opcua_config,
+            &config.modbus_devices,
+            shared_state.clone(),
+            shutdown.clone(),
+        )?);
+    }
+
+    let mut mqtt: Option<JoinHandle<()>> = None;
+    if let Some(mqtt_config) = config.mqtt {
+        mqtt = Some(mqtt::spawn(
+            mqtt_config,
+            shared_state.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    let mut influx_writer: Option<JoinHandle<()>> = None;
+    if let Some(influx_config) = config.influx {
+        let samples = history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .subscribe();
+        influx_writer = Some(influx::spawn(influx_config, samples, shutdown.clone())?);
+    }
+
+    if let Some(modbus_config) = config.modbus_server {
+        let modbus_state = shared_state.clone();
+        let modbus_outputs = outputs.clone();
+        let modbus_interlocks = interlocks.clone();
+        tokio::spawn(async move {
+            if let Err(e) = modbus_server::serve(
+                modbus_config,
+                modbus_state,
+                modbus_outputs,
+                modbus_interlocks,
+            )
+            .await
+            {
+                println!("Modbus TCP server stopped: {}", e);
+            }
         });
     }
-    app(shared_state).await;
 
-    Ok(())
+    let calibrator = Calibrator::new(cli.config, shared_state.clone(), scalings);
+    let calibrator = Arc::new(Mutex::new(calibrator));
+
+    let server = app(
+        shared_state,
+        history.clone(),
+        calibrator,
+        alarms,
+        interlocks,
+        outputs.clone(),
+        shutdown.clone(),
+    );
+    tokio::pin!(server);
+    let mut server_stopped = false;
+    let mut failure = None;
+    tokio::select! {
+        result = &mut server => {
+            // it only stops by itself when it fails
+            let e = result
+                .err()
+                .unwrap_or_else(|| io::Error::other("web server stopped by itself"));
+            println!("Web server failed: {}, shutting down", e);
+            server_stopped = true;
+            failure = Some(e);
+        }
+        signal = shutdown::signal() => match signal {
+            Ok(signal) => println!("Got {}, shutting down", signal),
+            Err(e) => {
+                println!("Cannot wait for SIGINT and SIGTERM: {}, shutting down", e);
+                failure = Some(e);
+            }
+        }
+    }
+    shutdown.trigger();
+    // commands that come in while the rest winds down are refused
+    failsafe::lock(&outputs).hold(Hold::Shutdown);
+
+    let mut complete = true;
+    complete &= wait_for_task("sampling loop", sampler).await;
+    // nothing pets it from here on, and the steps below can take longer
+    // than the watchdog timeout
+    if let Some(watchdog) = watchdog {
+        watchdog
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .disarm();
+    }
+    if !server_stopped {
+        match tokio::time::timeout(SHUTDOWN_STEP_TIMEOUT, &mut server).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                println!("Web server failed while stopping: {}", e);
+                complete = false;
+            }
+            Err(_) => {
+                println!(
+                    "Web clients still connected after {:?}, going on without them",
+                    SHUTDOWN_STEP_TIMEOUT
+                );
+                complete = false;
+            }
+        }
+    }
+    if let Err(e) = history
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .flush()
+    {
+        println!("Failed to flush history: {}", e);
+        complete = false;
+    }
+    complete &= wait_for_task("Influx writer", influx_writer).await;
+    complete &= wait_for_task("MQTT bridge", mqtt).await;
+    complete &= wait_for_task("OPC UA server", opcua).await;
+
+    if let Some(e) = failure {
+        return Err(e.into());
+    }
+    if !complete {
+        println!("Shut down, but not everything was wrapped up");
+        return Ok(ExitCode::from(EXIT_INCOMPLETE_SHUTDOWN));
+    }
+    println!("Shut down cleanly");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Gives a task `SHUTDOWN_STEP_TIMEOUT` to finish after the shutdown was
+/// triggered. Returns whether it did.
+async fn wait_for_task(name: &str, task: Option<JoinHandle<()>>) -> bool {
+    let Some(task) = task else {
+        return true;
+    };
+    match tokio::time::timeout(SHUTDOWN_STEP_TIMEOUT, task).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            println!("The {} failed: {}", name, e);
+            false
+        }
+        Err(_) => {
+            println!(
+                "The {} did not stop within {:?}",
+                name, SHUTDOWN_STEP_TIMEOUT
+            );
+            false
+        }
+    }
 }
 
 // chip address u16; config reg1 u8, congfig reg2 u8;
@@ -228,264 +769,131 @@ fn get_pin_number(x: String) -> u8 {
     num
 }
 
+/// Mean and standard deviation of `sampling.conversions` readings of one
+/// channel, in volts.
 async fn get_adc_value(
     adc_address: u16,
     config_reg1: u8,
     config_reg2: u8,
+    sampling: &AdcSampling,
     print_text: &str,
-) -> Result<f32, Box<dyn Error>> {
+) -> Result<(f32, f32), Box<dyn Error>> {
     let mut adc_reg = [0u8; 2];
 
     let mut i2c0 = I2c::new()?;
     i2c0.set_slave_address(adc_address)?;
 
     i2c0.block_write(REG_CONFIGURATION, &[config_reg1, config_reg2])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
+    tokio::time::sleep(sampling.settle_time()).await;
 
     i2c0.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
 
-    i2c0.block_read(REG_CONVERSION, &mut adc_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
+    let mut voltages = Vec::with_capacity(sampling.conversions as usize);
+    for conversion in 0..sampling.conversions {
+        if conversion > 0 {
+            // wait out a fresh conversion so no reading is counted twice
+            tokio::time::sleep(sampling.conversion_period()).await;
+        }
+        i2c0.block_read(REG_CONVERSION, &mut adc_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
 
-    let adc_val: u16 = u16::from_be_bytes(adc_reg);
-    //println!(" ADC 0 decimal value = {:?} ", adc0val);
-    let adcvoltage: f32 = adc_val.into();
+        // two's complement, inputs a little below ground read negative
+        let adc_val: i16 = i16::from_be_bytes(adc_reg);
+        //println!(" ADC 0 decimal value = {:?} ", adc0val);
+        let adcvoltage: f32 = adc_val.into();
 
-    let mut adcvoltage: f32 = adcvoltage * 0.000125;
-    if adcvoltage > VOLTAGE_LIMIT {
-        adcvoltage = 0.01;
+        let adcvoltage: f32 = adcvoltage * 0.000125;
+        voltages.push(adcvoltage);
     }
-    println!("{} = {:.2?}", print_text, adcvoltage);
-
-    //let adcvoltage = Decimal::new(adcvoltage, 2);
-
-    Ok(adcvoltage)
-}
-
-async fn get_adc0_value() -> Result<f32, Box<dyn Error>> {
-    let mut adc0_reg = [0u8; 2];
-
-    let mut i2c0 = I2c::new()?;
-    i2c0.set_slave_address(ADDR_ADS115)?;
-
-    i2c0.block_write(REG_CONFIGURATION, &[0x42, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
 
-    i2c0.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_read(REG_CONVERSION, &mut adc0_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let adc0val: u16 = u16::from_be_bytes(adc0_reg);
-    //println!(" ADC 0 decimal value = {:?} ", adc0val);
-    let adc0voltage: f32 = adc0val.into();
-
-    let mut adc0voltage: f32 = adc0voltage * 0.000125;
-    if adc0voltage > VOLTAGE_LIMIT {
-        adc0voltage = 0.01;
+    let (mean, noise) = mean_and_noise(&voltages);
+    if sampling.conversions > 1 {
+        println!(
+            "{} = {:.4?} (σ {:.5} over {})",
+            print_text, mean, noise, sampling.conversions
+        );
+    } else {
+        println!("{} = {:.2?}", print_text, mean);
     }
-    println!(" ADC_1 0 voltage = {:?} ", adc0voltage);
 
-    Ok(adc0voltage)
+    Ok((mean, noise))
 }
 
-async fn get_adc1_value() -> Result<f32, Box<dyn Error>> {
-    let mut adc1_reg = [0u8; 2];
-
-    let mut i2c1 = I2c::new()?;
-    i2c1.set_slave_address(ADDR_ADS115)?;
-
-    i2c1.block_write(REG_CONFIGURATION, &[0x52, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c1.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c1.block_read(REG_CONVERSION, &mut adc1_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let adc1val: u16 = u16::from_be_bytes(adc1_reg);
-    //println!(" ADC 1 decimal value = {:?} ", adc1val);
-    let adc1voltage: f32 = adc1val.into();
-
-    let mut adc1voltage: f32 = adc1voltage * 0.000125;
-    if adc1voltage > VOLTAGE_LIMIT {
-        adc1voltage = 0.01;
-    }
-    println!(" ADC_1 1 voltage = {:?} ", adc1voltage);
-
-    Ok(adc1voltage)
+/// Mean and population standard deviation of the conversions of one read.
+fn mean_and_noise(voltages: &[f32]) -> (f32, f32) {
+    let count = voltages.len() as f32;
+    let mean = voltages.iter().sum::<f32>() / count;
+    let variance = voltages.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / count;
+    (mean, variance.sqrt())
 }
 
-async fn get_adc2_value() -> Result<f32, Box<dyn Error>> {
-    let mut adc2_reg = [0u8; 2];
-
-    let mut i2c2 = I2c::new()?;
-    i2c2.set_slave_address(ADDR_ADS115)?;
-
-    i2c2.block_write(REG_CONFIGURATION, &[0x62, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c2.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    i2c2.block_read(REG_CONVERSION, &mut adc2_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let adc2val: u16 = u16::from_be_bytes(adc2_reg);
-    //println!(" ADC 2 decimal value = {:?} ", adc2val);
-    let adc2voltage: f32 = adc2val.into();
-
-    let mut adc2voltage: f32 = adc2voltage * 0.000125;
-    if adc2voltage > VOLTAGE_LIMIT {
-        adc2voltage = 0.01;
+    fn channel(oversampling: Option<u32>, data_rate: Option<u16>) -> ChannelConfig {
+        ChannelConfig {
+            oversampling,
+            data_rate,
+            ..ChannelConfig::default()
+        }
     }
-    println!(" ADC_1 2 voltage = {:?} ", adc2voltage);
-
-    Ok(adc2voltage)
-}
 
-async fn get_adc3_value() -> Result<f32, Box<dyn Error>> {
-    let mut adc3_reg = [0u8; 2];
+    #[test]
+    fn oversampling_defaults_to_the_fastest_data_rate() {
+        let single = AdcSampling::new(None);
+        assert_eq!(single.conversions, 1);
+        assert_eq!(single.data_rate, DEFAULT_DATA_RATE);
 
-    let mut i2c3 = I2c::new()?;
-    i2c3.set_slave_address(ADDR_ADS115)?;
+        let oversampled = AdcSampling::new(Some(&channel(Some(16), None)));
+        assert_eq!(oversampled.conversions, 16);
+        assert_eq!(oversampled.data_rate, OVERSAMPLING_DATA_RATE);
 
-    i2c3.block_write(REG_CONFIGURATION, &[0x72, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c3.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c3.block_read(REG_CONVERSION, &mut adc3_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let adc3val: u16 = u16::from_be_bytes(adc3_reg);
-    //println!(" ADC 3 decimal value = {:?} ", adc3val);
-    let adc3voltage: f32 = adc3val.into();
-
-    let mut adc3voltage: f32 = adc3voltage * 0.000125;
-    if adc3voltage > VOLTAGE_LIMIT {
-        adc3voltage = 0.01;
+        let chosen = AdcSampling::new(Some(&channel(Some(16), Some(250))));
+        assert_eq!(chosen.data_rate, 250);
     }
-    println!(" ADC_1 3 voltage = {:?} ", adc3voltage);
 
-    Ok(adc3voltage)
-}
-
-async fn get_adc0_2_value() -> Result<f32, Box<dyn Error>> // this is a second ADS1115 ADC slave chip
-{
-    let mut adc0_2_reg = [0u8; 2];
-
-    let mut i2c0 = I2c::new()?;
-    i2c0.set_slave_address(ADDR_ADS115_TWO)?;
-
-    i2c0.block_write(REG_CONFIGURATION, &[0x42, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_read(REG_CONVERSION, &mut adc0_2_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let adc0_2_val: u16 = u16::from_be_bytes(adc0_2_reg);
-    //println!(" ADC 0 decimal value = {:?} ", adc0val);
-    let adc0_2_voltage: f32 = adc0_2_val.into();
-
-    let mut adc0_2_voltage: f32 = adc0_2_voltage * 0.000125;
-    if adc0_2_voltage > VOLTAGE_LIMIT {
-        adc0_2_voltage = 0.01;
+    #[test]
+    fn config_reg2_only_replaces_the_data_rate_bits() {
+        let sampling = AdcSampling::new(Some(&channel(Some(4), Some(860))));
+        assert_eq!(sampling.config_reg2(0x82), 0xe2);
+        let sampling = AdcSampling::new(Some(&channel(None, Some(8))));
+        assert_eq!(sampling.config_reg2(0x82), 0x02);
+        assert_eq!(sampling.config_reg2(0xff), 0x1f);
     }
-    println!(" ADC_2 0 voltage = {:?} ", adc0_2_voltage);
-
-    Ok(adc0_2_voltage)
-}
-
-async fn get_adc1_2_value() -> Result<f32, Box<dyn Error>> // this is a second ADS1115 ADC slave chip
-{
-    let mut adc1_2_reg = [0u8; 2];
-
-    let mut i2c0 = I2c::new()?;
-    i2c0.set_slave_address(ADDR_ADS115_TWO)?;
-
-    i2c0.block_write(REG_CONFIGURATION, &[0x52, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
 
-    i2c0.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_read(REG_CONVERSION, &mut adc1_2_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let mut adc1_2_val: u16 = u16::from_be_bytes(adc1_2_reg);
-    //println!(" ADC 0 decimal value = {:?} ", adc0val);
-    let adc1_2_voltage: f32 = adc1_2_val.into();
-
-    let mut adc1_2_voltage: f32 = adc1_2_voltage * 0.000125;
-    if adc1_2_voltage > VOLTAGE_LIMIT {
-        adc1_2_voltage = 0.01;
+    #[test]
+    fn oversampled_reads_take_the_bus_longer() {
+        let single = AdcSampling::new(Some(&channel(None, Some(860))));
+        let oversampled = AdcSampling::new(Some(&channel(Some(8), Some(860))));
+        assert_eq!(
+            oversampled.bus_time() - single.bus_time(),
+            (single.conversion_period() + I2C_TRANSFER_TIME) * 7
+        );
     }
-    println!(" ADC_2 0 voltage = {:?} ", adc1_2_voltage);
-    Ok(adc1_2_voltage)
-}
 
-async fn get_adc2_2_value() -> Result<f32, Box<dyn Error>> // this is a second ADS1115 ADC slave chip
-{
-    let mut adc2_2_reg = [0u8; 2];
-
-    let mut i2c0 = I2c::new()?;
-    i2c0.set_slave_address(ADDR_ADS115_TWO)?;
-
-    i2c0.block_write(REG_CONFIGURATION, &[0x62, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_read(REG_CONVERSION, &mut adc2_2_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let adc2_2_val: u16 = u16::from_be_bytes(adc2_2_reg);
-    //println!(" ADC 0 decimal value = {:?} ", adc0val);
-    let adc2_2_voltage: f32 = adc2_2_val.into();
-
-    let mut adc2_2_voltage: f32 = adc2_2_voltage * 0.000125;
-    if adc2_2_voltage > VOLTAGE_LIMIT {
-        adc2_2_voltage = 0.01;
+    #[test]
+    fn the_shortest_period_follows_the_data_rate_and_oversampling() {
+        assert_eq!(AdcSampling::new(None).min_period_ms(), 24);
+        let fast = AdcSampling::new(Some(&channel(None, Some(860))));
+        assert_eq!(fast.min_period_ms(), 6);
+        let oversampled = AdcSampling::new(Some(&channel(Some(16), None)));
+        assert_eq!(oversampled.min_period_ms(), 39);
     }
-    println!(" ADC_2 2 voltage = {:?} ", adc2_2_voltage);
-
-    Ok(adc2_2_voltage)
-}
 
-async fn get_adc3_2_value() -> Result<f32, Box<dyn Error>> // this is a second ADS1115 ADC slave chip
-{
-    let mut adc3_2_reg = [0u8; 2];
-
-    let mut i2c0 = I2c::new()?;
-    i2c0.set_slave_address(ADDR_ADS115_TWO)?;
-
-    i2c0.block_write(REG_CONFIGURATION, &[0x72, 0x82])?; // Set configuration setting to ADS115
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_write(REG_CONVERSION, &[0x00])?; // Set ADS115 config to look at the conversion registers
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    i2c0.block_read(REG_CONVERSION, &mut adc3_2_reg)?; // reads ADS115 conversion register and puts contents into reg buffer
-    tokio::time::sleep(Duration::from_millis(I2C_DELAY_TIME)).await;
-
-    let adc3_2_val: u16 = u16::from_be_bytes(adc3_2_reg);
-    //println!(" ADC 0 decimal value = {:?} ", adc0val);
-    let adc3_2_voltage: f32 = adc3_2_val.into();
-
-    let mut adc3_2_voltage: f32 = adc3_2_voltage * 0.000125;
-    if adc3_2_voltage > VOLTAGE_LIMIT {
-        adc3_2_voltage = 0.01;
+    #[test]
+    fn a_10_ms_channel_needs_the_fast_data_rate() {
+        let fast: Config =
+            toml::from_str("[channels.adc1_channel0]\nperiod_ms = 10\ndata_rate = 860").unwrap();
+        assert!(fast.validate().is_ok());
+        let slow: Config = toml::from_str("[channels.adc1_channel0]\nperiod_ms = 10").unwrap();
+        assert!(slow.validate().is_err());
     }
-    println!(" ADC_2 3 voltage = {:?} ", adc3_2_voltage);
 
-    Ok(adc3_2_voltage)
+    #[test]
+    fn mean_and_noise_of_the_conversions() {
+        assert_eq!(mean_and_noise(&[1.5]), (1.5, 0.0));
+        let (mean, noise) = mean_and_noise(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(mean, 2.5);
+        assert!((noise - 1.118_034).abs() < 1e-6);
+    }
 }
@@ -0,0 +1,201 @@
+/// Renders the IO state in the Prometheus text exposition format for `/metrics`.
+use std::fmt::Write;
+
+use crate::{IoState, DIGITAL_INPUTS};
+
+/// Builds the full scrape body from a snapshot of the IO state and the
+/// number of connected websocket clients.
+pub fn render(io_state: &IoState, ws_clients: usize) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "io_channel_value",
+        "gauge",
+        "Latest value of a channel.",
+    );
+    for (channel, value) in io_state.channels.iter() {
+        writeln!(
+            out,
+            "io_channel_value{{channel=\"{}\"}} {}",
+            label(channel),
+            value
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_channel_raw_volts",
+        "gauge",
+        "ADC voltage of a channel before scaling.",
+    );
+    for (channel, volts) in io_state.raw.iter() {
+        writeln!(
+            out,
+            "io_channel_raw_volts{{channel=\"{}\"}} {}",
+            label(channel),
+            volts
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_channel_filtered_volts",
+        "gauge",
+        "ADC voltage of a channel after its filters, before scaling.",
+    );
+    for (channel, volts) in io_state.filtered.iter() {
+        writeln!(
+            out,
+            "io_channel_filtered_volts{{channel=\"{}\"}} {}",
+            label(channel),
+            volts
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_channel_noise_volts",
+        "gauge",
+        "Standard deviation of the ADC conversions averaged in the last read.",
+    );
+    for (channel, volts) in io_state.noise.iter() {
+        writeln!(
+            out,
+            "io_channel_noise_volts{{channel=\"{}\"}} {}",
+            label(channel),
+            volts
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_input_state",
+        "gauge",
+        "Level of a digital input (1 = high).",
+    );
+    for (name, pin) in DIGITAL_INPUTS.iter() {
+        let value = io_state.inputs.get(*name).copied().unwrap_or(false);
+        writeln!(
+            out,
+            "io_input_state{{input=\"{}\",pin=\"{}\"}} {}",
+            label(name),
+            pin,
+            value as u8
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_output_state",
+        "gauge",
+        "Level of a GPIO output (1 = high).",
+    );
+    for (pin, value) in io_state.outputs.iter() {
+        writeln!(out, "io_output_state{{pin=\"{}\"}} {}", pin, *value as u8).unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_i2c_errors_total",
+        "counter",
+        "Failed I2C transactions per chip address.",
+    );
+    for (address, count) in io_state.i2c_errors.iter() {
+        writeln!(
+            out,
+            "io_i2c_errors_total{{address=\"{:#04x}\"}} {}",
+            address, count
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_channel_sample_rate_hz",
+        "gauge",
+        "Reads per second an ADC channel is getting.",
+    );
+    for (channel, stats) in io_state.sampling.iter() {
+        writeln!(
+            out,
+            "io_channel_sample_rate_hz{{channel=\"{}\"}} {}",
+            label(channel),
+            stats.effective_hz
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_channel_read_duration_seconds",
+        "gauge",
+        "Duration of the last read of an ADC channel.",
+    );
+    for (channel, stats) in io_state.sampling.iter() {
+        writeln!(
+            out,
+            "io_channel_read_duration_seconds{{channel=\"{}\"}} {}",
+            label(channel),
+            stats.read_ms / 1000.0
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_channel_overruns_total",
+        "counter",
+        "Reads of an ADC channel skipped because an earlier read ran late.",
+    );
+    for (channel, stats) in io_state.sampling.iter() {
+        writeln!(
+            out,
+            "io_channel_overruns_total{{channel=\"{}\"}} {}",
+            label(channel),
+            stats.overruns
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "io_websocket_clients",
+        "gauge",
+        "Connected websocket clients.",
+    );
+    writeln!(out, "io_websocket_clients {ws_clients}").unwrap();
+
+    out
+}
+
+/// Escapes a label value as the text format requires.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(label("tank_level"), "tank_level");
+        assert_eq!(label("a\\b"), "a\\\\b");
+        assert_eq!(label("say \"hi\""), "say \\\"hi\\\"");
+        assert_eq!(label("two\nlines"), "two\\nlines");
+    }
+}
@@ -0,0 +1,406 @@
+/// Modbus master that polls external devices (power meters, VFDs, ...) and
+/// publishes their registers as channels next to the ADC values.
+///
+/// Each device gets its own task and connection. A failed or timed out poll
+/// marks that device's channels bad and drops the connection so the next poll
+/// reconnects.
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio_modbus::client::{rtu, tcp, Context, Reader};
+use tokio_modbus::Slave;
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::history::{self, History, Quality, Sample};
+use crate::state::SharedState;
+use crate::value::ChannelValue;
+use crate::ADC_CHANNELS;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ModbusDeviceConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub transport: Transport,
+    #[serde(default = "default_unit_id")]
+    pub unit_id: u8,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// Applies to connecting and to each request.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    pub registers: Vec<DeviceRegister>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum Transport {
+    Tcp {
+        address: SocketAddr,
+    },
+    /// 8 data bits, no parity, one stop bit.
+    Rtu {
+        port: String,
+        #[serde(default = "default_baud_rate")]
+        baud_rate: u32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterKind {
+    #[default]
+    Holding,
+    Input,
+}
+
+/// Data type of a register (or register pair for the 32 bit types).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterType {
+    #[default]
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl RegisterType {
+    fn word_count(&self) -> u16 {
+        match self {
+            RegisterType::U16 | RegisterType::I16 => 1,
+            RegisterType::U32 | RegisterType::I32 | RegisterType::F32 => 2,
+        }
+    }
+}
+
+/// One device register published as `channel = raw * scale + offset`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceRegister {
+    pub channel: String,
+    pub address: u16,
+    #[serde(default)]
+    pub kind: RegisterKind,
+    #[serde(default, rename = "type")]
+    pub data_type: RegisterType,
+    /// 32 bit values are high word first unless this is set.
+    #[serde(default)]
+    pub swap_words: bool,
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    #[serde(default)]
+    pub offset: f32,
+    /// Engineering units of the published value, e.g. "V" or "Hz".
+    pub units: Option<String>,
+}
+
+fn default_unit_id() -> u8 {
+    1
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_timeout_ms() -> u64 {
+    500
+}
+
+fn default_baud_rate() -> u32 {
+    9600
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+/// Checks the devices against each other and against the ADC channels they
+/// are published next to.
+pub fn validate(devices: &[ModbusDeviceConfig]) -> Result<(), String> {
+    let mut seen: Vec<&str> = Vec::new();
+    let mut names: Vec<&str> = Vec::new();
+    for (index, device) in devices.iter().enumerate() {
+        let context = format!("modbus_devices[{index}] ({})", device.name);
+        if device.name.trim().is_empty() {
+            return Err(format!("{context}: name must not be empty"));
+        }
+        // each device gets a folder of its own in OPC UA
+        if names.contains(&device.name.as_str()) {
+            return Err(format!("{context}: name is used twice"));
+        }
+        names.push(&device.name);
+        if device.poll_interval_ms == 0 {
+            return Err(format!("{context}: poll_interval_ms must be at least 1"));
+        }
+        if device.timeout_ms == 0 {
+            return Err(format!("{context}: timeout_ms must be at least 1"));
+        }
+        for register in device.registers.iter() {
+            let channel = register.channel.as_str();
+            if channel.trim().is_empty() {
+                return Err(format!("{context}: channel must not be empty"));
+            }
+            if ADC_CHANNELS.iter().any(|adc| adc.name == channel) {
+                return Err(format!(
+                    "{context}: channel {channel} is already an ADC channel"
+                ));
+            }
+            if seen.contains(&channel) {
+                return Err(format!("{context}: channel {channel} is used twice"));
+            }
+            seen.push(channel);
+        }
+    }
+    Ok(())
+}
+
+impl DeviceRegister {
+    fn decode(&self, words: &[u16]) -> f32 {
+        let (high, low) = match words {
+            [high, low] if self.swap_words => (*low, *high),
+            [high, low] => (*high, *low),
+            [word] => (0, *word),
+            _ => (0, 0),
+        };
+        let wide = ((high as u32) << 16) | low as u32;
+        let raw = match self.data_type {
+            RegisterType::U16 => low as f32,
+            RegisterType::I16 => low as i16 as f32,
+            RegisterType::U32 => wide as f32,
+            RegisterType::I32 => wide as i32 as f32,
+            RegisterType::F32 => f32::from_bits(wide),
+        };
+        raw * self.scale + self.offset
+    }
+}
+
+/// Starts one polling task per configured device.
+pub fn spawn(
+    devices: Vec<ModbusDeviceConfig>,
+    shared_state: SharedState,
+    history: Arc<Mutex<History>>,
+) {
+    for device in devices {
+        // register the channels up front so they are listed before the first poll
+        shared_state.update(|io_state| {
+            let channels = Arc::make_mut(&mut io_state.channels);
+            let units = Arc::make_mut(&mut io_state.units);
+            let quality = Arc::make_mut(&mut io_state.quality);
+            for register in device.registers.iter() {
+                channels.insert(register.channel.clone(), ChannelValue::default());
+                if let Some(register_units) = register.units.as_ref() {
+                    units.insert(register.channel.clone(), register_units.clone());
+                }
+                quality.insert(register.channel.clone(), Quality::Bad);
+            }
+        });
+        tokio::spawn(poll_device(device, shared_state.clone(), history.clone()));
+    }
+}
+
+async fn connect(device: &ModbusDeviceConfig) -> Result<Context, Box<dyn Error + Send + Sync>> {
+    let slave = Slave(device.unit_id);
+    let context = match &device.transport {
+        Transport::Tcp { address } => tcp::connect_slave(*address, slave).await?,
+        Transport::Rtu { port, baud_rate } => {
+            let serial = tokio_serial::new(port, *baud_rate).open_native_async()?;
+            rtu::attach_slave(serial, slave)
+        }
+    };
+    Ok(context)
+}
+
+async fn read_register(
+    context: &mut Context,
+    register: &DeviceRegister,
+) -> Result<f32, Box<dyn Error + Send + Sync>> {
+    let count = register.data_type.word_count();
+    let words = match register.kind {
+        RegisterKind::Holding => {
+            context
+                .read_holding_registers(register.address, count)
+                .await??
+        }
+        RegisterKind::Input => {
+            context
+                .read_input_registers(register.address, count)
+                .await??
+        }
+    };
+    Ok(register.decode(&words))
+}
+
+/// Polls every register of the device once.
+async fn poll_once(
+    context: &mut Context,
+    device: &ModbusDeviceConfig,
+    timeout: Duration,
+) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> {
+    let mut values = Vec::with_capacity(device.registers.len());
+    for register in device.registers.iter() {
+        let value = tokio::time::timeout(timeout, read_register(context, register))
+            .await
+            .map_err(|_| format!("{} timed out", register.channel))??;
+        values.push(value);
+    }
+    Ok(values)
+}
+
+async fn poll_device(
+    device: ModbusDeviceConfig,
+    shared_state: SharedState,
+    history: Arc<Mutex<History>>,
+) {
+    let timeout = Duration::from_millis(device.timeout_ms);
+    let mut interval = tokio::time::interval(Duration::from_millis(device.poll_interval_ms));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut context: Option<Context> = None;
+
+    loop {
+        interval.tick().await;
+
+        if context.is_none() {
+            match tokio::time::timeout(timeout, connect(&device)).await {
+                Ok(Ok(connected)) => {
+                    println!("Modbus device {} connected", device.name);
+                    context = Some(connected);
+                }
+                Ok(Err(e)) => println!("Modbus device {} connect failed: {}", device.name, e),
+                Err(_) => println!("Modbus device {} connect timed out", device.name),
+            }
+        }
+
+        let values = match context.as_mut() {
+            Some(connected) => match poll_once(connected, &device, timeout).await {
+                Ok(values) => Some(values),
+                Err(e) => {
+                    println!("Modbus device {} poll failed: {}", device.name, e);
+                    context = None;
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let sampled_at = history::now_ms();
+        let mut samples = Vec::with_capacity(device.registers.len());
+        shared_state.update(|io_state| {
+            let channels = Arc::make_mut(&mut io_state.channels);
+            let qualities = Arc::make_mut(&mut io_state.quality);
+            let read_ms = Arc::make_mut(&mut io_state.read_ms);
+            for (i, register) in device.registers.iter().enumerate() {
+                let precision = io_state
+                    .precision
+                    .get(&register.channel)
+                    .copied()
+                    .unwrap_or_default();
+                let reading = values
+                    .as_ref()
+                    .and_then(|values| precision.value(values[i]));
+                let (value, quality) = match reading {
+                    Some(value) => (value, Quality::Good),
+                    // also NaN or infinite floats, which have no decimal value
+                    None => {
+                        let last = channels.get(&register.channel).copied();
+                        (last.unwrap_or_default(), Quality::Bad)
+                    }
+                };
+                channels.insert(register.channel.clone(), value);
+                qualities.insert(register.channel.clone(), quality);
+                read_ms.insert(register.channel.clone(), sampled_at);
+                samples.push(Sample {
+                    ts_ms: sampled_at,
+                    channel: register.channel.clone(),
+                    value: value.to_f32(),
+                    quality,
+                });
+            }
+        });
+
+        let mut history = history.lock().unwrap_or_else(PoisonError::into_inner);
+        for sample in samples {
+            if let Err(e) = history.record(sample) {
+                println!("Failed to record history: {}", e);
+            }
+        }
+        if let Err(e) = history.flush() {
+            println!("Failed to flush history: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(data_type: RegisterType, swap_words: bool) -> DeviceRegister {
+        DeviceRegister {
+            channel: "meter".to_string(),
+            address: 0,
+            kind: RegisterKind::Holding,
+            data_type,
+            swap_words,
+            scale: 1.0,
+            offset: 0.0,
+            units: None,
+        }
+    }
+
+    #[test]
+    fn decodes_every_type() {
+        use RegisterType::*;
+        // 1.5 is 0x3fc00000
+        for (data_type, swap_words, words, expected) in [
+            (U16, false, vec![0xfffe], 65534.0),
+            (I16, false, vec![0xfffe], -2.0),
+            (U32, false, vec![0x0001, 0x0002], 65538.0),
+            (U32, true, vec![0x0002, 0x0001], 65538.0),
+            (I32, false, vec![0xffff, 0xfffe], -2.0),
+            (I32, true, vec![0xfffe, 0xffff], -2.0),
+            (F32, false, vec![0x3fc0, 0x0000], 1.5),
+            (F32, true, vec![0x0000, 0x3fc0], 1.5),
+        ] {
+            let register = register(data_type, swap_words);
+            assert_eq!(
+                register.decode(&words),
+                expected,
+                "{data_type:?} swap_words={swap_words} {words:04x?}"
+            );
+        }
+    }
+
+    #[test]
+    fn applies_scale_then_offset() {
+        let mut register = register(RegisterType::I16, false);
+        register.scale = 0.1;
+        register.offset = -40.0;
+        assert!((register.decode(&[250]) - -15.0).abs() < 1e-4);
+        assert!((register.decode(&[(-100i16) as u16]) - -50.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rejects_clashing_names() {
+        let device = |name: &str, channel: &str| ModbusDeviceConfig {
+            name: name.to_string(),
+            transport: Transport::Tcp {
+                address: "127.0.0.1:502".parse().unwrap(),
+            },
+            unit_id: default_unit_id(),
+            poll_interval_ms: default_poll_interval_ms(),
+            timeout_ms: default_timeout_ms(),
+            registers: vec![DeviceRegister {
+                channel: channel.to_string(),
+                ..register(RegisterType::U16, false)
+            }],
+        };
+        assert!(validate(&[device("meter", "volts"), device("vfd", "hz")]).is_ok());
+        let error = validate(&[device("meter", "volts"), device("meter", "amps")]).unwrap_err();
+        assert!(error.contains("name is used twice"), "{error}");
+        assert!(validate(&[device("meter", "volts"), device("vfd", "volts")]).is_err());
+        assert!(validate(&[device("meter", "adc1_channel0")]).is_err());
+        assert!(validate(&[device(" ", "volts")]).is_err());
+    }
+}
@@ -0,0 +1,523 @@
+/// Modbus TCP slave so PLCs and SCADA can read the IO state.
+///
+/// Analog channels are served as input registers, digital inputs as discrete
+/// inputs and GPIO outputs as coils. Coil writes are turned into
+/// `OutputCommand`s on the same channel the websocket uses, so the output
+/// task stays the only owner of the output pins. A write the interlocks or a
+/// fail-safe hold would refuse is answered with an IllegalDataValue
+/// exception, and none of its coils are switched.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+use tokio_modbus::{ExceptionCode, Request, Response};
+
+use crate::failsafe::{self, Outputs};
+use crate::interlock::Interlocks;
+use crate::state::SharedState;
+use crate::{IoState, OutputCommand, DIGITAL_INPUTS, SWITCHED_OUTPUTS};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModbusServerConfig {
+    /// Address to listen on, e.g. "0.0.0.0:502".
+    pub bind: SocketAddr,
+    #[serde(default)]
+    pub input_registers: Vec<InputRegisterMap>,
+    #[serde(default)]
+    pub discrete_inputs: Vec<DiscreteInputMap>,
+    #[serde(default)]
+    pub coils: Vec<CoilMap>,
+}
+
+/// How a channel value is packed into registers.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterEncoding {
+    /// One register holding `round(value * scale)`, clamped to 16 bits.
+    #[default]
+    Scaled,
+    /// Two registers holding the IEEE 754 single, high word first unless
+    /// `swap_words` is set.
+    Float,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputRegisterMap {
+    pub address: u16,
+    pub channel: String,
+    #[serde(default)]
+    pub encoding: RegisterEncoding,
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// Scaled values are two's complement when set, unsigned otherwise.
+    #[serde(default)]
+    pub signed: bool,
+    #[serde(default)]
+    pub swap_words: bool,
+}
+
+fn default_scale() -> f32 {
+    1000.0
+}
+
+impl ModbusServerConfig {
+    /// Checks that every mapping points at something that exists and that
+    /// no address is mapped twice or runs past the end of the address range.
+    /// `channels` are the names of all channels, ADC and Modbus devices.
+    pub fn validate(&self, channels: &[String]) -> Result<(), String> {
+        for map in self.input_registers.iter() {
+            if !channels.contains(&map.channel) {
+                return Err(format!(
+                    "modbus_server.input_registers: no channel named {}",
+                    map.channel
+                ));
+            }
+        }
+        for map in self.discrete_inputs.iter() {
+            if !DIGITAL_INPUTS.iter().any(|(name, _)| *name == map.input) {
+                return Err(format!(
+                    "modbus_server.discrete_inputs: no input named {}",
+                    map.input
+                ));
+            }
+        }
+        for map in self.coils.iter() {
+            if !SWITCHED_OUTPUTS.contains(&map.pin) {
+                return Err(format!(
+                    "modbus_server.coils: pin {} is not a switched output",
+                    map.pin
+                ));
+            }
+        }
+        RegisterMap::new(self)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscreteInputMap {
+    pub address: u16,
+    pub input: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoilMap {
+    pub address: u16,
+    pub pin: u8,
+}
+
+/// What a single register address reads from.
+#[derive(Clone)]
+enum RegisterSource {
+    Scaled {
+        channel: String,
+        scale: f32,
+        signed: bool,
+    },
+    FloatWord {
+        channel: String,
+        /// 0 for the high half of the float, 1 for the low half.
+        half: usize,
+    },
+}
+
+/// Address lookup tables built once from the config.
+struct RegisterMap {
+    input_registers: BTreeMap<u16, RegisterSource>,
+    discrete_inputs: BTreeMap<u16, String>,
+    coils: BTreeMap<u16, u8>,
+}
+
+impl RegisterMap {
+    fn new(config: &ModbusServerConfig) -> Result<Self, String> {
+        let mut input_registers = BTreeMap::new();
+        for map in config.input_registers.iter() {
+            let sources = match map.encoding {
+                RegisterEncoding::Scaled => vec![(
+                    map.address,
+                    RegisterSource::Scaled {
+                        channel: map.channel.clone(),
+                        scale: map.scale,
+                        signed: map.signed,
+                    },
+                )],
+                RegisterEncoding::Float => {
+                    if map.address == u16::MAX {
+                        return Err(format!(
+                            "modbus_server.input_registers: float register {} needs two \
+                             registers and runs past the last address",
+                            map.address
+                        ));
+                    }
+                    let (first, second) = if map.swap_words { (1, 0) } else { (0, 1) };
+                    vec![
+                        (
+                            map.address,
+                            RegisterSource::FloatWord {
+                                channel: map.channel.clone(),
+                                half: first,
+                            },
+                        ),
+                        (
+                            map.address + 1,
+                            RegisterSource::FloatWord {
+                                channel: map.channel.clone(),
+                                half: second,
+                            },
+                        ),
+                    ]
+                }
+            };
+            for (address, source) in sources {
+                if input_registers.insert(address, source).is_some() {
+                    return Err(overlap("input_registers", address));
+                }
+            }
+        }
+
+        let mut discrete_inputs = BTreeMap::new();
+        for map in config.discrete_inputs.iter() {
+            if discrete_inputs
+                .insert(map.address, map.input.clone())
+                .is_some()
+            {
+                return Err(overlap("discrete_inputs", map.address));
+            }
+        }
+
+        let mut coils = BTreeMap::new();
+        for map in config.coils.iter() {
+            if coils.insert(map.address, map.pin).is_some() {
+                return Err(overlap("coils", map.address));
+            }
+        }
+
+        Ok(Self {
+            input_registers,
+            discrete_inputs,
+            coils,
+        })
+    }
+}
+
+fn overlap(table: &str, address: u16) -> String {
+    format!("modbus_server.{table}: address {address} is mapped more than once")
+}
+
+struct IoService {
+    map: Arc<RegisterMap>,
+    shared_state: SharedState,
+    output_sender: Sender<OutputCommand>,
+    outputs: Arc<Mutex<Outputs>>,
+    interlocks: Arc<Mutex<Interlocks>>,
+}
+
+impl IoService {
+    /// Addresses `address .. address + quantity`, or an exception if any of
+    /// them is outside the map.
+    fn addresses<T>(
+        table: &BTreeMap<u16, T>,
+        address: u16,
+        quantity: u16,
+    ) -> Result<Vec<&T>, ExceptionCode> {
+        (0..quantity)
+            .map(|offset| {
+                address
+                    .checked_add(offset)
+                    .and_then(|address| table.get(&address))
+                    .ok_or(ExceptionCode::IllegalDataAddress)
+            })
+            .collect()
+    }
+
+    fn read_input_registers(&self, address: u16, quantity: u16) -> Result<Response, ExceptionCode> {
+        let sources = Self::addresses(&self.map.input_registers, address, quantity)?;
+        let io_state = self.shared_state.snapshot();
+        let words = sources
+            .into_iter()
+            .map(|source| source.word(&io_state))
+            .collect();
+        Ok(Response::ReadInputRegisters(words))
+    }
+
+    fn read_discrete_inputs(&self, address: u16, quantity: u16) -> Result<Response, ExceptionCode> {
+        let inputs = Self::addresses(&self.map.discrete_inputs, address, quantity)?;
+        let io_state = self.shared_state.snapshot();
+        let bits = inputs
+            .into_iter()
+            .map(|input| io_state.inputs.get(input).copied().unwrap_or(false))
+            .collect();
+        Ok(Response::ReadDiscreteInputs(bits))
+    }
+
+    fn read_coils(&self, address: u16, quantity: u16) -> Result<Response, ExceptionCode> {
+        let pins = Self::addresses(&self.map.coils, address, quantity)?;
+        let io_state = self.shared_state.snapshot();
+        let bits = pins
+            .into_iter()
+            .map(|pin| io_state.outputs.get(pin).copied().unwrap_or(false))
+            .collect();
+        Ok(Response::ReadCoils(bits))
+    }
+
+    fn write_coils(&self, address: u16, values: &[bool]) -> Result<(), ExceptionCode> {
+        let pins = Self::addresses(&self.map.coils, address, values.len() as u16)?;
+        for (pin, value) in pins.iter().zip(values) {
+            if !self.permits(**pin, *value) {
+                println!("Modbus client was refused setting pin {} to {}", pin, value);
+                return Err(ExceptionCode::IllegalDataValue);
+            }
+        }
+        for (pin, value) in pins.into_iter().zip(values) {
+            println!("Modbus client set pin {} to {}", pin, value);
+            self.output_sender
+                .try_send(OutputCommand::LedSet(*pin as i32, *value))
+                .map_err(|_| ExceptionCode::ServerDeviceBusy)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the output task would switch `pin` to `high` right now. It
+    /// checks again when the command runs.
+    fn permits(&self, pin: u8, high: bool) -> bool {
+        let outputs = failsafe::lock(&self.outputs);
+        if !outputs.permits(pin, high) {
+            return false;
+        }
+        self.interlocks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .permits(pin, high, &self.shared_state.snapshot())
+    }
+}
+
+impl RegisterSource {
+    /// The register's current content.
+    fn word(&self, io_state: &IoState) -> u16 {
+        match self {
+            RegisterSource::Scaled {
+                channel,
+                scale,
+                signed,
+            } => {
+                let value = (channel_value(io_state, channel) * scale).round();
+                if *signed {
+                    value.clamp(i16::MIN as f32, i16::MAX as f32) as i16 as u16
+                } else {
+                    value.clamp(0.0, u16::MAX as f32) as u16
+                }
+            }
+            RegisterSource::FloatWord { channel, half } => {
+                let bits = channel_value(io_state, channel).to_bits();
+                if *half == 0 {
+                    (bits >> 16) as u16
+                } else {
+                    bits as u16
+                }
+            }
+        }
+    }
+}
+
+fn channel_value(io_state: &IoState, channel: &str) -> f32 {
+    io_state
+        .channels
+        .get(channel)
+        .map_or(0.0, |value| value.to_f32())
+}
+
+impl tokio_modbus::server::Service for IoService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = future::Ready<Result<Response, ExceptionCode>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let response = match req {
+            Request::ReadInputRegisters(address, quantity) => {
+                self.read_input_registers(address, quantity)
+            }
+            Request::ReadDiscreteInputs(address, quantity) => {
+                self.read_discrete_inputs(address, quantity)
+            }
+            Request::ReadCoils(address, quantity) => self.read_coils(address, quantity),
+            Request::WriteSingleCoil(address, value) => self
+                .write_coils(address, &[value])
+                .map(|_| Response::WriteSingleCoil(address, value)),
+            Request::WriteMultipleCoils(address, values) => self
+                .write_coils(address, &values)
+                .map(|_| Response::WriteMultipleCoils(address, values.len() as u16)),
+            _ => Err(ExceptionCode::IllegalFunction),
+        };
+        future::ready(response)
+    }
+}
+
+/// Runs the Modbus TCP server until the listener fails.
+pub async fn serve(
+    config: ModbusServerConfig,
+    shared_state: SharedState,
+    outputs: Arc<Mutex<Outputs>>,
+    interlocks: Arc<Mutex<Interlocks>>,
+) -> io::Result<()> {
+    // already checked by config validation
+    let map = RegisterMap::new(&config).map_err(io::Error::other)?;
+    let listener = TcpListener::bind(config.bind).await?;
+    println!("Modbus TCP server listening on {}", config.bind);
+    serve_on(listener, map, shared_state, outputs, interlocks).await
+}
+
+async fn serve_on(
+    listener: TcpListener,
+    map: RegisterMap,
+    shared_state: SharedState,
+    outputs: Arc<Mutex<Outputs>>,
+    interlocks: Arc<Mutex<Interlocks>>,
+) -> io::Result<()> {
+    let map = Arc::new(map);
+    let output_sender = shared_state.snapshot().sneaky_sender.clone();
+    let server = Server::new(listener);
+
+    let on_connected = |stream, socket_addr| {
+        let map = map.clone();
+        let shared_state = shared_state.clone();
+        let output_sender = output_sender.clone();
+        let outputs = outputs.clone();
+        let interlocks = interlocks.clone();
+        async move {
+            println!("Modbus client connected from {}", socket_addr);
+            accept_tcp_connection(stream, socket_addr, move |_| {
+                Ok(Some(IoService {
+                    map: map.clone(),
+                    shared_state: shared_state.clone(),
+                    output_sender: output_sender.clone(),
+                    outputs: outputs.clone(),
+                    interlocks: interlocks.clone(),
+                }))
+            })
+        }
+    };
+    let on_process_error = |e| println!("Modbus client error: {}", e);
+    server.serve(&on_connected, on_process_error).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failsafe::FailSafeConfig;
+    use crate::value::Precision;
+    use tokio_modbus::client::{tcp, Reader};
+
+    fn register(address: u16, channel: &str, encoding: RegisterEncoding) -> InputRegisterMap {
+        InputRegisterMap {
+            address,
+            channel: channel.to_string(),
+            encoding,
+            scale: default_scale(),
+            signed: false,
+            swap_words: false,
+        }
+    }
+
+    fn config(input_registers: Vec<InputRegisterMap>) -> ModbusServerConfig {
+        ModbusServerConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            input_registers,
+            discrete_inputs: Vec::new(),
+            coils: Vec::new(),
+        }
+    }
+
+    fn channels() -> Vec<String> {
+        ["level", "tank"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn rejects_overlapping_registers() {
+        let float = register(0, "tank", RegisterEncoding::Float);
+        let scaled = register(1, "level", RegisterEncoding::Scaled);
+        let error = config(vec![float, scaled])
+            .validate(&channels())
+            .unwrap_err();
+        assert!(
+            error.contains("address 1 is mapped more than once"),
+            "{error}"
+        );
+
+        let mut coils = config(Vec::new());
+        coils.coils = vec![
+            CoilMap {
+                address: 4,
+                pin: 20,
+            },
+            CoilMap {
+                address: 4,
+                pin: 20,
+            },
+        ];
+        assert!(coils.validate(&channels()).is_err());
+    }
+
+    #[test]
+    fn rejects_a_float_at_the_last_address() {
+        let float = register(u16::MAX, "tank", RegisterEncoding::Float);
+        assert!(config(vec![float]).validate(&channels()).is_err());
+        let scaled = register(u16::MAX, "tank", RegisterEncoding::Scaled);
+        assert!(config(vec![scaled]).validate(&channels()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn serves_the_register_encodings() {
+        let mut io_state = IoState::new(tokio::sync::mpsc::channel(1).0);
+        let values = Arc::make_mut(&mut io_state.channels);
+        values.insert(
+            "level".to_string(),
+            Precision::default().value(1.25).unwrap(),
+        );
+        values.insert(
+            "tank".to_string(),
+            Precision::default().value(-1.5).unwrap(),
+        );
+        let shared_state = SharedState::new(io_state);
+
+        let unsigned = register(0, "level", RegisterEncoding::Scaled);
+        let clamped = register(1, "tank", RegisterEncoding::Scaled);
+        let mut signed = register(2, "tank", RegisterEncoding::Scaled);
+        signed.signed = true;
+        signed.scale = 100.0;
+        let float = register(10, "level", RegisterEncoding::Float);
+        let mut swapped = register(12, "level", RegisterEncoding::Float);
+        swapped.swap_words = true;
+        let config = config(vec![unsigned, clamped, signed, float, swapped]);
+        config.validate(&channels()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let outputs = Arc::new(Mutex::new(Outputs::new(&FailSafeConfig::default())));
+        let interlocks = Arc::new(Mutex::new(Interlocks::new(
+            Vec::new(),
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+        )));
+        let map = RegisterMap::new(&config).unwrap();
+        tokio::spawn(serve_on(listener, map, shared_state, outputs, interlocks));
+
+        let mut client = tcp::connect(address).await.unwrap();
+        let scaled = client.read_input_registers(0, 3).await.unwrap().unwrap();
+        assert_eq!(scaled, vec![1250, 0, (-150i16) as u16]);
+        // 1.25 is 0x3fa00000
+        let floats = client.read_input_registers(10, 4).await.unwrap().unwrap();
+        assert_eq!(floats, vec![0x3fa0, 0x0000, 0x0000, 0x3fa0]);
+        let unmapped = client.read_input_registers(3, 1).await.unwrap();
+        assert_eq!(unmapped, Err(ExceptionCode::IllegalDataAddress));
+    }
+}
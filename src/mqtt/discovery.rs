@@ -0,0 +1,114 @@
+/// Home Assistant MQTT discovery.
+///
+/// On every connect a retained config message is published per entity so HA
+/// picks the box up without hand-written YAML: channels become sensors,
+/// digital inputs binary_sensors and GPIO outputs switches. All of them point
+/// at the topics the bridge already publishes and share the bridge's
+/// online/offline status as availability.
+use rumqttc::{AsyncClient, QoS};
+use serde_json::{json, Value};
+
+use super::{output_name, status_topic, MqttConfig};
+use crate::{IoState, SWITCHED_OUTPUTS};
+
+/// Publishes discovery configs for everything currently in `io_state`.
+pub fn publish(config: &MqttConfig, client: &AsyncClient, io_state: &IoState) {
+    let node_id = node_id(&config.client_id);
+    let device = json!({
+        "identifiers": [node_id],
+        "name": config.device_name.clone().unwrap_or_else(|| config.client_id.clone()),
+        "manufacturer": "io_server",
+        "model": "Raspberry Pi IO",
+    });
+
+    let mut entities: Vec<(&str, String, Value)> = Vec::new();
+
+    for channel in io_state.channels.keys() {
+        let units = io_state.units.get(channel);
+        let mut entity = entity(config, &node_id, &device, channel);
+        entity["value_template"] = json!("{{ value_json.value }}");
+        entity["state_class"] = json!("measurement");
+        if let Some(units) = units {
+            entity["unit_of_measurement"] = json!(units);
+            if let Some(device_class) = device_class(units) {
+                entity["device_class"] = json!(device_class);
+                if device_class == "energy" {
+                    // HA rejects energy sensors that are not totals
+                    entity["state_class"] = json!("total_increasing");
+                }
+            }
+        }
+        entities.push(("sensor", channel.clone(), entity));
+    }
+
+    for input in io_state.inputs.keys() {
+        let mut entity = entity(config, &node_id, &device, input);
+        entity["value_template"] = json!("{{ 'ON' if value_json.value else 'OFF' }}");
+        entities.push(("binary_sensor", input.clone(), entity));
+    }
+
+    for pin in SWITCHED_OUTPUTS {
+        let name = output_name(pin);
+        let mut entity = entity(config, &node_id, &device, &name);
+        entity["value_template"] = json!("{{ 'ON' if value_json.value else 'OFF' }}");
+        entity["command_topic"] = json!(format!("{}/{}/set", config.prefix, name));
+        entity["payload_on"] = json!("ON");
+        entity["payload_off"] = json!("OFF");
+        entities.push(("switch", name, entity));
+    }
+
+    for (component, name, entity) in entities {
+        let topic = format!(
+            "{}/{}/{}/{}/config",
+            config.discovery_prefix, component, node_id, name
+        );
+        if client
+            .try_publish(&topic, QoS::AtLeastOnce, true, entity.to_string())
+            .is_err()
+        {
+            println!("MQTT discovery for {} dropped, request queue is full", name);
+        }
+    }
+}
+
+/// Fields shared by every entity.
+fn entity(config: &MqttConfig, node_id: &str, device: &Value, name: &str) -> Value {
+    json!({
+        "name": name,
+        "unique_id": format!("{}_{}", node_id, name),
+        "object_id": format!("{}_{}", node_id, name),
+        "state_topic": format!("{}/{}", config.prefix, name),
+        "availability_topic": status_topic(config),
+        "payload_available": "online",
+        "payload_not_available": "offline",
+        "device": device,
+    })
+}
+
+/// HA only allows `[a-zA-Z0-9_-]` in discovery node ids.
+fn node_id(client_id: &str) -> String {
+    client_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Best guess at the HA device class from the units.
+fn device_class(units: &str) -> Option<&'static str> {
+    match units {
+        "V" | "mV" => Some("voltage"),
+        "A" | "mA" => Some("current"),
+        "W" | "kW" => Some("power"),
+        "Wh" | "kWh" => Some("energy"),
+        "Hz" => Some("frequency"),
+        "°C" | "°F" | "K" => Some("temperature"),
+        "bar" | "mbar" | "Pa" | "kPa" | "hPa" | "psi" => Some("pressure"),
+        _ => None,
+    }
+}
@@ -0,0 +1,329 @@
+/// MQTT bridge: publishes channel, input and output changes and takes output
+/// commands from the broker.
+///
+/// Values go to `<prefix>/<name>` as retained JSON. Outputs are named
+/// `output_<pin>` and accept commands on `<prefix>/output_<pin>/set`, which
+/// are fed into the same `OutputCommand` channel as the websocket. The broker
+/// is told `<prefix>/status` is "offline" through the last will, and "online"
+/// once connected. On shutdown the bridge publishes the last values and
+/// "offline" itself and disconnects cleanly, so the will is not sent.
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+mod discovery;
+
+use crate::history::{self, Quality};
+use crate::shutdown::Shutdown;
+use crate::state::SharedState;
+use crate::value::ChannelValue;
+use crate::OutputCommand;
+
+// Reconnect backoff doubles from the first delay up to the cap.
+const RECONNECT_DELAY_FIRST: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    /// Topic prefix, e.g. "lab/pi-01".
+    pub prefix: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// How often the IO state is checked for changes to publish.
+    #[serde(default = "default_publish_interval_ms")]
+    pub publish_interval_ms: u64,
+    /// Publish Home Assistant discovery configs on connect.
+    #[serde(default)]
+    pub discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Device name shown in Home Assistant, defaults to the client id.
+    pub device_name: Option<String>,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "io_server".to_string()
+}
+
+fn default_keep_alive_secs() -> u64 {
+    30
+}
+
+fn default_publish_interval_ms() -> u64 {
+    500
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl MqttConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.publish_interval_ms == 0 {
+            return Err("mqtt.publish_interval_ms must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Retained payload for an analog or Modbus channel.
+#[derive(Serialize)]
+struct ChannelPayload {
+    value: ChannelValue,
+    units: Option<String>,
+    quality: Quality,
+    ts_ms: u64,
+}
+
+/// Retained payload for a digital input or output.
+#[derive(Serialize)]
+struct StatePayload {
+    value: bool,
+    ts_ms: u64,
+}
+
+/// Name the MQTT bridge (and discovery) uses for a GPIO output.
+pub fn output_name(pin: u8) -> String {
+    format!("output_{pin}")
+}
+
+fn status_topic(config: &MqttConfig) -> String {
+    format!("{}/status", config.prefix)
+}
+
+/// Connects to the broker and keeps the bridge running, reconnecting with
+/// backoff whenever the connection drops, until `shutdown`. The returned
+/// handle finishes once the broker has been told goodbye.
+pub fn spawn(config: MqttConfig, shared_state: SharedState, shutdown: Shutdown) -> JoinHandle<()> {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+    options.set_last_will(LastWill::new(
+        status_topic(&config),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, eventloop) = AsyncClient::new(options, 256);
+    let output_sender = shared_state.snapshot().sneaky_sender.clone();
+    // bumped on every (re)connect so the publisher resends everything
+    let (connected_tx, connected_rx) = watch::channel(0u64);
+
+    tokio::spawn(publish_changes(
+        config.clone(),
+        client.clone(),
+        shared_state.clone(),
+        connected_rx,
+        shutdown.clone(),
+    ));
+    tokio::spawn(run_eventloop(
+        config,
+        client,
+        eventloop,
+        shared_state,
+        output_sender,
+        connected_tx,
+        shutdown,
+    ))
+}
+
+async fn run_eventloop(
+    config: MqttConfig,
+    client: AsyncClient,
+    mut eventloop: EventLoop,
+    shared_state: SharedState,
+    output_sender: Sender<OutputCommand>,
+    connected: watch::Sender<u64>,
+    shutdown: Shutdown,
+) {
+    let command_filter = format!("{}/+/set", config.prefix);
+    let mut delay = RECONNECT_DELAY_FIRST;
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("MQTT connected to {}:{}", config.host, config.port);
+                delay = RECONNECT_DELAY_FIRST;
+                // the session is clean, so subscriptions are redone on every connect
+                let _ = client.try_subscribe(&command_filter, QoS::AtLeastOnce);
+                let _ = client.try_publish(status_topic(&config), QoS::AtLeastOnce, true, "online");
+                if config.discovery {
+                    let io_state = shared_state.snapshot();
+                    discovery::publish(&config, &client, &io_state);
+                }
+                connected.send_modify(|count| *count += 1);
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                handle_command(&config, &publish.topic, &publish.payload, &output_sender);
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                println!("MQTT disconnected");
+                return;
+            }
+            Ok(_) => {}
+            Err(e) if shutdown.is_triggered() => {
+                println!("MQTT connection error while shutting down: {}", e);
+                return;
+            }
+            Err(e) => {
+                println!("MQTT connection error: {}, retrying in {:?}", e, delay);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    // offline anyway, so there is nobody to say goodbye to
+                    _ = shutdown.wait() => return,
+                }
+                delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+            }
+        }
+    }
+}
+
+/// Turns `<prefix>/output_<pin>/set` messages into output commands. Accepts
+/// ON/OFF, true/false, 1/0 and TOGGLE.
+fn handle_command(
+    config: &MqttConfig,
+    topic: &str,
+    payload: &[u8],
+    output_sender: &Sender<OutputCommand>,
+) {
+    let pin = topic
+        .strip_prefix(config.prefix.as_str())
+        .and_then(|rest| rest.strip_prefix("/output_"))
+        .and_then(|rest| rest.strip_suffix("/set"))
+        .and_then(|pin| pin.parse::<i32>().ok());
+    let Some(pin) = pin else {
+        println!("MQTT ignoring command on {}", topic);
+        return;
+    };
+
+    let payload = String::from_utf8_lossy(payload).trim().to_ascii_lowercase();
+    let command = match payload.as_str() {
+        "on" | "true" | "1" => OutputCommand::LedSet(pin, true),
+        "off" | "false" | "0" => OutputCommand::LedSet(pin, false),
+        "toggle" => OutputCommand::LedToggle(pin),
+        _ => {
+            println!("MQTT ignoring command {:?} on {}", payload, topic);
+            return;
+        }
+    };
+    println!("MQTT command on {}: {}", topic, payload);
+    if output_sender.try_send(command).is_err() {
+        println!("MQTT dropped command on {}, output queue is full", topic);
+    }
+}
+
+/// Publishes every value whose payload changed since it was last sent. On
+/// shutdown it publishes once more, then "offline" and the disconnect.
+async fn publish_changes(
+    config: MqttConfig,
+    client: AsyncClient,
+    shared_state: SharedState,
+    mut connected: watch::Receiver<u64>,
+    shutdown: Shutdown,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(config.publish_interval_ms));
+    // last published payload per topic, without the timestamp
+    let mut published: BTreeMap<String, String> = BTreeMap::new();
+
+    loop {
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            Ok(()) = connected.changed() => {
+                published.clear();
+                false
+            }
+            _ = shutdown.wait() => true,
+        };
+
+        let io_state = shared_state.snapshot();
+        let ts_ms = history::now_ms();
+        let mut messages: Vec<(String, String, String)> = Vec::new();
+
+        for (channel, value) in io_state.channels.iter() {
+            let payload = ChannelPayload {
+                value: *value,
+                units: io_state.units.get(channel).cloned(),
+                quality: io_state.quality.get(channel).copied().unwrap_or_default(),
+                ts_ms: 0,
+            };
+            messages.push(channel_message(&config, channel, payload, ts_ms));
+        }
+        for (input, value) in io_state.inputs.iter() {
+            messages.push(state_message(&config, input, *value, ts_ms));
+        }
+        for (pin, value) in io_state.outputs.iter() {
+            messages.push(state_message(&config, &output_name(*pin), *value, ts_ms));
+        }
+
+        for (topic, key, payload) in messages {
+            if published.get(&topic) == Some(&key) {
+                continue;
+            }
+            // when offline the request queue fills up; keep the old key so the
+            // value is retried on the next tick
+            if client
+                .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+                .is_ok()
+            {
+                published.insert(topic, key);
+            }
+        }
+
+        if stopping {
+            // queued behind the values, and the event loop ends once the
+            // disconnect is out
+            let _ = client
+                .publish(status_topic(&config), QoS::AtLeastOnce, true, "offline")
+                .await;
+            let _ = client.disconnect().await;
+            return;
+        }
+    }
+}
+
+/// (topic, change key, payload) for a channel. The key is the payload without
+/// its timestamp so a steady value is not republished every tick.
+fn channel_message(
+    config: &MqttConfig,
+    channel: &str,
+    mut payload: ChannelPayload,
+    ts_ms: u64,
+) -> (String, String, String) {
+    let key = serde_json::to_string(&payload).unwrap();
+    payload.ts_ms = ts_ms;
+    (
+        format!("{}/{}", config.prefix, channel),
+        key,
+        serde_json::to_string(&payload).unwrap(),
+    )
+}
+
+fn state_message(
+    config: &MqttConfig,
+    name: &str,
+    value: bool,
+    ts_ms: u64,
+) -> (String, String, String) {
+    let key = serde_json::to_string(&StatePayload { value, ts_ms: 0 }).unwrap();
+    let payload = serde_json::to_string(&StatePayload { value, ts_ms }).unwrap();
+    (format!("{}/{}", config.prefix, name), key, payload)
+}
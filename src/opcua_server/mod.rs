@@ -0,0 +1,455 @@
+/// OPC UA server exposing the IO model for the MES.
+///
+/// The address space has an `IO` folder under Objects with one folder per
+/// ADS1115 chip and per polled Modbus device holding its channels as
+/// AnalogItems (value, EngineeringUnits, quality as the status code), plus
+/// `Inputs` and `Outputs` folders for the GPIO pins. Writing an output node
+/// sends an `OutputCommand`, the node itself follows the pin once the
+/// sampling task has switched it.
+///
+/// Endpoints are offered without security and with Basic256Sha256
+/// sign & encrypt; the server certificate is created in `pki_dir` on first
+/// start. Clients log in anonymously (if enabled) or with one of the
+/// configured users. Anonymous sessions can only read unless
+/// `anonymous_writes` is set.
+use async_trait::async_trait;
+use opcua::server::address_space::{AccessLevel, AddressSpace, VariableBuilder};
+use opcua::server::authenticator::{AuthManager, DefaultAuthenticator, Password, UserToken};
+use opcua::server::diagnostics::NamespaceMetadata;
+use opcua::server::node_manager::memory::{simple_node_manager, SimpleNodeManager};
+use opcua::server::{ServerBuilder, ServerEndpoint, ServerUserToken, ANONYMOUS_USER_TOKEN_ID};
+use opcua::types::{
+    DataTypeId, DataValue, DateTime, EUInformation, Error as UaError, LocalizedText, NodeId,
+    ObjectId, StatusCode, UserTokenPolicy, VariableTypeId, Variant,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::history::Quality;
+use crate::modbus_master::ModbusDeviceConfig;
+use crate::shutdown::Shutdown;
+use crate::state::SharedState;
+use crate::{IoState, OutputCommand, ADC_CHANNELS, DIGITAL_INPUTS, SWITCHED_OUTPUTS};
+
+const NAMESPACE: &str = "urn:io_server:io";
+const ENDPOINT_PATH: &str = "/";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpcUaConfig {
+    /// Host name clients use to reach the server; it is also bound to and
+    /// put in the endpoint URLs, e.g. "pi1.lan".
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_application_name")]
+    pub application_name: String,
+    #[serde(default = "default_anonymous")]
+    pub anonymous: bool,
+    #[serde(default)]
+    pub anonymous_writes: bool,
+    #[serde(default)]
+    pub users: Vec<OpcUaUser>,
+    /// Server certificate, private key and trusted/rejected client certs.
+    #[serde(default = "default_pki_dir")]
+    pub pki_dir: PathBuf,
+    /// Accept any client certificate on the encrypted endpoint. Otherwise
+    /// new certs land in `<pki_dir>/rejected` until moved to `trusted`.
+    #[serde(default)]
+    pub trust_client_certs: bool,
+    /// How often the node values are refreshed from the IO state.
+    #[serde(default = "default_update_interval_ms")]
+    pub update_interval_ms: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpcUaUser {
+    pub username: String,
+    pub password: String,
+}
+
+fn default_port() -> u16 {
+    4840
+}
+
+fn default_application_name() -> String {
+    "io_server".to_string()
+}
+
+fn default_anonymous() -> bool {
+    true
+}
+
+fn default_pki_dir() -> PathBuf {
+    PathBuf::from("pki")
+}
+
+fn default_update_interval_ms() -> u64 {
+    500
+}
+
+impl OpcUaConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.update_interval_ms == 0 {
+            return Err("opcua.update_interval_ms must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The default authenticator, with writes taken away from anonymous users
+/// unless the config allows them.
+struct IoAuthenticator {
+    inner: DefaultAuthenticator,
+    anonymous_writes: bool,
+}
+
+#[async_trait]
+impl AuthManager for IoAuthenticator {
+    async fn authenticate_anonymous_token(&self, endpoint: &ServerEndpoint) -> Result<(), UaError> {
+        self.inner.authenticate_anonymous_token(endpoint).await
+    }
+
+    async fn authenticate_username_identity_token(
+        &self,
+        endpoint: &ServerEndpoint,
+        username: &str,
+        password: &Password,
+    ) -> Result<UserToken, UaError> {
+        let token = self
+            .inner
+            .authenticate_username_identity_token(endpoint, username, password)
+            .await?;
+        println!("OPC UA user {} logged in", username);
+        Ok(token)
+    }
+
+    fn effective_user_access_level(
+        &self,
+        token: &UserToken,
+        user_access_level: AccessLevel,
+        _node_id: &NodeId,
+    ) -> AccessLevel {
+        if token.is_anonymous() && !self.anonymous_writes {
+            user_access_level - AccessLevel::CURRENT_WRITE
+        } else {
+            user_access_level
+        }
+    }
+
+    fn user_token_policies(&self, endpoint: &ServerEndpoint) -> Vec<UserTokenPolicy> {
+        self.inner.user_token_policies(endpoint)
+    }
+}
+
+/// Node ids of everything the updater refreshes.
+struct Nodes {
+    channels: BTreeMap<String, NodeId>,
+    inputs: BTreeMap<String, NodeId>,
+    outputs: BTreeMap<u8, NodeId>,
+}
+
+/// Builds the address space from the current channel registry and starts
+/// the server and the task keeping the node values current. Both stop on
+/// `shutdown`; the returned handle is the server's.
+pub fn spawn(
+    config: OpcUaConfig,
+    devices: &[ModbusDeviceConfig],
+    shared_state: SharedState,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>, String> {
+    let mut users = BTreeMap::new();
+    let mut token_ids = Vec::new();
+    if config.anonymous {
+        token_ids.push(ANONYMOUS_USER_TOKEN_ID.to_string());
+    }
+    for user in config.users.iter() {
+        users.insert(
+            user.username.clone(),
+            ServerUserToken::user_pass(user.username.clone(), user.password.clone()),
+        );
+        token_ids.push(user.username.clone());
+    }
+    if token_ids.is_empty() {
+        return Err("OPC UA needs anonymous access or at least one user".to_string());
+    }
+
+    let mut builder = ServerBuilder::new()
+        .application_name(&config.application_name)
+        .application_uri(format!("urn:{}", config.application_name))
+        .product_uri("urn:io_server")
+        .host(&config.host)
+        .port(config.port)
+        .pki_dir(&config.pki_dir)
+        .certificate_path("own/cert.der")
+        .private_key_path("private/private.pem")
+        .create_sample_keypair(true)
+        .trust_client_certs(config.trust_client_certs)
+        .discovery_urls(vec![ENDPOINT_PATH.to_string()])
+        .add_endpoint("none", ServerEndpoint::new_none(ENDPOINT_PATH, &token_ids))
+        .add_endpoint(
+            "basic256sha256_sign_encrypt",
+            ServerEndpoint::new_basic256sha256_sign_encrypt(ENDPOINT_PATH, &token_ids),
+        )
+        .with_authenticator(Arc::new(IoAuthenticator {
+            inner: DefaultAuthenticator::new(users.clone()),
+            anonymous_writes: config.anonymous_writes,
+        }))
+        .with_node_manager(simple_node_manager(
+            NamespaceMetadata {
+                namespace_uri: NAMESPACE.to_string(),
+                ..Default::default()
+            },
+            "io",
+        ));
+    for (id, token) in users {
+        builder = builder.add_user_token(id, token);
+    }
+
+    let (server, handle) = builder.build()?;
+    let node_manager = handle
+        .node_managers()
+        .get_of_type::<SimpleNodeManager>()
+        .unwrap();
+    let ns = handle.get_namespace_index(NAMESPACE).unwrap();
+
+    let io_state = shared_state.snapshot();
+    let nodes = {
+        let mut address_space = node_manager.address_space().write();
+        build_address_space(&mut address_space, ns, devices, &io_state)
+    };
+
+    let output_sender = io_state.sneaky_sender.clone();
+    for (pin, node_id) in nodes.outputs.iter() {
+        let pin = *pin;
+        let output_sender = output_sender.clone();
+        node_manager
+            .inner()
+            .add_write_callback(node_id.clone(), move |value, _| {
+                let Some(Variant::Boolean(on)) = value.value else {
+                    return StatusCode::BadTypeMismatch;
+                };
+                println!("OPC UA client set pin {} to {}", pin, on);
+                match output_sender.try_send(OutputCommand::LedSet(pin as i32, on)) {
+                    Ok(()) => StatusCode::Good,
+                    Err(_) => StatusCode::BadResourceUnavailable,
+                }
+            });
+    }
+
+    let endpoint = format!("opc.tcp://{}:{}{}", config.host, config.port, ENDPOINT_PATH);
+    let server = tokio::spawn(async move {
+        println!("OPC UA server listening on {}", endpoint);
+        match server.run().await {
+            Ok(()) => println!("OPC UA server stopped"),
+            Err(e) => println!("OPC UA server stopped: {}", e),
+        }
+    });
+
+    let interval = Duration::from_millis(config.update_interval_ms);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.wait() => {
+                    // closes the client sessions and stops listening
+                    handle.cancel();
+                    return;
+                }
+            }
+            let io_state = shared_state.snapshot();
+            let values = values(&nodes, &io_state);
+            let updates = values
+                .iter()
+                .map(|(node_id, value)| (node_id, None, value.clone()));
+            if let Err(e) = node_manager.set_values(handle.subscriptions(), updates) {
+                println!("OPC UA update failed: {}", e);
+            }
+        }
+    });
+    Ok(server)
+}
+
+/// Folder each channel is filed under: its ADC chip, its Modbus device, or
+/// "Other" for anything registered elsewhere.
+fn channel_groups(
+    devices: &[ModbusDeviceConfig],
+    io_state: &IoState,
+) -> BTreeMap<String, Vec<String>> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for channel in io_state.channels.keys() {
+        let adc = ADC_CHANNELS.iter().find(|adc| adc.name == channel);
+        let device = devices.iter().find(|device| {
+            device
+                .registers
+                .iter()
+                .any(|register| &register.channel == channel)
+        });
+        let group = match (adc, device) {
+            (Some(adc), _) => format!("ADS1115_0x{:02x}", adc.address),
+            (None, Some(device)) => device.name.clone(),
+            (None, None) => "Other".to_string(),
+        };
+        groups.entry(group).or_default().push(channel.clone());
+    }
+    groups
+}
+
+fn build_address_space(
+    address_space: &mut AddressSpace,
+    ns: u16,
+    devices: &[ModbusDeviceConfig],
+    io_state: &IoState,
+) -> Nodes {
+    let root = NodeId::new(ns, "IO");
+    address_space.add_folder(&root, "IO", "IO", &ObjectId::ObjectsFolder.into());
+
+    let mut channels = BTreeMap::new();
+    for (group, names) in channel_groups(devices, io_state) {
+        let folder = NodeId::new(ns, format!("IO/{group}"));
+        address_space.add_folder(&folder, group.as_str(), group.as_str(), &root);
+        for name in names {
+            let node_id = NodeId::new(ns, format!("IO/{group}/{name}"));
+            VariableBuilder::new(&node_id, name.as_str(), name.as_str())
+                .data_type(DataTypeId::Float)
+                .value(0.0f32)
+                .has_type_definition(VariableTypeId::AnalogItemType)
+                .organized_by(folder.clone())
+                .insert(address_space);
+            if let Some(units) = io_state.units.get(&name) {
+                VariableBuilder::new(
+                    &NodeId::new(ns, format!("IO/{group}/{name}/EngineeringUnits")),
+                    "EngineeringUnits",
+                    "EngineeringUnits",
+                )
+                .data_type(DataTypeId::EUInformation)
+                .value(engineering_units(units))
+                .has_type_definition(VariableTypeId::PropertyType)
+                .property_of(node_id.clone())
+                .insert(address_space);
+            }
+            channels.insert(name, node_id);
+        }
+    }
+
+    let folder = NodeId::new(ns, "IO/Inputs");
+    address_space.add_folder(&folder, "Inputs", "Inputs", &root);
+    let mut inputs = BTreeMap::new();
+    for (name, pin) in DIGITAL_INPUTS {
+        let node_id = NodeId::new(ns, format!("IO/Inputs/{name}"));
+        VariableBuilder::new(&node_id, name, name)
+            .description(format!("GPIO {pin}"))
+            .data_type(DataTypeId::Boolean)
+            .value(false)
+            .organized_by(folder.clone())
+            .insert(address_space);
+        inputs.insert(name.to_string(), node_id);
+    }
+
+    let folder = NodeId::new(ns, "IO/Outputs");
+    address_space.add_folder(&folder, "Outputs", "Outputs", &root);
+    let mut outputs = BTreeMap::new();
+    for pin in SWITCHED_OUTPUTS {
+        let name = format!("output_{pin}");
+        let node_id = NodeId::new(ns, format!("IO/Outputs/{name}"));
+        VariableBuilder::new(&node_id, name.as_str(), name.as_str())
+            .description(format!("GPIO {pin}"))
+            .data_type(DataTypeId::Boolean)
+            .value(false)
+            .writable()
+            .organized_by(folder.clone())
+            .insert(address_space);
+        outputs.insert(pin, node_id);
+    }
+
+    Nodes {
+        channels,
+        inputs,
+        outputs,
+    }
+}
+
+fn values(nodes: &Nodes, io_state: &IoState) -> Vec<(NodeId, DataValue)> {
+    let now = DateTime::now();
+    let data_value = |value: Variant, status: StatusCode| DataValue {
+        value: Some(value),
+        status: Some(status),
+        source_timestamp: Some(now),
+        server_timestamp: Some(now),
+        ..Default::default()
+    };
+
+    let mut values = Vec::new();
+    for (name, node_id) in nodes.channels.iter() {
+        let value = io_state
+            .channels
+            .get(name)
+            .map_or(0.0, |value| value.to_f32());
+        let quality = io_state.quality.get(name).copied().unwrap_or_default();
+        values.push((node_id.clone(), data_value(value.into(), status(quality))));
+    }
+    for (name, node_id) in nodes.inputs.iter() {
+        let status = match io_state.inputs.get(name) {
+            Some(_) => StatusCode::Good,
+            None => StatusCode::BadWaitingForInitialData,
+        };
+        let value = io_state.inputs.get(name).copied().unwrap_or(false);
+        values.push((node_id.clone(), data_value(value.into(), status)));
+    }
+    for (pin, node_id) in nodes.outputs.iter() {
+        let status = match io_state.outputs.get(pin) {
+            Some(_) => StatusCode::Good,
+            None => StatusCode::BadWaitingForInitialData,
+        };
+        let value = io_state.outputs.get(pin).copied().unwrap_or(false);
+        values.push((node_id.clone(), data_value(value.into(), status)));
+    }
+    values
+}
+
+fn status(quality: Quality) -> StatusCode {
+    match quality {
+        Quality::Good => StatusCode::Good,
+        Quality::Uncertain => StatusCode::Uncertain,
+        Quality::Bad => StatusCode::Bad,
+    }
+}
+
+/// EUInformation for the units string, with the UNECE code for the common
+/// ones so clients can convert.
+fn engineering_units(units: &str) -> EUInformation {
+    let code = match units {
+        "V" => "VLT",
+        "mV" => "2Z",
+        "A" => "AMP",
+        "mA" => "4K",
+        "W" => "WTT",
+        "kW" => "KWT",
+        "Wh" => "WHR",
+        "kWh" => "KWH",
+        "Hz" => "HTZ",
+        "°C" => "CEL",
+        "bar" => "BAR",
+        "Pa" => "PAL",
+        "kPa" => "KPA",
+        _ => "",
+    };
+    // OPC UA packs the up to three character code into an Int32
+    let unit_id = if code.is_empty() {
+        -1
+    } else {
+        code.bytes().fold(0i32, |id, byte| (id << 8) | byte as i32)
+    };
+    EUInformation {
+        namespace_uri: "http://www.opcfoundation.org/UA/units/un/cefact".into(),
+        unit_id,
+        display_name: LocalizedText::from(units),
+        description: LocalizedText::from(units),
+    }
+}
@@ -0,0 +1,142 @@
+/// Salted password hashes for the users listed in the config.
+///
+/// A hash is stored as `pbkdf2-sha256$<iterations>$<salt hex>$<hash hex>`
+/// and made with `io_server hash-password`. Checking a password derives it
+/// again with the stored salt and iterations and compares in constant time,
+/// so neither the config file nor the time a login takes gives it away.
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{self, BufRead};
+use std::num::NonZeroU32;
+
+const SCHEME: &str = "pbkdf2-sha256";
+const ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// A new hash of `password` with a random salt.
+pub fn hash(password: &str) -> io::Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| io::Error::other("no random numbers for the salt"))?;
+    let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+    let mut derived = [0u8; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut derived,
+    );
+    Ok(format!(
+        "{SCHEME}${iterations}${}${}",
+        to_hex(&salt),
+        to_hex(&derived)
+    ))
+}
+
+/// Whether `password` is the one `stored` was made from. A malformed hash
+/// matches nothing.
+pub fn verify(stored: &str, password: &str) -> bool {
+    let Some((iterations, salt, derived)) = parse(stored) else {
+        return false;
+    };
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &derived,
+    )
+    .is_ok()
+}
+
+/// Checks a configured hash can be used, for config validation.
+pub fn validate(stored: &str) -> Result<(), String> {
+    match parse(stored) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "not a {SCHEME} hash, make one with `io_server hash-password`"
+        )),
+    }
+}
+
+/// Reads a password from the first line of stdin and prints its hash, for
+/// pasting into the config.
+pub fn run() -> io::Result<()> {
+    eprintln!("Password:");
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the password is empty",
+        ));
+    }
+    println!("{}", hash(password)?);
+    Ok(())
+}
+
+fn parse(stored: &str) -> Option<(NonZeroU32, Vec<u8>, Vec<u8>)> {
+    let mut parts = stored.split('$');
+    if parts.next()? != SCHEME {
+        return None;
+    }
+    let iterations = NonZeroU32::new(parts.next()?.parse().ok()?)?;
+    let salt = from_hex(parts.next()?)?;
+    let derived = from_hex(parts.next()?)?;
+    if parts.next().is_some() || salt.is_empty() || derived.len() != HASH_LEN {
+        return None;
+    }
+    Some((iterations, salt, derived))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_only_the_hashed_password() {
+        let stored = hash("change-me").unwrap();
+        assert!(validate(&stored).is_ok());
+        assert!(verify(&stored, "change-me"));
+        assert!(!verify(&stored, "change-me "));
+        assert!(!verify(&stored, ""));
+    }
+
+    #[test]
+    fn salts_every_hash() {
+        assert_ne!(hash("change-me").unwrap(), hash("change-me").unwrap());
+    }
+
+    #[test]
+    fn malformed_hashes_match_nothing() {
+        for stored in [
+            "",
+            "change-me",
+            "pbkdf2-sha256$0$00$00",
+            "pbkdf2-sha256$1000$zz$00",
+            "pbkdf2-sha256$1000$0011$0011",
+            "md5$1000$0011$00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff",
+        ] {
+            assert!(validate(stored).is_err(), "{stored}");
+            assert!(!verify(stored, "change-me"), "{stored}");
+        }
+    }
+}
@@ -1,16 +1,22 @@
 /// Provides management of communicating between the back end and the front end.
 use axum::extract::ws::Message;
 use axum::extract::ws::WebSocket;
+use axum::extract::ws::{close_code, CloseFrame};
 use futures::stream::SplitSink;
 use futures::stream::SplitStream;
 use futures::SinkExt;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::mpsc::{self, Receiver, Sender};
 
+use crate::alarm::{AlarmEngine, AlarmError};
+use crate::calibration::{Calibrator, Fit};
+use crate::failsafe::{self, Outputs};
 use crate::OutputCommand;
 
-pub struct TextDisplay {}
+// Replies queued for one client before further ones are dropped.
+const REPLY_QUEUE: usize = 16;
 
 /// Send from the server to the client.
 #[derive(Serialize, Deserialize)]
@@ -24,31 +30,144 @@ pub struct LEDToggleUpdate {
     id: String,
     message: String,
 }
-pub enum ServerCommands {}
 
-pub enum ClientCommands {}
-
-pub struct InnerRhino {}
+/// Just the tag of a command that didn't parse, so it can be logged without
+/// the rest of the message (which may hold a password).
+#[derive(Deserialize)]
+struct CommandTag {
+    command: String,
+}
 
+/// Sent from the client to the server, tagged by `command`. The replies come
+/// back as a `TextUpdate` with id "calibration" or "alarm_command" and the
+/// preview or alarm (or an `error`) as JSON text.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ClientCommands {
+    CalibrationCapture {
+        channel: String,
+        reference: f32,
+        #[serde(flatten)]
+        fit: Fit,
+    },
+    CalibrationPreview {
+        channel: String,
+        #[serde(flatten)]
+        fit: Fit,
+    },
+    CalibrationDiscard {
+        channel: String,
+    },
+    CalibrationCommit {
+        channel: String,
+        user: String,
+        units: Option<String>,
+        #[serde(flatten)]
+        fit: Fit,
+    },
+    AlarmAck {
+        id: String,
+        user: String,
+        comment: Option<String>,
+    },
+    /// Only users listed under `[alarm_shelving]` may shelve.
+    AlarmShelve {
+        id: String,
+        user: String,
+        password: String,
+        minutes: u64,
+        comment: Option<String>,
+    },
+    AlarmUnshelve {
+        id: String,
+        user: String,
+        password: String,
+    },
+    AlarmComment {
+        id: String,
+        user: String,
+        text: String,
+    },
+    /// Keeps the outputs out of their safe states when
+    /// `fail_safe.heartbeat_timeout_ms` is set. Not replied to.
+    Heartbeat,
+}
 // has an maintance loop for shuttling data back and forth.
 pub struct Rhino {
     sender: SplitSink<WebSocket, Message>,
+    replies: Receiver<TextUpdate>,
+}
+
+struct RhinoMaintainer {
+    calibrator: Arc<Mutex<Calibrator>>,
+    alarms: Arc<Mutex<AlarmEngine>>,
+    outputs: Arc<Mutex<Outputs>>,
+    replies: Sender<TextUpdate>,
 }
 
-struct RhinoMaintainer {}
+impl ClientCommands {
+    /// For the log; `Debug` would print passwords.
+    fn name(&self) -> &'static str {
+        match self {
+            ClientCommands::CalibrationCapture { .. } => "calibration_capture",
+            ClientCommands::CalibrationPreview { .. } => "calibration_preview",
+            ClientCommands::CalibrationDiscard { .. } => "calibration_discard",
+            ClientCommands::CalibrationCommit { .. } => "calibration_commit",
+            ClientCommands::AlarmAck { .. } => "alarm_ack",
+            ClientCommands::AlarmShelve { .. } => "alarm_shelve",
+            ClientCommands::AlarmUnshelve { .. } => "alarm_unshelve",
+            ClientCommands::AlarmComment { .. } => "alarm_comment",
+            ClientCommands::Heartbeat => "heartbeat",
+        }
+    }
+}
 
 impl RhinoMaintainer {
-    async fn maintenance(mut receiver: SplitStream<WebSocket>, output_sender: Sender<OutputCommand>) {
+    async fn maintenance(
+        self,
+        mut receiver: SplitStream<WebSocket>,
+        output_sender: Sender<OutputCommand>,
+    ) {
         // read commands from the client.
         loop {
             let msg = receiver.next().await;
             match msg {
                 Some(Ok(Message::Text(msg))) => {
-                    let led_toggle: LEDToggleUpdate = serde_json::from_str(&msg).unwrap();
+                    if let Ok(command) = serde_json::from_str::<ClientCommands>(&msg) {
+                        if let ClientCommands::Heartbeat = command {
+                            failsafe::lock(&self.outputs).heartbeat();
+                            continue;
+                        }
+                        println!("Got a command from a client: {}", command.name());
+                        self.handle_command(command);
+                        continue;
+                    }
+                    if let Ok(tag) = serde_json::from_str::<CommandTag>(&msg) {
+                        println!("Ignoring malformed {} command from a client", tag.command);
+                        continue;
+                    }
+                    let led_toggle: LEDToggleUpdate = match serde_json::from_str(&msg) {
+                        Ok(led_toggle) => led_toggle,
+                        Err(e) => {
+                            println!("Ignoring client message: {}", e);
+                            continue;
+                        }
+                    };
                     println!("Got a message from a client: {:?}", led_toggle);
-                    output_sender.send(OutputCommand::LedToggle(led_toggle.id.parse().unwrap())).await;
+                    let Ok(pin) = led_toggle.id.parse() else {
+                        println!("Ignoring toggle of unknown pin {}", led_toggle.id);
+                        continue;
+                    };
+                    if output_sender
+                        .send(OutputCommand::LedToggle(pin))
+                        .await
+                        .is_err()
+                    {
+                        println!("output task is gone, dropping client commands");
+                        break;
+                    }
                 }
-                Some(_) => { 
+                Some(_) => {
                     println!("Iunno got somethign weird");
                 }
                 None => {
@@ -58,17 +177,134 @@ impl RhinoMaintainer {
             }
         }
     }
+
+    fn handle_command(&self, command: ClientCommands) {
+        let command = match self.handle_alarm_command(command) {
+            Ok(reply) => {
+                self.reply("alarm_command", reply);
+                return;
+            }
+            Err(command) => command,
+        };
+        let mut calibrator = self
+            .calibrator
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (channel, result) = match command {
+            ClientCommands::CalibrationCapture {
+                channel,
+                reference,
+                fit,
+            } => {
+                let result = calibrator.capture(&channel, reference, fit);
+                (channel, result)
+            }
+            ClientCommands::CalibrationPreview { channel, fit } => {
+                let result = calibrator.preview(&channel, fit);
+                (channel, result)
+            }
+            ClientCommands::CalibrationDiscard { channel } => {
+                let result = calibrator
+                    .discard(&channel)
+                    .and_then(|_| calibrator.preview(&channel, Fit::default()));
+                (channel, result)
+            }
+            ClientCommands::CalibrationCommit {
+                channel,
+                user,
+                units,
+                fit,
+            } => {
+                let result = calibrator.commit(&channel, fit, &user, units);
+                (channel, result)
+            }
+            _ => unreachable!("alarm commands are handled above"),
+        };
+        let text = match result {
+            Ok(preview) => serde_json::to_string(&preview).unwrap(),
+            Err(e) => serde_json::json!({ "channel": channel, "error": e.to_string() }).to_string(),
+        };
+        self.reply("calibration", text);
+    }
+
+    /// Runs an alarm command and returns the reply text, or gives the command
+    /// back if it is not about alarms.
+    fn handle_alarm_command(&self, command: ClientCommands) -> Result<String, ClientCommands> {
+        let mut alarms = self.alarms.lock().unwrap_or_else(PoisonError::into_inner);
+        let (id, result): (String, Result<_, AlarmError>) = match command {
+            ClientCommands::AlarmAck { id, user, comment } => {
+                let result = alarms.acknowledge(&id, &user, comment);
+                (id, result)
+            }
+            ClientCommands::AlarmShelve {
+                id,
+                user,
+                password,
+                minutes,
+                comment,
+            } => {
+                let result = alarms.shelve(&id, &user, &password, minutes, comment);
+                (id, result)
+            }
+            ClientCommands::AlarmUnshelve { id, user, password } => {
+                let result = alarms.unshelve(&id, &user, &password);
+                (id, result)
+            }
+            ClientCommands::AlarmComment { id, user, text } => {
+                let result = alarms.comment(&id, &user, &text);
+                (id, result)
+            }
+            command => return Err(command),
+        };
+        Ok(match result {
+            Ok(alarm) => serde_json::to_string(&alarm).unwrap(),
+            Err(e) => serde_json::json!({ "id": id, "error": e.to_string() }).to_string(),
+        })
+    }
+
+    fn reply(&self, id: &str, text: String) {
+        let reply = TextUpdate {
+            id: id.to_string(),
+            text,
+        };
+        if self.replies.try_send(reply).is_err() {
+            println!("Dropping {} reply, client is not reading", id);
+        }
+    }
 }
 
 impl Rhino {
-    pub fn new(socket: WebSocket, output_sender: Sender<OutputCommand>) -> Self {
+    pub fn new(
+        socket: WebSocket,
+        output_sender: Sender<OutputCommand>,
+        calibrator: Arc<Mutex<Calibrator>>,
+        alarms: Arc<Mutex<AlarmEngine>>,
+        outputs: Arc<Mutex<Outputs>>,
+    ) -> Self {
         let (sender, receiver) = socket.split();
+        let (replies_tx, replies) = mpsc::channel(REPLY_QUEUE);
 
-        tokio::spawn(RhinoMaintainer::maintenance(receiver, output_sender));
-        Self { sender }
+        let maintainer = RhinoMaintainer {
+            calibrator,
+            alarms,
+            outputs,
+            replies: replies_tx,
+        };
+        tokio::spawn(maintainer.maintenance(receiver, output_sender));
+        Self { sender, replies }
     }
 
-    pub async fn send_text_update(&mut self, id: &str, text: String) {
+    /// Sends the replies to the client's commands queued since the last call.
+    pub async fn send_replies(&mut self) -> Result<(), axum::Error> {
+        while let Ok(reply) = self.replies.try_recv() {
+            self.sender
+                .send(Message::Text(serde_json::to_string(&reply).unwrap()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_text_update(&mut self, id: &str, text: String) -> Result<(), axum::Error> {
         // Set the value of "some widget or something that is displaying aDC info"
         // self.state.get_widget(id).set_value(value);
         let text_update = TextUpdate {
@@ -77,6 +313,18 @@ impl Rhino {
         };
         self.sender
             .send(Message::Text(serde_json::to_string(&text_update).unwrap()))
-            .await;
+            .await
+    }
+
+    /// Tells the client the server is going away and closes the socket.
+    pub async fn close(&mut self) -> Result<(), axum::Error> {
+        self.send_text_update("server", "shutting down".to_string())
+            .await?;
+        self.sender
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: "server shutting down".into(),
+            })))
+            .await
     }
 }
@@ -0,0 +1,142 @@
+/// Scheduling of the ADC reads.
+///
+/// Every ADC channel is read on its own period, `period_ms` in
+/// `[channels.<name>]`. The channel whose read is due soonest goes next, so a
+/// fast pressure channel is read many times between two reads of a slow
+/// temperature channel. The periods have to fit the I2C bus: the estimated
+/// bus time of every channel's reads may use at most `I2C_BUDGET` of it,
+/// which is checked when the config is loaded. A read that ends after the
+/// channel's next read was due is an overrun; the missed reads are skipped
+/// rather than caught up on, counted, and reported every little while.
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::config::ChannelConfig;
+use crate::{AdcSampling, ADC_CHANNELS};
+
+pub const DEFAULT_PERIOD_MS: u64 = 500;
+// Share of the bus the reads may be planned to take, the rest is slack for
+// retries and timer jitter.
+pub const I2C_BUDGET: f64 = 0.8;
+// Weight of the newest interval in the effective rate.
+const RATE_SMOOTHING: f64 = 0.1;
+const OVERRUN_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Estimated share of the I2C bus taken by reading the ADC channels on
+/// their periods.
+pub fn i2c_load(channels: &BTreeMap<String, ChannelConfig>) -> f64 {
+    ADC_CHANNELS
+        .iter()
+        .map(|channel| {
+            let sampling = AdcSampling::new(channels.get(channel.name));
+            sampling.bus_time().as_secs_f64() / sampling.period.as_secs_f64()
+        })
+        .sum()
+}
+
+/// How one channel is being sampled, for the API and metrics.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChannelStats {
+    pub period_ms: u64,
+    /// Estimated bus time of one read.
+    pub bus_ms: f64,
+    /// Reads per second actually achieved, averaged over the last few.
+    pub effective_hz: f64,
+    /// How long the last read took.
+    pub read_ms: f64,
+    /// Reads skipped because an earlier one ran late.
+    pub overruns: u64,
+}
+
+struct Slot {
+    period: Duration,
+    due: Instant,
+    last_read: Option<Instant>,
+    stats: ChannelStats,
+    unreported: u64,
+}
+
+/// Decides which ADC channel to read next, indexed like `ADC_CHANNELS`.
+pub struct Scheduler {
+    slots: Vec<Slot>,
+    last_report: Instant,
+}
+
+impl Scheduler {
+    pub fn new(samplings: &[AdcSampling]) -> Self {
+        let now = Instant::now();
+        Self {
+            slots: samplings
+                .iter()
+                .map(|sampling| Slot {
+                    period: sampling.period,
+                    due: now,
+                    last_read: None,
+                    stats: ChannelStats {
+                        period_ms: sampling.period.as_millis() as u64,
+                        bus_ms: sampling.bus_time().as_secs_f64() * 1000.0,
+                        ..ChannelStats::default()
+                    },
+                    unreported: 0,
+                })
+                .collect(),
+            last_report: now,
+        }
+    }
+
+    /// The channel due soonest and when it is due. Ties go to the channel
+    /// listed first.
+    pub fn next(&self) -> (usize, Instant) {
+        self.slots
+            .iter()
+            .enumerate()
+            .map(|(index, slot)| (index, slot.due))
+            .min_by_key(|(_, due)| *due)
+            .unwrap()
+    }
+
+    /// Books a read of channel `index` that ran from `started` to
+    /// `finished` and schedules its next one.
+    pub fn done(&mut self, index: usize, started: Instant, finished: Instant) {
+        let slot = &mut self.slots[index];
+        slot.due += slot.period;
+        while slot.due <= finished {
+            slot.due += slot.period;
+            slot.stats.overruns += 1;
+            slot.unreported += 1;
+        }
+        if let Some(last_read) = slot.last_read {
+            let hz = 1.0 / started.duration_since(last_read).as_secs_f64().max(1e-6);
+            slot.stats.effective_hz = if slot.stats.effective_hz == 0.0 {
+                hz
+            } else {
+                slot.stats.effective_hz + RATE_SMOOTHING * (hz - slot.stats.effective_hz)
+            };
+        }
+        slot.last_read = Some(started);
+        slot.stats.read_ms = finished.duration_since(started).as_secs_f64() * 1000.0;
+    }
+
+    pub fn stats(&self, index: usize) -> ChannelStats {
+        self.slots[index].stats.clone()
+    }
+
+    /// Prints the overruns since the last report, at most every
+    /// `OVERRUN_REPORT_INTERVAL`.
+    pub fn report_overruns(&mut self) {
+        if self.last_report.elapsed() < OVERRUN_REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+        for (channel, slot) in ADC_CHANNELS.iter().zip(self.slots.iter_mut()) {
+            if slot.unreported > 0 {
+                println!(
+                    "Sampling overrun: {} skipped {} reads (period {} ms, last read {:.1} ms)",
+                    channel.name, slot.unreported, slot.stats.period_ms, slot.stats.read_ms
+                );
+                slot.unreported = 0;
+            }
+        }
+    }
+}
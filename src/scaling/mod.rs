@@ -0,0 +1,210 @@
+/// Conversion of raw ADC volts into engineering units.
+///
+/// Each ADC channel can have one scaling in its `[channels.<name>]` config
+/// section. The scaled value is what gets published and recorded; the raw
+/// volts stay available next to it in the IO state.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::history::Quality;
+
+// NAMUR NE 43: loop currents outside this band mean a sensor or wiring fault.
+const LOOP_FAULT_LOW_MA: f32 = 3.6;
+const LOOP_FAULT_HIGH_MA: f32 = 21.0;
+
+/// Scalings in effect, keyed by channel. Shared between the sampling loop and
+/// the calibration workflow, which replaces entries on commit.
+pub type Scalings = Arc<Mutex<BTreeMap<String, Scaling>>>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Scaling {
+    /// `value = volts * gain + offset`
+    Linear {
+        #[serde(default = "default_gain")]
+        gain: f32,
+        #[serde(default)]
+        offset: f32,
+    },
+    /// Straight lines between `[volts, value]` points, sorted by volts.
+    /// Outside the table the first or last segment is extended.
+    Piecewise { points: Vec<[f32; 2]> },
+    /// `value = c0 + c1 * volts + c2 * volts^2 + ...`
+    Polynomial { coefficients: Vec<f32> },
+    /// 4-20 mA transmitter read across a shunt resistor, mapped linearly onto
+    /// `low ..= high`. Currents outside 3.6-21 mA are flagged bad.
+    CurrentLoop {
+        shunt_ohms: f32,
+        low: f32,
+        high: f32,
+    },
+}
+
+fn default_gain() -> f32 {
+    1.0
+}
+
+impl Scaling {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Scaling::Linear { .. } => Ok(()),
+            Scaling::Piecewise { points } => {
+                if points.len() < 2 {
+                    return Err("piecewise scaling needs at least two points".to_string());
+                }
+                if points.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+                    return Err(
+                        "piecewise points must be sorted by strictly rising volts".to_string()
+                    );
+                }
+                Ok(())
+            }
+            Scaling::Polynomial { coefficients } => {
+                if coefficients.is_empty() {
+                    return Err("polynomial scaling needs at least one coefficient".to_string());
+                }
+                Ok(())
+            }
+            Scaling::CurrentLoop { shunt_ohms, .. } => {
+                if *shunt_ohms <= 0.0 {
+                    return Err("current loop shunt_ohms must be positive".to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Engineering value for `volts`, and whether the reading is plausible.
+    pub fn apply(&self, volts: f32) -> (f32, Quality) {
+        match self {
+            Scaling::Linear { gain, offset } => (volts * gain + offset, Quality::Good),
+            Scaling::Piecewise { points } => {
+                // segment containing volts, or the nearest end segment
+                let segment = points
+                    .windows(2)
+                    .position(|pair| volts < pair[1][0])
+                    .unwrap_or(points.len() - 2);
+                let [x0, y0] = points[segment];
+                let [x1, y1] = points[segment + 1];
+                (y0 + (volts - x0) * (y1 - y0) / (x1 - x0), Quality::Good)
+            }
+            Scaling::Polynomial { coefficients } => {
+                // Horner's method, highest order first
+                let value = coefficients
+                    .iter()
+                    .rev()
+                    .fold(0.0, |acc, coefficient| acc * volts + coefficient);
+                (value, Quality::Good)
+            }
+            Scaling::CurrentLoop {
+                shunt_ohms,
+                low,
+                high,
+            } => {
+                let milliamps = volts / shunt_ohms * 1000.0;
+                let value = low + (milliamps - 4.0) / 16.0 * (high - low);
+                let quality = if (LOOP_FAULT_LOW_MA..=LOOP_FAULT_HIGH_MA).contains(&milliamps) {
+                    Quality::Good
+                } else {
+                    Quality::Bad
+                };
+                (value, quality)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_scales(scaling: &Scaling, volts: f32, expected: f32, quality: Quality) {
+        let (value, actual) = scaling.apply(volts);
+        assert!(
+            (value - expected).abs() < 1e-4,
+            "{volts} V gave {value}, not {expected}"
+        );
+        assert_eq!(actual, quality, "{volts} V");
+    }
+
+    #[test]
+    fn linear() {
+        let scaling = Scaling::Linear {
+            gain: 100.0,
+            offset: -50.0,
+        };
+        assert_scales(&scaling, 0.0, -50.0, Quality::Good);
+        assert_scales(&scaling, 1.5, 100.0, Quality::Good);
+    }
+
+    #[test]
+    fn piecewise_interpolates_and_extends_the_end_segments() {
+        let scaling = Scaling::Piecewise {
+            points: vec![[0.0, 0.0], [1.0, 20.0], [3.0, 60.0]],
+        };
+        scaling.validate().unwrap();
+        for (volts, expected) in [
+            (0.5, 10.0),
+            (1.0, 20.0),
+            (2.0, 40.0),
+            (3.0, 60.0),
+            (-1.0, -20.0),
+            (4.0, 80.0),
+        ] {
+            assert_scales(&scaling, volts, expected, Quality::Good);
+        }
+    }
+
+    #[test]
+    fn polynomial() {
+        let scaling = Scaling::Polynomial {
+            coefficients: vec![1.0, -2.0, 0.5],
+        };
+        assert_scales(&scaling, 0.0, 1.0, Quality::Good);
+        assert_scales(&scaling, 2.0, -1.0, Quality::Good);
+        assert_scales(&scaling, 4.0, 1.0, Quality::Good);
+    }
+
+    #[test]
+    fn current_loop_flags_namur_faults() {
+        // 4-20 mA across 250 ohms is 1-5 V
+        let scaling = Scaling::CurrentLoop {
+            shunt_ohms: 250.0,
+            low: 0.0,
+            high: 10.0,
+        };
+        assert_scales(&scaling, 1.0, 0.0, Quality::Good);
+        assert_scales(&scaling, 3.0, 5.0, Quality::Good);
+        assert_scales(&scaling, 5.0, 10.0, Quality::Good);
+        // just inside 3.6 and 21 mA
+        assert_scales(&scaling, 0.925, -0.1875, Quality::Good);
+        assert_scales(&scaling, 5.2, 10.5, Quality::Good);
+        // a broken wire reads 0 mA, a short runs over 21 mA
+        assert_scales(&scaling, 0.0, -2.5, Quality::Bad);
+        assert_scales(&scaling, 0.875, -0.3125, Quality::Bad);
+        assert_scales(&scaling, 5.275, 10.6875, Quality::Bad);
+    }
+
+    #[test]
+    fn validate() {
+        let unsorted = Scaling::Piecewise {
+            points: vec![[1.0, 0.0], [1.0, 1.0]],
+        };
+        assert!(unsorted.validate().is_err());
+        let single = Scaling::Piecewise {
+            points: vec![[1.0, 0.0]],
+        };
+        assert!(single.validate().is_err());
+        let empty = Scaling::Polynomial {
+            coefficients: Vec::new(),
+        };
+        assert!(empty.validate().is_err());
+        let no_shunt = Scaling::CurrentLoop {
+            shunt_ohms: 0.0,
+            low: 0.0,
+            high: 1.0,
+        };
+        assert!(no_shunt.validate().is_err());
+    }
+}
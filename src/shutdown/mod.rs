@@ -0,0 +1,50 @@
+/// Graceful shutdown on SIGTERM or SIGINT.
+///
+/// The signal is passed on to every task that has something to wrap up: the
+/// web server stops taking connections and lets the requests in flight
+/// finish, websocket clients are told and closed, the sampling loop stops
+/// after the read it is in and the Influx writer sends, or buffers, its last
+/// batch, the MQTT bridge says goodbye to the broker and the OPC UA server
+/// closes its sessions. `main` waits for each of them in turn, holds the
+/// outputs safe, flushes the history and disarms the watchdog before exiting.
+use std::io;
+use tokio::signal::unix::{self, SignalKind};
+use tokio::sync::watch;
+
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self { sender }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Returns once the shutdown has been triggered, straight away if it
+    /// already was.
+    pub async fn wait(&self) {
+        let mut receiver = self.sender.subscribe();
+        // only fails once the sender is gone, and self holds on to it
+        let _ = receiver.wait_for(|stopping| *stopping).await;
+    }
+}
+
+/// Waits for SIGINT or SIGTERM and returns which one came. Fails if the
+/// handlers cannot be installed.
+pub async fn signal() -> io::Result<&'static str> {
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|()| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
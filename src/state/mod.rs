@@ -0,0 +1,45 @@
+/// The live IO state, published as immutable snapshots.
+///
+/// Readers take the latest snapshot, an `Arc<IoState>` that no one changes
+/// after it is published, and can keep it as long as they like without
+/// holding anything up. Writers copy the latest snapshot, change the copy and
+/// publish it with the next sequence number; writers take turns, so no
+/// change is lost. The copy shares every map it does not change with the
+/// snapshot it came from, and `Arc::make_mut` copies only the ones it does.
+/// A writer that panics leaves the published snapshot as it was, and there
+/// is no lock left poisoned for the other tasks to trip on.
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::IoState;
+
+#[derive(Clone)]
+pub struct SharedState {
+    sender: watch::Sender<Arc<IoState>>,
+}
+
+impl SharedState {
+    pub fn new(io_state: IoState) -> Self {
+        let (sender, _) = watch::channel(Arc::new(io_state));
+        Self { sender }
+    }
+
+    /// The latest snapshot.
+    pub fn snapshot(&self) -> Arc<IoState> {
+        self.sender.borrow().clone()
+    }
+
+    /// Publishes a copy of the latest snapshot with `change` applied. The copy
+    /// is shallow; `change` goes through `Arc::make_mut` for the maps it
+    /// writes.
+    pub fn update<R>(&self, change: impl FnOnce(&mut IoState) -> R) -> R {
+        let mut result = None;
+        self.sender.send_modify(|current| {
+            let mut next = IoState::clone(current);
+            result = Some(change(&mut next));
+            next.seq = current.seq + 1;
+            *current = Arc::new(next);
+        });
+        result.unwrap()
+    }
+}
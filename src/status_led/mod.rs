@@ -0,0 +1,115 @@
+/// Status LED on the output pin picked at startup.
+///
+/// The LED has its own timer, so it keeps the same rhythm however long the
+/// ADC reads take. It blinks slowly while all is well, fast while a channel
+/// has a bad reading and stays on while an alarm is raised. Shelved alarms do
+/// not count. The pin is one of the fail-safe outputs: while they are held it
+/// sits at its safe level (off unless `[[fail_safe.outputs]]` says otherwise)
+/// and stops blinking. Its level is not published with the other outputs.
+use rppal::gpio::OutputPin;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::alarm::AlarmEngine;
+use crate::failsafe::{self, Outputs};
+use crate::history::Quality;
+use crate::state::SharedState;
+
+// Faster than this and a blink is hard to tell from steady light.
+const MIN_BLINK_MS: u64 = 20;
+
+/// `[status_led]`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusLedConfig {
+    /// Time on, and then off, while all is well.
+    pub blink_ms: u64,
+    /// Time on, and then off, on a fault.
+    pub fault_blink_ms: u64,
+}
+
+impl Default for StatusLedConfig {
+    fn default() -> Self {
+        Self {
+            blink_ms: 500,
+            fault_blink_ms: 100,
+        }
+    }
+}
+
+impl StatusLedConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.blink_ms < MIN_BLINK_MS || self.fault_blink_ms < MIN_BLINK_MS {
+            return Err(format!(
+                "status_led: blink times must be at least {MIN_BLINK_MS} ms"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Pattern {
+    Normal,
+    Fault,
+    Alarm,
+}
+
+/// Hands the LED on `pin` to the fail-safe outputs and drives it until the
+/// process exits.
+pub fn spawn(
+    pin: u8,
+    led: OutputPin,
+    config: StatusLedConfig,
+    shared_state: SharedState,
+    alarms: Arc<Mutex<AlarmEngine>>,
+    outputs: Arc<Mutex<Outputs>>,
+) {
+    failsafe::lock(&outputs).add_status_led(pin, led);
+    tokio::spawn(failsafe::io_task(async move {
+        let mut last_pattern = Pattern::Normal;
+        let mut lit = false;
+        loop {
+            let pattern = if alarms
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .any_raised()
+            {
+                Pattern::Alarm
+            } else if has_bad_channel(&shared_state) {
+                Pattern::Fault
+            } else {
+                Pattern::Normal
+            };
+            if pattern != last_pattern {
+                println!("Status LED: {:?}", pattern);
+                last_pattern = pattern;
+            }
+            let step = match pattern {
+                Pattern::Alarm => {
+                    lit = true;
+                    config.blink_ms
+                }
+                Pattern::Fault => {
+                    lit = !lit;
+                    config.fault_blink_ms
+                }
+                Pattern::Normal => {
+                    lit = !lit;
+                    config.blink_ms
+                }
+            };
+            failsafe::lock(&outputs).set_status_led(lit);
+            tokio::time::sleep(Duration::from_millis(step)).await;
+        }
+    }));
+}
+
+fn has_bad_channel(shared_state: &SharedState) -> bool {
+    shared_state
+        .snapshot()
+        .quality
+        .values()
+        .any(|quality| *quality == Quality::Bad)
+}
@@ -0,0 +1,203 @@
+/// Fixed-precision channel values.
+///
+/// Each channel is published with a set number of decimal places and a
+/// rounding rule, `precision` and `rounding` in `[channels.<name>]`. The
+/// reading is rounded once, as a `Decimal`, when it is sampled, and that same
+/// decimal is what the websocket, REST API, metrics and exports print, so they
+/// all show the same digits.
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_DECIMALS: u32 = 2;
+// An f32 only carries about 7 significant digits, more places would be noise.
+pub const MAX_DECIMALS: u32 = 9;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    /// Halves away from zero: 2.5 -> 3, -2.5 -> -3.
+    #[default]
+    HalfUp,
+    /// Halves to the even neighbour: 2.5 -> 2, 3.5 -> 4.
+    HalfEven,
+    /// Toward zero.
+    Truncate,
+    Floor,
+    Ceiling,
+}
+
+impl Rounding {
+    fn strategy(&self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+            Rounding::Floor => RoundingStrategy::ToNegativeInfinity,
+            Rounding::Ceiling => RoundingStrategy::ToPositiveInfinity,
+        }
+    }
+}
+
+/// How a channel's values are rounded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Precision {
+    pub decimals: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            decimals: DEFAULT_DECIMALS,
+            rounding: Rounding::HalfUp,
+        }
+    }
+}
+
+impl Precision {
+    /// Raw ADC volts, shown next to the scaled values.
+    pub const RAW_VOLTS: Precision = Precision {
+        decimals: 4,
+        rounding: Rounding::HalfUp,
+    };
+
+    /// Spread of oversampled ADC readings, a fraction of an LSB matters.
+    pub const NOISE_VOLTS: Precision = Precision {
+        decimals: 6,
+        rounding: Rounding::HalfUp,
+    };
+
+    /// `value` rounded to this precision. `None` for NaN, infinities and
+    /// magnitudes a decimal cannot hold.
+    pub fn value(&self, value: f32) -> Option<ChannelValue> {
+        // through the shortest text that reads back as the same float, so
+        // 1.2 is 1.2 and not 1.2000001, which would ceil to 1.3
+        let mut decimal = Decimal::from_str(&value.to_string())
+            .ok()?
+            .round_dp_with_strategy(self.decimals, self.rounding.strategy());
+        // pad with zeros so 1.5 prints as 1.50
+        decimal.rescale(self.decimals);
+        Some(ChannelValue(decimal))
+    }
+
+    /// The rounded value as a float, or `value` itself if it cannot be
+    /// rounded.
+    pub fn round(&self, value: f32) -> f32 {
+        self.value(value).map_or(value, |value| value.to_f32())
+    }
+
+    /// The rounded value with exactly `decimals` places.
+    pub fn format(&self, value: f32) -> String {
+        self.value(value)
+            .map_or_else(|| value.to_string(), |value| value.to_string())
+    }
+}
+
+/// A channel reading rounded to its configured precision.
+///
+/// Displays with exactly that many places and serializes as a JSON number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChannelValue(Decimal);
+
+impl ChannelValue {
+    pub fn to_f32(self) -> f32 {
+        self.0.to_f32().unwrap_or(0.0)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0.to_f64().unwrap_or(0.0)
+    }
+}
+
+impl fmt::Display for ChannelValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Serialize for ChannelValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // the shortest f64 that reads back as this decimal, so 0.1 stays 0.1
+        serializer.serialize_f64(self.to_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rounded(value: f32, decimals: u32, rounding: Rounding) -> String {
+        Precision { decimals, rounding }.format(value)
+    }
+
+    #[test]
+    fn rounds_halves_by_the_rule() {
+        for (value, half_up, half_even) in [
+            (2.5, "3", "2"),
+            (3.5, "4", "4"),
+            (-2.5, "-3", "-2"),
+            (-3.5, "-4", "-4"),
+            (0.0, "0", "0"),
+        ] {
+            assert_eq!(rounded(value, 0, Rounding::HalfUp), half_up, "{value}");
+            assert_eq!(rounded(value, 0, Rounding::HalfEven), half_even, "{value}");
+        }
+        assert_eq!(rounded(0.125, 2, Rounding::HalfUp), "0.13");
+        assert_eq!(rounded(0.125, 2, Rounding::HalfEven), "0.12");
+        assert_eq!(rounded(-0.125, 2, Rounding::HalfUp), "-0.13");
+    }
+
+    #[test]
+    fn rounds_toward_the_rule() {
+        for (value, truncate, floor, ceiling) in [
+            (1.27, "1.2", "1.2", "1.3"),
+            (-1.27, "-1.2", "-1.3", "-1.2"),
+            (1.2, "1.2", "1.2", "1.2"),
+        ] {
+            assert_eq!(rounded(value, 1, Rounding::Truncate), truncate, "{value}");
+            assert_eq!(rounded(value, 1, Rounding::Floor), floor, "{value}");
+            assert_eq!(rounded(value, 1, Rounding::Ceiling), ceiling, "{value}");
+        }
+    }
+
+    #[test]
+    fn pads_to_the_decimals() {
+        assert_eq!(rounded(1.5, 2, Rounding::HalfUp), "1.50");
+        assert_eq!(rounded(-7.0, 3, Rounding::HalfUp), "-7.000");
+        assert_eq!(rounded(1.5, MAX_DECIMALS, Rounding::HalfUp), "1.500000000");
+        assert_eq!(
+            rounded(0.123456, MAX_DECIMALS, Rounding::HalfUp),
+            "0.123456000"
+        );
+    }
+
+    #[test]
+    fn has_no_value_for_nan_or_infinity() {
+        let precision = Precision::default();
+        assert_eq!(precision.value(f32::NAN), None);
+        assert_eq!(precision.value(f32::INFINITY), None);
+        // more than a decimal holds
+        assert_eq!(precision.value(1e30), None);
+        assert_eq!(precision.format(f32::NEG_INFINITY), "-inf");
+        assert!(precision.round(f32::NAN).is_nan());
+        assert_eq!(precision.round(1.005), 1.01);
+    }
+
+    #[test]
+    fn serializes_as_the_shortest_number() {
+        let json = |value: f32, decimals| {
+            let precision = Precision {
+                decimals,
+                rounding: Rounding::HalfUp,
+            };
+            serde_json::to_string(&precision.value(value).unwrap()).unwrap()
+        };
+        assert_eq!(json(0.1, 2), "0.1");
+        assert_eq!(json(1.5, 2), "1.5");
+        assert_eq!(json(-2.675, 2), "-2.68");
+        assert_eq!(json(3.0, 0), "3.0");
+    }
+}
@@ -0,0 +1,131 @@
+/// Hardware watchdog, so a hung box reboots itself.
+///
+/// With a `[watchdog]` section the sampling loop opens the Linux watchdog
+/// device, which arms it, and writes to it after every good ADC read. A read
+/// stuck on the I2C bus or on a lock never gets that far, and after
+/// `max_failed_reads` failed reads in a row the writes stop too; either way
+/// the watchdog runs out and resets the Pi. The timeout is the driver's, e.g. `soft_margin` of the
+/// softdog module. A graceful shutdown disarms it with the magic close as
+/// soon as the sampling loop has stopped; a panic does not.
+///
+/// Any writable file works as the device for trying this out: each pet is a
+/// line, so `wc -l` counts them.
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+/// `[watchdog]`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchdogConfig {
+    #[serde(default = "default_device")]
+    pub device: String,
+    /// Failed ADC reads in a row before the watchdog is left to run out.
+    #[serde(default = "default_max_failed_reads")]
+    pub max_failed_reads: u32,
+    /// The driver's timeout, or less. Only used to check that the sampling
+    /// loop pets often enough; the bcm2835 driver allows about 15 s at most.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_device() -> String {
+    "/dev/watchdog".to_string()
+}
+
+fn default_max_failed_reads() -> u32 {
+    40
+}
+
+fn default_timeout_ms() -> u64 {
+    15000
+}
+
+impl WatchdogConfig {
+    /// `pet_interval_ms` is the longest the sampling loop goes between pets
+    /// while all is well: the shortest channel period plus its read. It has
+    /// to fit in the timeout twice over, so a late read does not reboot the
+    /// Pi.
+    pub fn validate(&self, pet_interval_ms: u64) -> Result<(), String> {
+        if self.timeout_ms == 0 {
+            return Err("watchdog.timeout_ms must be at least 1".to_string());
+        }
+        if pet_interval_ms.saturating_mul(2) > self.timeout_ms {
+            return Err(format!(
+                "watchdog: the sampling loop only pets it every {pet_interval_ms} ms, which is \
+                 not well inside timeout_ms = {}; give a channel a period_ms shorter than half \
+                 of it",
+                self.timeout_ms
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct Watchdog {
+    // closed once disarmed
+    file: Option<File>,
+    device: String,
+    max_failed_reads: u32,
+    failed_reads: u32,
+}
+
+impl Watchdog {
+    /// Opens the device, from which point it has to be petted.
+    pub fn open(config: &WatchdogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&config.device)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.device, e)))?;
+        println!("watchdog: armed {}", config.device);
+        Ok(Self {
+            file: Some(file),
+            device: config.device.clone(),
+            max_failed_reads: config.max_failed_reads,
+            failed_reads: 0,
+        })
+    }
+
+    /// Called after every ADC read, `good` if it succeeded.
+    pub fn read_done(&mut self, good: bool) {
+        if self.file.is_none() {
+            return;
+        }
+        if good {
+            if self.failed_reads > self.max_failed_reads {
+                println!("watchdog: reads are working again, petting {}", self.device);
+            }
+            self.failed_reads = 0;
+        } else {
+            self.failed_reads = self.failed_reads.saturating_add(1);
+            if self.failed_reads == self.max_failed_reads + 1 {
+                println!(
+                    "watchdog: {} failed reads in a row, no longer petting {}",
+                    self.max_failed_reads, self.device
+                );
+            }
+            if self.failed_reads > self.max_failed_reads {
+                return;
+            }
+        }
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        if let Err(e) = file.write_all(b"\n") {
+            println!("watchdog: failed to pet {}: {}", self.device, e);
+        }
+    }
+
+    /// Magic close: the driver stops the timer once the device is closed
+    /// after a "V", so the device is closed here and not when the process
+    /// exits. Nothing is written after this.
+    pub fn disarm(&mut self) {
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+        match file.write_all(b"V") {
+            Ok(()) => println!("watchdog: disarmed {}", self.device),
+            Err(e) => println!("watchdog: failed to disarm {}: {}", self.device, e),
+        }
+    }
+}
@@ -2,40 +2,183 @@
 /// Website related functionality goes here.
 // pulled from https://github.com/tokio-rs/axum/blob/main/examples/websockets/src/main.rs
 use axum::{
+    body::{Body, Bytes},
     extract::{
         ws::{WebSocket, WebSocketUpgrade},
-        State,
+        FromRef, Path, Query, State,
     },
-    http::{StatusCode, Uri},
-    response::{Html, IntoResponse},
-    routing::get,
-    Router,
+    http::{header, StatusCode, Uri},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
-use tower_http::services::{ServeDir, ServeFile};
+use serde::{Deserialize, Serialize};
+use tower_http::services::ServeDir;
 
-use crate::IoState;
-use std::sync::{Arc, Mutex};
+use crate::alarm::{AlarmEngine, AlarmError};
+use crate::calibration::{CalibrationError, Calibrator, Fit, Preview};
+use crate::export::{self, Format, Layout};
+use crate::failsafe::Outputs;
+use crate::history::{self, Bucket, History, Quality};
+use crate::interlock::Interlocks;
+use crate::metrics;
+use crate::shutdown::Shutdown;
+use crate::state::SharedState;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::Duration;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::mpsc;
 
 use crate::rhino::Rhino;
+use crate::sampling::{ChannelStats, I2C_BUDGET};
+use crate::value::{ChannelValue, Precision};
 
-/// Main application that launches the server
-///
-pub async fn app(shared_state: Arc<Mutex<IoState>>) {
+// History queries default to the last hour split into about this many buckets.
+const HISTORY_DEFAULT_SPAN_MS: u64 = 60 * 60 * 1000;
+const HISTORY_DEFAULT_BUCKETS: u64 = 500;
+const HISTORY_MAX_BUCKETS: u64 = 10_000;
+
+// Alarm log entries returned when no limit is given.
+const ALARM_LOG_DEFAULT_LIMIT: usize = 100;
+
+// Exports are streamed to the client in chunks of about this size.
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+
+// How often a stopping server checks whether the websocket clients are gone.
+const WS_DRAIN_POLL: Duration = Duration::from_millis(50);
+
+/// Everything the handlers need, handed out by axum through `State`.
+#[derive(Clone)]
+pub struct AppState {
+    io_state: SharedState,
+    history: Arc<Mutex<History>>,
+    calibrator: Arc<Mutex<Calibrator>>,
+    alarms: Arc<Mutex<AlarmEngine>>,
+    interlocks: Arc<Mutex<Interlocks>>,
+    outputs: Arc<Mutex<Outputs>>,
+    shutdown: Shutdown,
+    // kept out of the IO state so a connect or disconnect does not count as a
+    // change and resend everything to every client
+    ws_clients: Arc<AtomicUsize>,
+}
+
+impl FromRef<AppState> for SharedState {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.io_state.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Mutex<History>> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.history.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Mutex<Calibrator>> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.calibrator.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Mutex<AlarmEngine>> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.alarms.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Mutex<Interlocks>> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.interlocks.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Mutex<Outputs>> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.outputs.clone()
+    }
+}
+
+impl FromRef<AppState> for Shutdown {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.shutdown.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<AtomicUsize> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.ws_clients.clone()
+    }
+}
+
+/// Main application that launches the server. Runs until `shutdown` is
+/// triggered and every request and websocket client is done.
+pub async fn app(
+    shared_state: SharedState,
+    history: Arc<Mutex<History>>,
+    calibrator: Arc<Mutex<Calibrator>>,
+    alarms: Arc<Mutex<AlarmEngine>>,
+    interlocks: Arc<Mutex<Interlocks>>,
+    outputs: Arc<Mutex<Outputs>>,
+    shutdown: Shutdown,
+) -> io::Result<()> {
     println!("Launching web server");
 
+    let ws_clients = Arc::new(AtomicUsize::new(0));
+    let app_state = AppState {
+        io_state: shared_state,
+        history,
+        calibrator,
+        alarms,
+        interlocks,
+        outputs,
+        shutdown: shutdown.clone(),
+        ws_clients: ws_clients.clone(),
+    };
+
     let serve_dir = ServeDir::new("assets");
 
     let app = Router::new()
         .route("/index", get(index))
         .route("/ws", get(ws_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/api/history/:channel", get(history_handler))
+        .route("/api/export", get(export_handler))
+        .route("/api/alarms", get(alarms_handler))
+        .route("/api/alarms/log", get(alarm_log_handler))
+        .route("/api/alarms/:id/ack", post(alarm_ack_handler))
+        .route("/api/interlocks", get(interlocks_handler))
+        .route("/api/sampling", get(sampling_handler))
+        .route("/api/channels", get(channels_handler))
+        .route(
+            "/api/calibration/:channel",
+            get(calibration_preview_handler).delete(calibration_discard_handler),
+        )
+        .route(
+            "/api/calibration/:channel/points",
+            post(calibration_capture_handler),
+        )
+        .route(
+            "/api/calibration/:channel/commit",
+            post(calibration_commit_handler),
+        )
         // no idea why nest service is required, seems like fallback service should be enough.
         .nest_service("/", serve_dir.clone())
         .fallback(fallback)
-        .with_state(shared_state);
+        .with_state(app_state);
     // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.wait().await })
+        .await?;
+    // the server only waits for requests, the upgraded websockets are on their own
+    while ws_clients.load(Ordering::SeqCst) > 0 {
+        tokio::time::sleep(WS_DRAIN_POLL).await;
+    }
+    println!("Web server stopped");
+    Ok(())
 }
 
 async fn fallback(uri: Uri) -> (StatusCode, String) {
@@ -44,9 +187,485 @@ async fn fallback(uri: Uri) -> (StatusCode, String) {
 }
 
 async fn index() -> (StatusCode, Html<String>) {
-    println!("no such file assets/index.html");
-    let string = std::fs::read_to_string("assets/index.html").unwrap();
-    (StatusCode::OK, Html(string))
+    match tokio::fs::read_to_string("assets/index.html").await {
+        Ok(string) => (StatusCode::OK, Html(string)),
+        Err(e) => {
+            println!("Failed to read assets/index.html: {}", e);
+            (StatusCode::NOT_FOUND, Html("no index.html".to_string()))
+        }
+    }
+}
+
+/// Prometheus scrape endpoint.
+async fn metrics_handler(
+    State(shared_state): State<SharedState>,
+    State(ws_clients): State<Arc<AtomicUsize>>,
+) -> impl IntoResponse {
+    let io_state = shared_state.snapshot();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(&io_state, ws_clients.load(Ordering::SeqCst)),
+    )
+}
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    /// Unix milliseconds, defaults to an hour before `to`.
+    from: Option<u64>,
+    /// Unix milliseconds, defaults to now.
+    to: Option<u64>,
+    /// Bucket width in milliseconds, defaults to splitting the range into
+    /// `HISTORY_DEFAULT_BUCKETS`.
+    step: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    channel: String,
+    from: u64,
+    to: u64,
+    step: u64,
+    buckets: Vec<Bucket>,
+}
+
+/// Downsampled history of one channel as min/max/avg/last buckets.
+async fn history_handler(
+    Path(channel): Path<String>,
+    Query(params): Query<HistoryParams>,
+    State(shared_state): State<SharedState>,
+    State(history): State<Arc<Mutex<History>>>,
+) -> Response {
+    let precision = {
+        let io_state = shared_state.snapshot();
+        if !io_state.channels.contains_key(&channel) {
+            return (StatusCode::NOT_FOUND, format!("no channel named {channel}")).into_response();
+        }
+        io_state.precision(&channel)
+    };
+
+    let to = params.to.unwrap_or_else(history::now_ms);
+    let from = params
+        .from
+        .unwrap_or_else(|| to.saturating_sub(HISTORY_DEFAULT_SPAN_MS));
+    if from > to {
+        return (
+            StatusCode::BAD_REQUEST,
+            "from must not be after to".to_string(),
+        )
+            .into_response();
+    }
+    let step = params
+        .step
+        .unwrap_or_else(|| ((to - from) / HISTORY_DEFAULT_BUCKETS).max(1));
+    if step == 0 || (to - from) / step >= HISTORY_MAX_BUCKETS {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("step must split the range into at most {HISTORY_MAX_BUCKETS} buckets"),
+        )
+            .into_response();
+    }
+
+    let channels = [channel.clone()];
+    let range = history
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .range(Some(&channels), from, to);
+    // reading older samples back from disk is blocking work
+    let buckets = tokio::task::spawn_blocking(move || {
+        range.and_then(|range| history::downsample(range, from, to, step))
+    })
+    .await
+    .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+    match buckets {
+        Ok(mut buckets) => {
+            // averages in particular come out with float noise
+            for bucket in buckets.iter_mut() {
+                for value in [
+                    &mut bucket.min,
+                    &mut bucket.max,
+                    &mut bucket.avg,
+                    &mut bucket.last,
+                ] {
+                    *value = value.map(|value| precision.round(value));
+                }
+            }
+            Json(HistoryResponse {
+                channel,
+                from,
+                to,
+                step,
+                buckets,
+            })
+            .into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("history read failed: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    /// Unix milliseconds.
+    from: u64,
+    /// Unix milliseconds, defaults to now.
+    to: Option<u64>,
+    /// Comma separated channel names, defaults to every channel.
+    channels: Option<String>,
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    layout: Layout,
+    /// Milliseconds of samples per row of the wide layout.
+    resolution_ms: Option<u64>,
+}
+
+/// Streams a time range of recorded samples as CSV or Parquet.
+async fn export_handler(
+    Query(params): Query<ExportParams>,
+    State(shared_state): State<SharedState>,
+    State(history): State<Arc<Mutex<History>>>,
+) -> Response {
+    let (known, precisions) = {
+        let io_state = shared_state.snapshot();
+        let known: Vec<String> = io_state.channels.keys().cloned().collect();
+        (known, io_state.precision.clone())
+    };
+    let channels: Vec<String> = match params.channels.as_deref() {
+        Some(list) => list
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .collect(),
+        None => known.clone(),
+    };
+    if let Some(unknown) = channels.iter().find(|name| !known.contains(name)) {
+        return (StatusCode::NOT_FOUND, format!("no channel named {unknown}")).into_response();
+    }
+    let resolution_ms = params
+        .resolution_ms
+        .unwrap_or(export::DEFAULT_RESOLUTION_MS);
+    if resolution_ms == 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            "resolution_ms must be at least 1".to_string(),
+        )
+            .into_response();
+    }
+
+    let to = params.to.unwrap_or_else(history::now_ms);
+    let range = match history
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .range(Some(&channels), params.from, to)
+    {
+        Ok(range) => range,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("history read failed: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(4);
+    let format = params.format;
+    let layout = params.layout;
+    tokio::task::spawn_blocking(move || {
+        let mut out = ChunkWriter::new(tx);
+        let written = export::write(
+            range,
+            &channels,
+            &precisions,
+            format,
+            layout,
+            resolution_ms,
+            &mut out,
+        );
+        if let Err(e) = written.and_then(|_| out.flush()) {
+            println!("export stopped: {}", e);
+            out.fail(e);
+        }
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    let filename = format!("io_export_{}_{}.{}", params.from, to, format.extension());
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// Hands written bytes to the response body in chunks. Writes fail once the
+/// client has gone away, which stops the export.
+struct ChunkWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(tx: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        Self {
+            tx,
+            buf: Vec::with_capacity(EXPORT_CHUNK_BYTES),
+        }
+    }
+
+    /// Aborts the response so the client sees a truncated download rather
+    /// than a file that looks complete.
+    fn fail(self, e: io::Error) {
+        let _ = self.tx.blocking_send(Err(e));
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buf,
+            Vec::with_capacity(EXPORT_CHUNK_BYTES),
+        ));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= EXPORT_CHUNK_BYTES {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+/// Alarms that are raised or still need acknowledging.
+async fn alarms_handler(State(alarms): State<Arc<Mutex<AlarmEngine>>>) -> Response {
+    Json(
+        alarms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .listed(),
+    )
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct AlarmLogParams {
+    limit: Option<usize>,
+}
+
+/// The newest alarm events, oldest first.
+async fn alarm_log_handler(
+    Query(params): Query<AlarmLogParams>,
+    State(alarms): State<Arc<Mutex<AlarmEngine>>>,
+) -> Response {
+    let limit = params.limit.unwrap_or(ALARM_LOG_DEFAULT_LIMIT);
+    Json(
+        alarms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .log(limit),
+    )
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct AlarmAck {
+    user: String,
+    comment: Option<String>,
+}
+
+async fn alarm_ack_handler(
+    Path(id): Path<String>,
+    State(alarms): State<Arc<Mutex<AlarmEngine>>>,
+    Json(ack): Json<AlarmAck>,
+) -> Response {
+    match alarms
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .acknowledge(&id, &ack.user, ack.comment)
+    {
+        Ok(alarm) => Json(alarm).into_response(),
+        Err(e) => {
+            let status = match e {
+                AlarmError::UnknownAlarm(_) => StatusCode::NOT_FOUND,
+                AlarmError::NotPending(_) => StatusCode::CONFLICT,
+                AlarmError::Unauthorized(_) => StatusCode::FORBIDDEN,
+                AlarmError::Invalid(_) => StatusCode::BAD_REQUEST,
+            };
+            (status, e.to_string()).into_response()
+        }
+    }
+}
+
+/// Every interlock rule, whether it currently holds and what it last did.
+async fn interlocks_handler(State(interlocks): State<Arc<Mutex<Interlocks>>>) -> Response {
+    Json(
+        interlocks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .status(),
+    )
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct SamplingReport {
+    /// Planned share of the I2C bus, from the periods and estimated read times.
+    i2c_load: f64,
+    i2c_budget: f64,
+    channels: BTreeMap<String, ChannelStats>,
+}
+
+/// Configured and achieved sampling of every ADC channel read so far.
+async fn sampling_handler(State(shared_state): State<SharedState>) -> Response {
+    let channels = BTreeMap::clone(&shared_state.snapshot().sampling);
+    let i2c_load = channels
+        .values()
+        .map(|stats| stats.bus_ms / stats.period_ms as f64)
+        .sum();
+    Json(SamplingReport {
+        i2c_load,
+        i2c_budget: I2C_BUDGET,
+        channels,
+    })
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct ChannelReport {
+    value: ChannelValue,
+    units: Option<String>,
+    quality: Option<Quality>,
+    /// ADC channels only: volts as read, after the filters and the noise of
+    /// the last read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filtered: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noise: Option<f32>,
+}
+
+/// Latest value of every channel, with the raw and filtered volts of the ADC
+/// channels.
+async fn channels_handler(State(shared_state): State<SharedState>) -> Response {
+    let io_state = shared_state.snapshot();
+    let channels: BTreeMap<&String, ChannelReport> = io_state
+        .channels
+        .iter()
+        .map(|(channel, value)| {
+            let report = ChannelReport {
+                value: *value,
+                units: io_state.units.get(channel).cloned(),
+                quality: io_state.quality.get(channel).copied(),
+                raw: io_state.raw.get(channel).copied(),
+                filtered: io_state.filtered.get(channel).copied(),
+                noise: io_state.noise.get(channel).copied(),
+            };
+            (channel, report)
+        })
+        .collect();
+    Json(channels).into_response()
+}
+
+#[derive(Deserialize)]
+struct CapturePoint {
+    reference: f32,
+    #[serde(flatten)]
+    fit: Fit,
+}
+
+#[derive(Deserialize)]
+struct CommitCalibration {
+    user: String,
+    units: Option<String>,
+    #[serde(flatten)]
+    fit: Fit,
+}
+
+/// Captured points of a channel and the fit they give, `?fit=&degree=`.
+async fn calibration_preview_handler(
+    Path(channel): Path<String>,
+    Query(fit): Query<Fit>,
+    State(calibrator): State<Arc<Mutex<Calibrator>>>,
+) -> Response {
+    let preview = calibrator
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .preview(&channel, fit);
+    calibration_response(preview)
+}
+
+/// Adds a point at the channel's current raw reading.
+async fn calibration_capture_handler(
+    Path(channel): Path<String>,
+    State(calibrator): State<Arc<Mutex<Calibrator>>>,
+    Json(point): Json<CapturePoint>,
+) -> Response {
+    let preview = calibrator
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .capture(&channel, point.reference, point.fit);
+    calibration_response(preview)
+}
+
+/// Throws away the captured points.
+async fn calibration_discard_handler(
+    Path(channel): Path<String>,
+    State(calibrator): State<Arc<Mutex<Calibrator>>>,
+) -> Response {
+    let mut calibrator = calibrator.lock().unwrap_or_else(PoisonError::into_inner);
+    let preview = calibrator
+        .discard(&channel)
+        .and_then(|_| calibrator.preview(&channel, Fit::default()));
+    calibration_response(preview)
+}
+
+/// Saves the fit to the config file and applies it.
+async fn calibration_commit_handler(
+    Path(channel): Path<String>,
+    State(calibrator): State<Arc<Mutex<Calibrator>>>,
+    Json(commit): Json<CommitCalibration>,
+) -> Response {
+    let preview = calibrator
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .commit(&channel, commit.fit, &commit.user, commit.units);
+    calibration_response(preview)
+}
+
+fn calibration_response(preview: Result<Preview, CalibrationError>) -> Response {
+    match preview {
+        Ok(preview) => Json(preview).into_response(),
+        Err(e) => {
+            let status = match e {
+                CalibrationError::UnknownChannel(_) => StatusCode::NOT_FOUND,
+                CalibrationError::Invalid(_) => StatusCode::BAD_REQUEST,
+                CalibrationError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, e.to_string()).into_response()
+        }
+    }
 }
 
 /// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
@@ -54,68 +673,164 @@ async fn index() -> (StatusCode, Html<String>) {
 /// websocket protocol will occur.
 /// This is the last point where we can extract TCP/IP metadata such as IP address of the client
 /// as well as things from HTTP headers such as user-agent of the browser etc.
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State(shared_state): State<Arc<Mutex<IoState>>>,
-) -> impl IntoResponse {
+async fn ws_handler(ws: WebSocketUpgrade, State(app_state): State<AppState>) -> impl IntoResponse {
     println!("Ws handler got called");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, shared_state))
+    ws.on_upgrade(move |socket| handle_socket(socket, app_state))
 }
 
 /// Actual websocket statemachine (one will be spawned per connection)
-async fn handle_socket(socket: WebSocket, shared_state: Arc<Mutex<IoState>>) {
+async fn handle_socket(socket: WebSocket, app_state: AppState) {
+    let AppState {
+        io_state: shared_state,
+        calibrator,
+        alarms,
+        interlocks,
+        outputs,
+        shutdown,
+        ws_clients,
+        ..
+    } = app_state;
+
     // returning from the handler closes the websocket connection
     println!("Websocket context marques destroyed");
 
-    let tx = {
-        let tmp = shared_state.lock().unwrap();
-        tmp.sneaky_sender.clone()
-    };
+    ws_clients.fetch_add(1, Ordering::SeqCst);
+    let _counted = WsClientCount(ws_clients);
+    let tx = shared_state.snapshot().sneaky_sender.clone();
 
-    let mut rhino = Rhino::new(socket, tx);
+    let mut rhino = Rhino::new(socket, tx, calibrator, alarms.clone(), outputs);
+
+    // the client gets the current alarm list, then each event as it happens
+    let (listed, mut alarm_events) = {
+        let alarms = alarms.lock().unwrap_or_else(PoisonError::into_inner);
+        (alarms.listed(), alarms.subscribe())
+    };
+    let mut send_list = Some(listed);
+    // values are only sent again once a newer snapshot is out
+    let mut sent_seq: Option<u64> = None;
+    // blocked commands and trips, so the client sees why a toggle did nothing
+    let mut interlock_events = interlocks
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .subscribe();
 
     //let mut counter = 0;
 
-    loop {
-        let io_state = { shared_state.lock().unwrap().clone() };
-        rhino
-            .send_text_update("adc1_channel0", format!("{:.2}", io_state.adc1_channel0))
-            .await;
-        rhino
-            .send_text_update("adc1_channel1", format!("{:.2}", io_state.adc1_channel1))
-            .await;
-        rhino
-            .send_text_update("adc1_channel2", format!("{:.2}", io_state.adc1_channel2))
-            .await;
-        rhino
-            .send_text_update("adc1_channel3", format!("{:.2}", io_state.adc1_channel3))
-            .await;
-        rhino
-            .send_text_update("adc2_channel0", format!("{:.2}", io_state.adc2_channel0))
-            .await;
-        rhino
-            .send_text_update("adc2_channel1", format!("{:.2}", io_state.adc2_channel1))
-            .await;
-        rhino
-            .send_text_update("adc2_channel2", format!("{:.2}", io_state.adc2_channel2))
-            .await;
-        rhino
-            .send_text_update("adc2_channel3", format!("{:.2}", io_state.adc2_channel3))
-            .await;
-        rhino
-            .send_text_update("pin_one", format!("{}", io_state.pin_one))
-            .await;
-        rhino
-            .send_text_update("pin_two", format!("{}", io_state.pin_two))
-            .await;
+    'session: loop {
+        if shutdown.is_triggered() {
+            // the client may be gone already
+            let _ = rhino.close().await;
+            break 'session;
+        }
+        if rhino.send_replies().await.is_err() {
+            break 'session;
+        }
+        loop {
+            match alarm_events.try_recv() {
+                Ok(event) => {
+                    let text = serde_json::to_string(&event).unwrap();
+                    if rhino.send_text_update("alarm", text).await.is_err() {
+                        break 'session;
+                    }
+                }
+                // missed events, catch up with the whole list instead
+                Err(TryRecvError::Lagged(_)) => {
+                    send_list = Some(
+                        alarms
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .listed(),
+                    );
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        while let Ok(event) = interlock_events.try_recv() {
+            let text = serde_json::to_string(&event).unwrap();
+            if rhino.send_text_update("interlock", text).await.is_err() {
+                break 'session;
+            }
+        }
+        if let Some(listed) = send_list.take() {
+            let text = serde_json::to_string(&listed).unwrap();
+            if rhino.send_text_update("alarms", text).await.is_err() {
+                break 'session;
+            }
+        }
+        let io_state = shared_state.snapshot();
+        if sent_seq == Some(io_state.seq) {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+                _ = shutdown.wait() => {}
+            }
+            continue;
+        }
+        sent_seq = Some(io_state.seq);
+        for (channel, value) in io_state.channels.iter() {
+            let text = match io_state.units.get(channel) {
+                Some(units) => format!("{} {}", value, units),
+                None => value.to_string(),
+            };
+            if rhino.send_text_update(channel, text).await.is_err() {
+                break 'session;
+            }
+        }
+        for (channel, volts) in io_state.raw.iter() {
+            let id = format!("{}_raw", channel);
+            if rhino
+                .send_text_update(&id, format!("{} V", Precision::RAW_VOLTS.format(*volts)))
+                .await
+                .is_err()
+            {
+                break 'session;
+            }
+        }
+        for (channel, volts) in io_state.filtered.iter() {
+            let id = format!("{}_filtered", channel);
+            let text = format!("{} V", Precision::RAW_VOLTS.format(*volts));
+            if rhino.send_text_update(&id, text).await.is_err() {
+                break 'session;
+            }
+        }
+        for (channel, volts) in io_state.noise.iter() {
+            let id = format!("{}_noise", channel);
+            let text = format!("{} V", Precision::NOISE_VOLTS.format(*volts));
+            if rhino.send_text_update(&id, text).await.is_err() {
+                break 'session;
+            }
+        }
+        for (input, value) in io_state.inputs.iter() {
+            if rhino
+                .send_text_update(input, format!("{}", value))
+                .await
+                .is_err()
+            {
+                break 'session;
+            }
+        }
 
         //println!("counter: {counter}");
 
         // socket.send(Message::Text(counter.to_string())).await;
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+            _ = shutdown.wait() => {}
+        }
 
         //counter += 1;
     }
+
+    println!("Websocket client went away");
+}
+
+/// Takes a websocket session off `ws_clients` when dropped, also when the
+/// session panicked, so the shutdown drain does not wait for it.
+struct WsClientCount(Arc<AtomicUsize>);
+
+impl Drop for WsClientCount {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }